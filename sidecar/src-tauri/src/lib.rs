@@ -4,21 +4,34 @@
 //! for the Sidecar AI Communication Assistant.
 
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL},
+    Engine,
+};
+use hmac::{Hmac, Mac};
 use parking_lot::Mutex;
+use r2d2_sqlite::SqliteConnectionManager;
 use rand::Rng;
-use rusqlite::{params, Connection};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::types::ValueRef;
+use rusqlite::{
+    CachedStatement, Connection, OpenFlags, OptionalExtension, Statement, StatementStatus,
+};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tauri::State;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, State, Window};
 use thiserror::Error;
 use uuid::Uuid;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 // ============================================================================
 // Error Types
@@ -27,11 +40,20 @@ use uuid::Uuid;
 #[derive(Error, Debug)]
 pub enum SidecarError {
     #[error("Database error: {0}")]
-    Database(#[from] rusqlite::Error),
+    Database(rusqlite::Error),
 
     #[error("Encryption error: {0}")]
     Encryption(String),
 
+    /// The encryption key isn't loaded: unlock before retrying
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    /// Ciphertext failed authentication, from a wrong password or
+    /// corrupted data
+    #[error("Encryption failed: {0}")]
+    EncryptionFailed(String),
+
     #[error("Keyring error: {0}")]
     Keyring(String),
 
@@ -43,14 +65,79 @@ pub enum SidecarError {
 
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Operation timed out: {0}")]
+    Timeout(String),
+
+    #[error("Connection pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+
+    #[error("Database is busy: {0}")]
+    Busy(String),
+
+    #[error("Cancelled: {0}")]
+    Cancelled(String),
+
+    /// SQL for a single-statement command held more than one statement
+    #[error("Multiple statements: {0}")]
+    MultipleStatements(String),
+}
+
+impl From<rusqlite::Error> for SidecarError {
+    /// Interrupted statements surface as `Cancelled` (or `Timeout` when a
+    /// deadline interrupted them) and lock contention as `Busy`, so the
+    /// frontend can tell a stopped or blocked query apart from a failing one
+    fn from(err: rusqlite::Error) -> Self {
+        match err.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::OperationInterrupted) => {
+                SidecarError::Cancelled(err.to_string())
+            }
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => {
+                SidecarError::Busy(err.to_string())
+            }
+            _ => SidecarError::Database(err),
+        }
+    }
 }
 
+impl SidecarError {
+    /// Stable identifier for the variant, for the frontend to match on
+    /// instead of the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            SidecarError::Database(_) => "database",
+            SidecarError::Encryption(_) => "encryption",
+            SidecarError::Unauthorized(_) => "unauthorized",
+            SidecarError::EncryptionFailed(_) => "encryption_failed",
+            SidecarError::Keyring(_) => "keyring",
+            SidecarError::InvalidState(_) => "invalid_state",
+            SidecarError::NotFound(_) => "not_found",
+            SidecarError::Serialization(_) => "serialization",
+            SidecarError::Io(_) => "io",
+            SidecarError::Timeout(_) => "timeout",
+            SidecarError::Pool(_) => "pool",
+            SidecarError::Busy(_) => "busy",
+            SidecarError::Cancelled(_) => "cancelled",
+            SidecarError::MultipleStatements(_) => "multiple_statements",
+        }
+    }
+}
+
+/// Sent to the frontend as `{ code, message }`
 impl Serialize for SidecarError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&self.to_string())
+        use serde::ser::SerializeStruct;
+
+        let mut error = serializer.serialize_struct("SidecarError", 2)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
     }
 }
 
@@ -58,386 +145,10870 @@ impl Serialize for SidecarError {
 // State Management
 // ============================================================================
 
+/// Read connections used alongside the single writer connection
+type ReaderPool = r2d2::Pool<SqliteConnectionManager>;
+
+/// Name of the primary database. It's what commands use when no
+/// `connection` is given.
+const MAIN_CONNECTION: &str = "main";
+
+/// Reader connections per database unless `db_init` asks otherwise
+const DEFAULT_POOL_SIZE: u32 = 4;
+
+/// How long a statement waits on a locked database unless `db_init` asks
+/// otherwise
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Prepared statements kept per connection unless configured otherwise
+const DEFAULT_STATEMENT_CACHE_SIZE: usize = 128;
+
+/// Statement cache lookups by `db_query` and `db_execute` since startup,
+/// across every connection
+static STATEMENT_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static STATEMENT_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// A 256-bit key whose bytes are zeroed when it's dropped
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct ZeroizeKey(pub [u8; 32]);
+
+impl std::ops::Deref for ZeroizeKey {
+    type Target = [u8; 32];
+
+    fn deref(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for ZeroizeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ZeroizeKey(..)")
+    }
+}
+
+/// Password for a SQLCipher database. It's handed to `PRAGMA key` as is,
+/// so SQLCipher derives the file's key with its own PBKDF2 and the salt
+/// stored in the file.
+#[derive(Clone)]
+struct DbPassphrase(Zeroizing<String>);
+
+impl DbPassphrase {
+    fn new(password: String) -> Self {
+        DbPassphrase(Zeroizing::new(password))
+    }
+}
+
+impl std::ops::Deref for DbPassphrase {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
 pub struct AppState {
     db: Mutex<Option<Connection>>,
-    encryption_key: Mutex<Option<[u8; 32]>>,
-    oauth_states: Mutex<HashMap<String, String>>,
+    readers: Mutex<Option<ReaderPool>>,
+    /// Databases attached to `main` as `(alias, path)`, re-attached on
+    /// every connection
+    attachments: Mutex<Vec<(String, PathBuf)>>,
+    /// Databases opened under a name other than `main`. These are single
+    /// connections, without a reader pool or change events.
+    connections: Mutex<HashMap<String, Connection>>,
+    pool_size: Mutex<u32>,
+    busy_timeout: Mutex<Duration>,
+    statement_cache_size: Mutex<usize>,
+    db_key: Mutex<Option<DbPassphrase>>,
+    encryption_key: Mutex<Option<ZeroizeKey>>,
+    oauth_states: Mutex<HashMap<String, (String, Instant)>>,
+    pkce_verifiers: Mutex<HashMap<String, (String, Instant)>>,
+    query_streams: Mutex<HashMap<String, Arc<AtomicBool>>>,
+    oauth_state_ttl: Mutex<Duration>,
+    nonce_counter: Mutex<NonceCounter>,
+    changes: Arc<Mutex<ChangeTracker>>,
+    /// Interrupt handles for the connections commands are using right now,
+    /// so `db_cancel` can stop them
+    active_queries: Mutex<HashMap<u64, rusqlite::InterruptHandle>>,
+    next_query_id: AtomicU64,
+    auto_lock_after: Mutex<Option<Duration>>,
+    key_last_used: Mutex<Instant>,
+    auto_backup: Mutex<Option<AutoBackup>>,
+    /// Set by `set_keyring_service`; `None` means `DEFAULT_KEYRING_SERVICE`
+    keyring_service: Mutex<Option<String>>,
+    /// File of the main database last closed by `db_close`, so `db_restore`
+    /// can replace it while nothing is open
+    closed_path: Mutex<Option<PathBuf>>,
+    /// Scalar functions added by `db_register_function`, as
+    /// `(sql_name, builtin)`, installed on every `main` connection
+    sql_functions: Mutex<Vec<(String, BuiltinFunction)>>,
+    /// Argon2id cost for keys derived from now on; stored parameters keep
+    /// whatever they were created with
+    kdf_cost: Mutex<KdfCost>,
+}
+
+/// How often the auto-lock task checks for an idle key
+const AUTO_LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Nonce counter values handed out ahead of time. Only the end of the
+/// reserved range is written to the database, so a crash skips unused
+/// values instead of reusing them.
+const NONCE_RESERVATION: u64 = 1024;
+
+#[derive(Debug, Default)]
+struct NonceCounter {
+    next: u64,
+    /// First value not yet recorded in the database
+    reserved: u64,
 }
 
 impl AppState {
     fn new() -> Self {
         Self {
             db: Mutex::new(None),
+            readers: Mutex::new(None),
+            attachments: Mutex::new(Vec::new()),
+            connections: Mutex::new(HashMap::new()),
+            pool_size: Mutex::new(DEFAULT_POOL_SIZE),
+            busy_timeout: Mutex::new(DEFAULT_BUSY_TIMEOUT),
+            statement_cache_size: Mutex::new(DEFAULT_STATEMENT_CACHE_SIZE),
+            db_key: Mutex::new(None),
             encryption_key: Mutex::new(None),
             oauth_states: Mutex::new(HashMap::new()),
+            pkce_verifiers: Mutex::new(HashMap::new()),
+            query_streams: Mutex::new(HashMap::new()),
+            oauth_state_ttl: Mutex::new(DEFAULT_OAUTH_STATE_TTL),
+            nonce_counter: Mutex::new(NonceCounter::default()),
+            changes: Arc::new(Mutex::new(ChangeTracker::default())),
+            active_queries: Mutex::new(HashMap::new()),
+            next_query_id: AtomicU64::new(0),
+            auto_lock_after: Mutex::new(None),
+            key_last_used: Mutex::new(Instant::now()),
+            auto_backup: Mutex::new(None),
+            keyring_service: Mutex::new(None),
+            closed_path: Mutex::new(None),
+            sql_functions: Mutex::new(Vec::new()),
+            kdf_cost: Mutex::new(KdfCost::default()),
         }
     }
-}
 
-// ============================================================================
-// Database Commands
-// ============================================================================
-
-/// Initialize the database with the given path
-#[tauri::command]
-pub fn db_init(state: State<'_, Arc<AppState>>, path: Option<String>) -> Result<(), SidecarError> {
-    let db_path = path.map(PathBuf::from).unwrap_or_else(|| {
-        let mut path = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
-        path.push("sidecar");
-        std::fs::create_dir_all(&path).ok();
-        path.push("sidecar.db");
-        path
-    });
-
-    let conn = Connection::open(&db_path)?;
+    /// Open the database at `path`, closing any connection that is already open
+    ///
+    /// Locks encryption: the field key's salt belongs to the database being
+    /// replaced.
+    fn open_db(&self, path: &Path) -> Result<(), SidecarError> {
+        self.lock_key();
+        {
+            let mut db = self.db.lock();
+            self.readers.lock().take();
+            if let Some(old) = db.take() {
+                close_connection(old)?;
+            }
+            // Attachments belonged to the previous database
+            self.attachments.lock().clear();
+            self.closed_path.lock().take();
+            if let Some(auto) = self.auto_backup.lock().as_mut() {
+                auto.last_fingerprint = None;
+            }
+            let conn = open_connection(path, self.db_key.lock().as_deref())?;
+            let stored = load_nonce_counter(&conn)?;
+            let mut counter = self.nonce_counter.lock();
+            counter.next = counter.next.max(stored);
+            // Nothing past `next` is recorded in this file yet
+            counter.reserved = counter.next;
+            drop(counter);
+            *db = Some(conn);
+        }
+        self.configure_connections()
+    }
 
-    // Enable WAL mode for better performance
-    conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;")?;
+    /// Close the database if open. Returns whether a connection was closed.
+    fn close_db(&self) -> Result<bool, SidecarError> {
+        let mut db = self.db.lock();
+        self.readers.lock().take();
+        match db.take() {
+            Some(conn) => {
+                let path = conn.path().filter(|p| !p.is_empty()).map(PathBuf::from);
+                close_connection(conn)?;
+                *self.closed_path.lock() = path;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 
-    let mut db = state.db.lock();
-    *db = Some(conn);
+    /// Finish setting up a newly opened database: apply the busy timeout and
+    /// statement cache size to every connection, install the change hooks on the writer and build
+    /// the reader pool. In-memory databases can't be shared between
+    /// connections, so they get no readers.
+    fn configure_connections(&self) -> Result<(), SidecarError> {
+        let busy_timeout = *self.busy_timeout.lock();
+        let cache_size = *self.statement_cache_size.lock();
+        let attachments = self.attachments.lock().clone();
+        let functions = self.sql_functions.lock().clone();
+        let keyed = self.db_key.lock().is_some();
+        let path = self.with_conn(|conn| {
+            conn.busy_timeout(busy_timeout)?;
+            conn.set_prepared_statement_cache_capacity(cache_size);
+            install_change_hooks(conn, &self.changes);
+            for (name, builtin) in &functions {
+                register_builtin_function(conn, name, *builtin)?;
+            }
+            for (alias, path) in &attachments {
+                if !is_attached(conn, alias)? {
+                    attach_database(conn, alias, path, keyed)?;
+                }
+            }
+            Ok(conn.path().filter(|p| !p.is_empty()).map(PathBuf::from))
+        })?;
+        let pool = match path {
+            Some(path) => {
+                let key = self.db_key.lock().clone();
+                // Readers can't write, so only the writer connection ever
+                // takes the write lock
+                let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+                let manager = SqliteConnectionManager::file(path)
+                    .with_flags(flags)
+                    .with_init(move |conn| {
+                        if let Some(key) = &key {
+                            conn.pragma_update(None, "key", &**key)?;
+                        }
+                        conn.busy_timeout(busy_timeout)?;
+                        conn.set_prepared_statement_cache_capacity(cache_size);
+                        register_sql_functions(conn)?;
+                        for (name, builtin) in &functions {
+                            register_builtin_function(conn, name, *builtin)?;
+                        }
+                        for (alias, path) in &attachments {
+                            attach_database(conn, alias, path, key.is_some())?;
+                        }
+                        conn.execute_batch("PRAGMA foreign_keys=ON;")
+                    });
+                // Connections are opened on first use rather than up front
+                Some(
+                    r2d2::Pool::builder()
+                        .max_size(*self.pool_size.lock())
+                        .min_idle(Some(0))
+                        .build(manager)?,
+                )
+            }
+            None => None,
+        };
+        *self.readers.lock() = pool;
+        Ok(())
+    }
 
-    Ok(())
-}
+    /// Run `f` against the open connection
+    fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, SidecarError>,
+    ) -> Result<T, SidecarError> {
+        let db = self.db.lock();
+        let conn = db.as_ref().ok_or(SidecarError::InvalidState(
+            "Database not initialized".to_string(),
+        ))?;
+        self.interruptible(conn, f)
+    }
 
-/// Execute a SQL statement (INSERT, UPDATE, DELETE, CREATE)
-#[tauri::command]
-pub fn db_execute(
-    state: State<'_, Arc<AppState>>,
-    sql: String,
-    params: Vec<serde_json::Value>,
-) -> Result<usize, SidecarError> {
-    let db = state.db.lock();
-    let conn = db.as_ref().ok_or(SidecarError::InvalidState(
-        "Database not initialized".to_string(),
-    ))?;
+    /// Run `f` with `conn` registered for `db_cancel`
+    fn interruptible<T>(
+        &self,
+        conn: &Connection,
+        f: impl FnOnce(&Connection) -> Result<T, SidecarError>,
+    ) -> Result<T, SidecarError> {
+        let id = self.next_query_id.fetch_add(1, Ordering::Relaxed);
+        self.active_queries
+            .lock()
+            .insert(id, conn.get_interrupt_handle());
+        let result = f(conn);
+        self.active_queries.lock().remove(&id);
+        result
+    }
 
-    let params: Vec<Box<dyn rusqlite::ToSql>> = params
-        .iter()
-        .map(|v| json_to_sql(v))
-        .collect();
+    /// Interrupt every statement running on behalf of a command. Returns
+    /// whether any command was using a connection.
+    fn cancel_queries(&self) -> bool {
+        let active = self.active_queries.lock();
+        for handle in active.values() {
+            handle.interrupt();
+        }
+        !active.is_empty()
+    }
 
-    let refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|b| b.as_ref()).collect();
+    /// Open `path` as the named connection `name`, replacing any connection
+    /// already open under that name
+    fn open_named(&self, name: &str, path: &Path, key: Option<&str>) -> Result<(), SidecarError> {
+        let mut connections = self.connections.lock();
+        if let Some(old) = connections.remove(name) {
+            close_connection(old)?;
+        }
+        let conn = open_connection(path, key)?;
+        conn.busy_timeout(*self.busy_timeout.lock())?;
+        conn.set_prepared_statement_cache_capacity(*self.statement_cache_size.lock());
+        connections.insert(name.to_string(), conn);
+        Ok(())
+    }
 
-    let affected = conn.execute(&sql, refs.as_slice())?;
-    Ok(affected)
-}
+    /// Close the connection called `name`, leaving the others open. Returns
+    /// whether a connection was closed.
+    fn close_named(&self, name: Option<&str>) -> Result<bool, SidecarError> {
+        match name.filter(|name| *name != MAIN_CONNECTION) {
+            None => self.close_db(),
+            Some(name) => match self.connections.lock().remove(name) {
+                Some(conn) => close_connection(conn).map(|_| true),
+                None => Ok(false),
+            },
+        }
+    }
 
-/// Query the database and return results as JSON
-#[tauri::command]
-pub fn db_query(
-    state: State<'_, Arc<AppState>>,
-    sql: String,
-    params: Vec<serde_json::Value>,
-) -> Result<Vec<serde_json::Value>, SidecarError> {
-    let db = state.db.lock();
-    let conn = db.as_ref().ok_or(SidecarError::InvalidState(
-        "Database not initialized".to_string(),
-    ))?;
+    /// `with_conn` for the connection called `name`, or `main` if `None`
+    fn with_named_conn<T>(
+        &self,
+        name: Option<&str>,
+        f: impl FnOnce(&Connection) -> Result<T, SidecarError>,
+    ) -> Result<T, SidecarError> {
+        match name.filter(|name| *name != MAIN_CONNECTION) {
+            None => self.with_conn(f),
+            Some(name) => {
+                let connections = self.connections.lock();
+                let conn = connections.get(name).ok_or_else(|| {
+                    SidecarError::InvalidState(format!("Database '{name}' not initialized"))
+                })?;
+                self.interruptible(conn, f)
+            }
+        }
+    }
 
-    let params: Vec<Box<dyn rusqlite::ToSql>> = params
-        .iter()
-        .map(|v| json_to_sql(v))
-        .collect();
+    /// `with_reader` for `main`; named connections have no pool, so reads
+    /// use the connection itself
+    fn with_named_reader<T>(
+        &self,
+        name: Option<&str>,
+        f: impl FnOnce(&Connection) -> Result<T, SidecarError>,
+    ) -> Result<T, SidecarError> {
+        match name.filter(|name| *name != MAIN_CONNECTION) {
+            None => self.with_reader(f),
+            name => self.with_named_conn(name, f),
+        }
+    }
 
-    let refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|b| b.as_ref()).collect();
+    /// Run a read-only `f` on a pooled reader connection, so slow reads
+    /// neither wait for nor block the writer. Falls back to the writer
+    /// connection when there is no pool (in-memory databases).
+    fn with_reader<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, SidecarError>,
+    ) -> Result<T, SidecarError> {
+        let pool = self.readers.lock().clone();
+        match pool {
+            Some(pool) => self.interruptible(&*pool.get()?, f),
+            None => self.with_conn(f),
+        }
+    }
 
-    let mut stmt = conn.prepare(&sql)?;
-    let column_count = stmt.column_count();
-    let column_names: Vec<String> = stmt
-        .column_names()
-        .iter()
-        .map(|s| s.to_string())
-        .collect();
+    /// A fresh AES-GCM nonce: the 8-byte big-endian counter followed by 4
+    /// random bytes
+    fn next_nonce(&self) -> Result<[u8; 12], SidecarError> {
+        {
+            let mut counter = self.nonce_counter.lock();
+            if counter.next < counter.reserved {
+                return Ok(take_nonce(&mut counter));
+            }
+        }
+        let db = self.db.lock();
+        self.next_nonce_on(db.as_ref())
+    }
 
-    let rows = stmt.query_map(refs.as_slice(), |row| {
-        let mut map = serde_json::Map::new();
-        for i in 0..column_count {
-            let value = row_value_to_json(row, i);
-            map.insert(column_names[i].clone(), value);
+    /// `next_nonce` for callers already holding the database connection
+    fn next_nonce_on(&self, conn: Option<&Connection>) -> Result<[u8; 12], SidecarError> {
+        let mut counter = self.nonce_counter.lock();
+        if counter.next >= counter.reserved {
+            let reserved = counter.next + NONCE_RESERVATION;
+            // Without a database the counter only lives for this session
+            if let Some(conn) = conn {
+                store_nonce_counter(conn, reserved)?;
+            }
+            counter.reserved = reserved;
         }
-        Ok(serde_json::Value::Object(map))
-    })?;
+        Ok(take_nonce(&mut counter))
+    }
 
-    let results: Result<Vec<_>, _> = rows.collect();
-    Ok(results?)
-}
+    /// Run `f` with the active encryption key
+    fn with_key<T>(
+        &self,
+        f: impl FnOnce(&[u8; 32]) -> Result<T, SidecarError>,
+    ) -> Result<T, SidecarError> {
+        *self.key_last_used.lock() = Instant::now();
+        let key = self.encryption_key.lock();
+        let key = key.as_ref().ok_or(SidecarError::Unauthorized(
+            "Encryption not initialized".to_string(),
+        ))?;
+        f(key)
+    }
 
-fn json_to_sql(value: &serde_json::Value) -> Box<dyn rusqlite::ToSql> {
-    match value {
-        serde_json::Value::Null => Box::new(rusqlite::types::Null),
-        serde_json::Value::Bool(b) => Box::new(*b),
-        serde_json::Value::Number(n) => {
-            if let Some(i) = n.as_i64() {
-                Box::new(i)
-            } else if let Some(f) = n.as_f64() {
-                Box::new(f)
-            } else {
-                Box::new(n.to_string())
-            }
+    /// Zero and forget the encryption key
+    fn lock_key(&self) {
+        if let Some(mut key) = self.encryption_key.lock().take() {
+            key.zeroize();
         }
-        serde_json::Value::String(s) => Box::new(s.clone()),
-        _ => Box::new(value.to_string()),
     }
-}
 
-fn row_value_to_json(row: &rusqlite::Row, idx: usize) -> serde_json::Value {
-    // Try different types in order of likelihood
-    if let Ok(s) = row.get::<_, String>(idx) {
-        serde_json::Value::String(s)
-    } else if let Ok(i) = row.get::<_, i64>(idx) {
-        serde_json::Value::Number(i.into())
-    } else if let Ok(f) = row.get::<_, f64>(idx) {
-        serde_json::json!(f)
-    } else if let Ok(b) = row.get::<_, bool>(idx) {
-        serde_json::Value::Bool(b)
-    } else if let Ok(bytes) = row.get::<_, Vec<u8>>(idx) {
-        serde_json::Value::String(BASE64.encode(&bytes))
-    } else {
-        serde_json::Value::Null
+    /// Lock the key if auto-lock is on and it hasn't been used for the
+    /// configured time. Returns whether it was locked.
+    fn lock_if_idle(&self, now: Instant) -> bool {
+        let Some(after) = *self.auto_lock_after.lock() else {
+            return false;
+        };
+        let idle = now.saturating_duration_since(*self.key_last_used.lock());
+        if idle < after || self.encryption_key.lock().is_none() {
+            return false;
+        }
+        self.lock_key();
+        true
     }
 }
 
 // ============================================================================
-// Encryption Commands
+// Database Commands
 // ============================================================================
 
-/// Initialize encryption with a password-derived key
+/// Initialize the database with the given path
+///
+/// Safe to call again (e.g. when switching profiles): an already open
+/// connection is checkpointed and closed before the new one is opened.
+/// Writes go through a single connection; queries use a pool of up to
+/// `pool_size` reader connections (default 4). Statements wait up to
+/// `busy_timeout_ms` (default 5000) for a lock held by another process.
+/// Each connection caches up to `statement_cache_size` prepared statements
+/// (default 128).
+///
+/// A `path` of `":memory:"` opens a database that never touches disk, for
+/// tests and guest sessions. It has no reader pool and is gone once
+/// closed; see `db_close`.
+///
+/// With `password`, the database is opened as a SQLCipher database keyed
+/// from that password by SQLCipher's own PBKDF2, so the file also opens in
+/// other SQLCipher tools. This needs a build with the `sqlcipher` feature.
+///
+/// `connection` opens an additional database under that name instead of
+/// `main`, e.g. a cache that can be wiped without touching user data.
+/// Named databases are single connections: `pool_size` doesn't apply and
+/// they emit no change events. Without a `path` they live next to the main
+/// database as `<connection>.db`.
+///
+/// Opening the main database locks encryption, since the field key is
+/// derived from a salt stored in each database; call `init_encryption`
+/// again afterwards.
 #[tauri::command]
-pub fn init_encryption(
+fn db_init(
     state: State<'_, Arc<AppState>>,
-    password: String,
+    path: Option<String>,
+    pool_size: Option<u32>,
+    password: Option<String>,
+    busy_timeout_ms: Option<u32>,
+    connection: Option<String>,
+    statement_cache_size: Option<usize>,
 ) -> Result<(), SidecarError> {
-    let mut hasher = Sha256::new();
-    hasher.update(password.as_bytes());
-    hasher.update(b"sidecar-encryption-salt-v1");
-    let result = hasher.finalize();
+    if let Some(size) = statement_cache_size {
+        *state.statement_cache_size.lock() = size;
+    }
+    if let Some(name) = connection.filter(|name| name != MAIN_CONNECTION) {
+        if name.is_empty() {
+            return Err(SidecarError::InvalidState(
+                "Connection name must not be empty".to_string(),
+            ));
+        }
+        let key = password.map(DbPassphrase::new);
+        let db_path = path
+            .map(PathBuf::from)
+            .unwrap_or_else(|| default_database_dir().join(format!("{name}.db")));
+        return state.open_named(&name, &db_path, key.as_deref());
+    }
 
-    let mut key = [0u8; 32];
-    key.copy_from_slice(&result);
+    if let Some(millis) = busy_timeout_ms {
+        *state.busy_timeout.lock() = Duration::from_millis(millis.into());
+    }
+    if let Some(size) = pool_size {
+        if size == 0 {
+            return Err(SidecarError::InvalidState(
+                "pool_size must be at least 1".to_string(),
+            ));
+        }
+        *state.pool_size.lock() = size;
+    }
+    *state.db_key.lock() = password.map(DbPassphrase::new);
 
-    let mut encryption_key = state.encryption_key.lock();
-    *encryption_key = Some(key);
+    let db_path = path.map(PathBuf::from).unwrap_or_else(|| {
+        relocated_database_path(&database_location_file())
+            .unwrap_or_else(|| default_database_dir().join("sidecar.db"))
+    });
 
+    state.open_db(&db_path)?;
+    // Keychain trouble shouldn't keep the database from opening;
+    // cleanup_pending_credentials retries
+    let _ = complete_pending_rotations(&state);
     Ok(())
 }
 
-/// Encrypt data for storage
-#[tauri::command]
-pub fn encrypt_data(
-    state: State<'_, Arc<AppState>>,
-    plaintext: String,
-) -> Result<String, SidecarError> {
-    let key = state.encryption_key.lock();
-    let key = key
-        .as_ref()
-        .ok_or(SidecarError::Encryption("Encryption not initialized".to_string()))?;
-
-    let cipher = Aes256Gcm::new_from_slice(key)
-        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+fn close_database(
+    state: &AppState,
+    name: Option<&str>,
+    confirm_data_loss: bool,
+) -> Result<bool, SidecarError> {
+    let in_memory = state
+        .with_named_conn(name, |conn| Ok(conn.path().is_none_or(str::is_empty)))
+        .unwrap_or(false);
+    if in_memory && !confirm_data_loss {
+        return Err(SidecarError::InvalidState(
+            "Closing an in-memory database discards its data; pass confirm_data_loss to close it anyway"
+                .to_string(),
+        ));
+    }
+    state.close_named(name)
+}
 
-    let mut nonce_bytes = [0u8; 12];
-    rand::thread_rng().fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+fn default_database_dir() -> PathBuf {
+    let mut path = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push("sidecar");
+    std::fs::create_dir_all(&path).ok();
+    path
+}
 
-    let ciphertext = cipher
-        .encrypt(nonce, plaintext.as_bytes())
-        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+/// Records where `db_relocate` moved the main database. It stays in the
+/// default directory so `db_init` can always find it.
+fn database_location_file() -> PathBuf {
+    default_database_dir().join("location.json")
+}
 
-    // Combine nonce + ciphertext and encode as base64
-    let mut combined = nonce_bytes.to_vec();
-    combined.extend(ciphertext);
+#[derive(Debug, Serialize, Deserialize)]
+struct DatabaseLocation {
+    path: PathBuf,
+}
 
-    Ok(BASE64.encode(&combined))
+fn relocated_database_path(location_file: &Path) -> Option<PathBuf> {
+    let json = std::fs::read(location_file).ok()?;
+    serde_json::from_slice::<DatabaseLocation>(&json)
+        .ok()
+        .map(|location| location.path)
 }
 
-/// Decrypt data from storage
+/// Move the main database file into `new_dir` and keep using it there
+///
+/// The file is copied and checked with `PRAGMA integrity_check` before the
+/// app switches to it, and the original is only deleted once the copy is
+/// open; if anything fails before that, the original stays in use. Later
+/// `db_init` calls without a `path` open the new location. Returns the new
+/// path. Moving into the directory the database is already in does nothing.
+/// A successful move locks encryption, like opening a database.
 #[tauri::command]
-pub fn decrypt_data(
-    state: State<'_, Arc<AppState>>,
-    ciphertext: String,
-) -> Result<String, SidecarError> {
-    let key = state.encryption_key.lock();
-    let key = key
-        .as_ref()
-        .ok_or(SidecarError::Encryption("Encryption not initialized".to_string()))?;
-
-    let cipher = Aes256Gcm::new_from_slice(key)
-        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
-
-    let combined = BASE64
-        .decode(&ciphertext)
-        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
-
-    if combined.len() < 12 {
-        return Err(SidecarError::Encryption("Invalid ciphertext".to_string()));
-    }
-
-    let (nonce_bytes, ciphertext_bytes) = combined.split_at(12);
-    let nonce = Nonce::from_slice(nonce_bytes);
-
-    let plaintext = cipher
-        .decrypt(nonce, ciphertext_bytes)
-        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
-
-    String::from_utf8(plaintext).map_err(|e| SidecarError::Encryption(e.to_string()))
+fn db_relocate(state: State<'_, Arc<AppState>>, new_dir: String) -> Result<String, SidecarError> {
+    let path = relocate_database(&state, Path::new(&new_dir), &database_location_file())?;
+    Ok(path.to_string_lossy().into_owned())
 }
 
-// ============================================================================
-// Credential Storage Commands (System Keychain)
-// ============================================================================
-
-const KEYRING_SERVICE: &str = "sidecar-app";
-
-/// Store credentials in system keychain
+/// Close the database connection, checkpointing the WAL into the main file.
+/// Closing when no database is open is a no-op. `connection` closes only
+/// that named database.
+///
+/// An in-memory database loses its data when closed, so closing one fails
+/// with `InvalidState` unless `confirm_data_loss` is set. Use `db_backup`
+/// first to keep the data.
 #[tauri::command]
-pub fn store_credentials(provider: String, credentials: String) -> Result<(), SidecarError> {
-    let entry = keyring::Entry::new(KEYRING_SERVICE, &provider)
-        .map_err(|e| SidecarError::Keyring(e.to_string()))?;
-
-    entry
-        .set_password(&credentials)
-        .map_err(|e| SidecarError::Keyring(e.to_string()))?;
-
+fn db_close(
+    state: State<'_, Arc<AppState>>,
+    connection: Option<String>,
+    confirm_data_loss: Option<bool>,
+) -> Result<(), SidecarError> {
+    close_database(
+        &state,
+        connection.as_deref(),
+        confirm_data_loss.unwrap_or(false),
+    )?;
     Ok(())
 }
 
-/// Get credentials from system keychain
+/// Attach the database file at `path` to connection `name` as `alias`, so
+/// its tables can be queried as `alias.table` alongside the connection's own
+///
+/// On `main` the attachment applies to the reader pool too and survives
+/// `db_restore`; opening a different main database drops it. When `main`
+/// is encrypted, the attached file is opened unencrypted.
 #[tauri::command]
-pub fn get_credentials(provider: String) -> Result<Option<String>, SidecarError> {
-    let entry = keyring::Entry::new(KEYRING_SERVICE, &provider)
-        .map_err(|e| SidecarError::Keyring(e.to_string()))?;
-
-    match entry.get_password() {
-        Ok(password) => Ok(Some(password)),
-        Err(keyring::Error::NoEntry) => Ok(None),
-        Err(e) => Err(SidecarError::Keyring(e.to_string())),
-    }
+fn db_attach(
+    state: State<'_, Arc<AppState>>,
+    name: String,
+    path: String,
+    alias: String,
+) -> Result<(), SidecarError> {
+    attach_to(&state, &name, Path::new(&path), &alias)
 }
 
-/// Delete credentials from system keychain
+/// Whether a database connection is currently open
 #[tauri::command]
-pub fn delete_credentials(provider: String) -> Result<(), SidecarError> {
-    let entry = keyring::Entry::new(KEYRING_SERVICE, &provider)
-        .map_err(|e| SidecarError::Keyring(e.to_string()))?;
-
-    match entry.delete_credential() {
-        Ok(_) => Ok(()),
-        Err(keyring::Error::NoEntry) => Ok(()), // Already deleted
-        Err(e) => Err(SidecarError::Keyring(e.to_string())),
-    }
+fn db_is_open(state: State<'_, Arc<AppState>>) -> bool {
+    state.db.lock().is_some()
 }
 
-// ============================================================================
-// OAuth State Management
-// ============================================================================
+/// Execute a SQL statement (INSERT, UPDATE, DELETE, CREATE)
+///
+/// `sql` must hold exactly one statement; anything after it other than
+/// comments and semicolons is rejected with `MultipleStatements`. Scripts go
+/// through `db_execute_batch`.
+///
+/// With `timeout_ms`, the statement is interrupted once the deadline passes
+/// and a `Timeout` error is returned.
+#[tauri::command]
+fn db_execute(
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    timeout_ms: Option<u64>,
+    connection: Option<String>,
+) -> Result<usize, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    retry_busy(|| {
+        state.with_named_conn(connection.as_deref(), |conn| {
+            with_deadline(conn, timeout_ms, |conn| {
+                execute_statement(conn, &sql, &params)
+            })
+        })
+    })
+}
 
-/// Store OAuth state for CSRF protection
+/// Run a script of semicolon-separated statements, such as a schema setup
+///
+/// The script runs in one transaction, so if any statement fails none of
+/// them take effect; it must not contain its own BEGIN or COMMIT. This and
+/// `db_execute_script` are the only commands that run more than one
+/// statement per call, so keep them to trusted SQL like migrations.
 #[tauri::command]
-pub fn store_oauth_state(
+fn db_execute_batch(
     state: State<'_, Arc<AppState>>,
-    provider: String,
-    oauth_state: String,
+    script: String,
+    connection: Option<String>,
 ) -> Result<(), SidecarError> {
-    let mut states = state.oauth_states.lock();
-    states.insert(provider, oauth_state);
+    retry_busy(|| {
+        state.with_named_conn(connection.as_deref(), |conn| execute_script(conn, &script))
+    })?;
     Ok(())
 }
 
-/// Validate OAuth state
+/// Run the contents of a `.sql` file the way `db_execute_batch` does and
+/// return how many statements it ran. Comments and empty statements don't
+/// count.
 #[tauri::command]
-pub fn validate_oauth_state(
+fn db_execute_script(
     state: State<'_, Arc<AppState>>,
-    provider: String,
-    oauth_state: String,
-) -> Result<bool, SidecarError> {
-    let mut states = state.oauth_states.lock();
-    if let Some(stored) = states.get(&provider) {
-        if stored == &oauth_state {
-            states.remove(&provider);
-            return Ok(true);
-        }
-    }
-    Ok(false)
+    sql: String,
+    connection: Option<String>,
+) -> Result<usize, SidecarError> {
+    retry_busy(|| state.with_named_conn(connection.as_deref(), |conn| execute_script(conn, &sql)))
 }
 
-// ============================================================================
-// Utility Commands
-// ============================================================================
+/// Open a savepoint named `name` on the main connection
+///
+/// With no transaction open this starts one, which lasts until the
+/// outermost savepoint is released. Later `db_execute` calls run inside
+/// it. Commands that manage their own transaction, like
+/// `db_execute_batch` and `db_import`, fail while a savepoint is open.
+#[tauri::command]
+fn db_savepoint(state: State<'_, Arc<AppState>>, name: String) -> Result<(), SidecarError> {
+    state.with_conn(|conn| run_savepoint(conn, SavepointAction::Open, &name))
+}
 
-/// Generate a random string for OAuth state
+/// Release the savepoint `name` and every savepoint opened after it,
+/// keeping their changes. Releasing the outermost one commits.
 #[tauri::command]
-pub fn generate_random_string(length: usize) -> String {
-    const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
-    let mut rng = rand::thread_rng();
+fn db_release(state: State<'_, Arc<AppState>>, name: String) -> Result<(), SidecarError> {
+    state.with_conn(|conn| run_savepoint(conn, SavepointAction::Release, &name))
+}
 
-    (0..length)
-        .map(|_| {
-            let idx = rng.gen_range(0..CHARSET.len());
-            CHARSET[idx] as char
+/// Undo every change made since the savepoint `name` was opened
+///
+/// The savepoint itself stays open, so it can be rolled back to again or
+/// released.
+#[tauri::command]
+fn db_rollback_to(state: State<'_, Arc<AppState>>, name: String) -> Result<(), SidecarError> {
+    state.with_conn(|conn| run_savepoint(conn, SavepointAction::RollbackTo, &name))
+}
+
+/// Execute an INSERT and return the rowid of the inserted row
+///
+/// The rowid is read under the same lock as the insert, so a concurrent
+/// write can't slip in between.
+#[tauri::command]
+fn db_insert(
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+) -> Result<i64, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    state.with_conn(|conn| {
+        execute_statement(conn, &sql, &params)?;
+        Ok(conn.last_insert_rowid())
+    })
+}
+
+/// Query a single row, returning `None` when there are no results
+///
+/// A second row is an `InvalidState` error, since it usually means the
+/// lookup wasn't as specific as intended. Pass `strict: false` to take the
+/// first row and ignore the rest.
+#[tauri::command(async)]
+fn db_query_one(
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    strict: Option<bool>,
+) -> Result<Option<serde_json::Value>, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    state.with_reader(|conn| query_one(conn, &sql, &params, strict.unwrap_or(true)))
+}
+
+/// Rows per `db:query-batch` event unless the caller picks a size
+const DEFAULT_STREAM_BATCH_SIZE: usize = 500;
+
+/// A chunk of rows from `db_query_stream`, emitted as `db:query-batch`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryBatch {
+    pub stream_id: String,
+    pub rows: Vec<serde_json::Value>,
+}
+
+/// Emitted as `db:query-done` once a stream has finished or was cancelled
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryDone {
+    pub stream_id: String,
+    pub total_rows: u64,
+    pub cancelled: bool,
+}
+
+/// Emitted as `db:query-error` when a stream fails partway
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryError {
+    pub stream_id: String,
+    pub error: String,
+}
+
+/// An event sent to the window that started a `db_query_stream`
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum StreamEvent {
+    Batch(QueryBatch),
+    Done(QueryDone),
+    Error(QueryError),
+}
+
+impl StreamEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::Batch(_) => "db:query-batch",
+            StreamEvent::Done(_) => "db:query-done",
+            StreamEvent::Error(_) => "db:query-error",
+        }
+    }
+}
+
+/// Stream a query's rows to the calling window as `db:query-batch` events
+///
+/// The caller picks `stream_id` so it can subscribe before invoking and
+/// cancel with `db_cancel_stream`. Every stream ends with exactly one
+/// `db:query-done` or `db:query-error` event; closing the window stops the
+/// stream early. Rows are read from the reader pool off the IPC thread, so
+/// neither the UI nor writes wait for the stream to finish.
+#[tauri::command(async)]
+fn db_query_stream(
+    window: Window,
+    state: State<'_, Arc<AppState>>,
+    stream_id: String,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    batch_size: Option<usize>,
+) -> Result<u64, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    let label = window.label().to_string();
+    stream_query(
+        &state,
+        stream_id,
+        &sql,
+        &params,
+        batch_size.unwrap_or(DEFAULT_STREAM_BATCH_SIZE),
+        |event| window.emit_to(label.as_str(), event.name(), event).is_ok(),
+    )
+}
+
+/// Ask a running `db_query_stream` to stop after its current batch.
+/// Returns false if no stream with that id is running.
+#[tauri::command]
+fn db_cancel_stream(state: State<'_, Arc<AppState>>, stream_id: String) -> bool {
+    match state.query_streams.lock().get(&stream_id) {
+        Some(cancelled) => {
+            cancelled.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// File format for `db_export`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+/// Progress of a running export, emitted as `db:export-progress`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportProgress {
+    pub rows_written: u64,
+}
+
+/// Result of a finished `db_export`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportSummary {
+    pub rows_written: u64,
+    pub size_bytes: u64,
+}
+
+const EXPORT_PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Write a query's rows to `path` as CSV (with a header row) or JSONL
+///
+/// Rows go straight from the statement to the file. Progress is emitted
+/// every 10,000 rows. `path` is only replaced once the export completes;
+/// on error the partial file is removed.
+#[tauri::command]
+fn db_export(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    path: String,
+    format: ExportFormat,
+) -> Result<ExportSummary, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    state.with_reader(|conn| {
+        export_query(conn, &sql, &params, Path::new(&path), format, |progress| {
+            let _ = app.emit("db:export-progress", progress);
         })
-        .collect()
+    })
 }
 
-/// Generate a secure ID (UUID v4)
+/// Outcome of `db_execute_returning`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecuteResult {
+    pub rows_affected: usize,
+    pub last_insert_rowid: Option<i64>,
+}
+
+/// Execute a statement and report what it changed
+///
+/// Both values are read under the same lock as the statement. The rowid
+/// is only set when the statement starts with INSERT or REPLACE and changed
+/// at least one row.
 #[tauri::command]
-pub fn generate_secure_id() -> String {
-    Uuid::new_v4().to_string()
+fn db_execute_returning(
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+) -> Result<ExecuteResult, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    state.with_conn(|conn| execute_returning(conn, &sql, &params))
 }
 
-/// Open a URL in the system browser
+/// Query the database and return results as JSON
+///
+/// `sql` must be a single statement and `timeout_ms` works as for
+/// `db_execute`.
+#[tauri::command(async)]
+fn db_query(
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    timeout_ms: Option<u64>,
+    connection: Option<String>,
+) -> Result<Vec<serde_json::Value>, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    retry_busy(|| {
+        state.with_named_reader(connection.as_deref(), |conn| {
+            with_deadline(conn, timeout_ms, |conn| query_rows(conn, &sql, &params))
+        })
+    })
+}
+
+/// `db_query`, with each result column's name and declared type
+///
+/// `declType` is the type from the column's table definition as written,
+/// such as `INTEGER` or `DATETIME`, so the frontend can map values without
+/// guessing. Expressions and columns declared without a type report null.
+#[tauri::command(async)]
+fn db_query_with_meta(
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    timeout_ms: Option<u64>,
+    connection: Option<String>,
+) -> Result<QueryWithMeta, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    retry_busy(|| {
+        state.with_named_reader(connection.as_deref(), |conn| {
+            with_deadline(conn, timeout_ms, |conn| {
+                query_with_meta(conn, &sql, &params)
+            })
+        })
+    })
+}
+
+/// Stop whatever the database is doing for other commands
+///
+/// Interrupted commands fail with a `Cancelled` error. Returns false, and
+/// does nothing, when no command is using the database.
 #[tauri::command]
-pub fn open_browser(url: String) -> Result<(), SidecarError> {
-    open::that(&url).map_err(|e| SidecarError::InvalidState(e.to_string()))?;
-    Ok(())
+fn db_cancel(state: State<'_, Arc<AppState>>) -> bool {
+    state.cancel_queries()
 }
 
-/// Get the app data directory
+/// Set how long statements wait on a locked database before failing
 #[tauri::command]
-pub fn get_app_data_dir() -> Result<String, SidecarError> {
-    let mut path = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
-    path.push("sidecar");
-    std::fs::create_dir_all(&path)
-        .map_err(|e| SidecarError::InvalidState(e.to_string()))?;
+fn db_set_busy_timeout(state: State<'_, Arc<AppState>>, millis: u32) -> Result<(), SidecarError> {
+    *state.busy_timeout.lock() = Duration::from_millis(millis.into());
+    // Readers pick up the new timeout as they're reopened
+    state.configure_connections()
+}
 
-    path.to_str()
-        .map(|s| s.to_string())
-        .ok_or(SidecarError::InvalidState("Invalid path".to_string()))
+/// Set how many prepared statements each connection keeps cached
+#[tauri::command]
+fn db_set_statement_cache_size(
+    state: State<'_, Arc<AppState>>,
+    size: usize,
+) -> Result<(), SidecarError> {
+    *state.statement_cache_size.lock() = size;
+    for conn in state.connections.lock().values() {
+        conn.set_prepared_statement_cache_capacity(size);
+    }
+    state.configure_connections()
 }
 
-// ============================================================================
-// Tauri App Entry Point
-// ============================================================================
+/// Statement cache usage, from `db_statement_cache_stats`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatementCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Statements each connection may keep
+    pub capacity: usize,
+}
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    let app_state = Arc::new(AppState::new());
+/// Report how often queries found their statement already prepared
+#[tauri::command]
+fn db_statement_cache_stats(state: State<'_, Arc<AppState>>) -> StatementCacheStats {
+    StatementCacheStats {
+        hits: STATEMENT_CACHE_HITS.load(Ordering::Relaxed),
+        misses: STATEMENT_CACHE_MISSES.load(Ordering::Relaxed),
+        capacity: *state.statement_cache_size.lock(),
+    }
+}
 
-    tauri::Builder::default()
-        .plugin(tauri_plugin_opener::init())
-        .manage(app_state)
-        .invoke_handler(tauri::generate_handler![
-            // Database
-            db_init,
-            db_execute,
-            db_query,
-            // Encryption
-            init_encryption,
-            encrypt_data,
-            decrypt_data,
-            // Credentials
-            store_credentials,
-            get_credentials,
-            delete_credentials,
-            // OAuth
-            store_oauth_state,
-            validate_oauth_state,
-            // Utilities
-            generate_random_string,
-            generate_secure_id,
-            open_browser,
-            get_app_data_dir,
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+/// A result column from `db_query_with_meta`
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnMeta {
+    pub name: String,
+    pub decl_type: Option<String>,
+}
+
+/// Query results from `db_query_with_meta`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryWithMeta {
+    pub columns: Vec<ColumnMeta>,
+    pub rows: Vec<serde_json::Value>,
+}
+
+/// One page of query results
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryPage {
+    pub rows: Vec<serde_json::Value>,
+    pub total_count: Option<u64>,
+    pub page: usize,
+    pub page_size: usize,
+}
+
+/// Query a single page of results. Pages are zero-based.
+///
+/// The caller's SQL is paged as a subquery, so a LIMIT of its own caps the
+/// rows paged through. When `include_count` is set, the total number of
+/// matching rows is returned too.
+#[tauri::command(async)]
+fn db_query_page(
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    page_size: usize,
+    page: usize,
+    include_count: Option<bool>,
+) -> Result<QueryPage, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    state.with_reader(|conn| {
+        query_page(
+            conn,
+            &sql,
+            &params,
+            page_size,
+            page,
+            include_count.unwrap_or(false),
+        )
+    })
+}
+
+/// Rows from a `db_query_paginated` call plus the unpaginated total
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaginatedRows {
+    pub rows: Vec<serde_json::Value>,
+    pub total: i64,
+    pub limit: u64,
+    pub offset: u64,
+}
+
+/// Query `limit` rows starting at `offset`, together with the total row count
+///
+/// The SQL is paged as a subquery, like `db_query_page`, so a LIMIT of its
+/// own caps `total` and the rows paged through.
+#[tauri::command(async)]
+fn db_query_paginated(
+    state: State<'_, Arc<AppState>>,
+    sql: String,
+    params: Option<Vec<serde_json::Value>>,
+    params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    limit: u64,
+    offset: u64,
+) -> Result<PaginatedRows, SidecarError> {
+    let params = SqlParams::from_args(params, params_named)?;
+    state.with_reader(|conn| query_paginated(conn, &sql, &params, limit, offset))
+}
+
+/// A column as reported by `pragma_table_info`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaColumn {
+    pub name: String,
+    /// The type as written in the table definition, possibly empty
+    pub declared_type: String,
+    pub not_null: bool,
+    /// Position in the primary key starting at 1, or 0 if not part of it
+    pub primary_key: u32,
+    /// The default as an SQL expression
+    pub default_value: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaIndex {
+    pub name: String,
+    pub unique: bool,
+    /// `"c"` for CREATE INDEX, `"u"` for a UNIQUE constraint, `"pk"` for
+    /// the primary key
+    pub origin: String,
+    pub partial: bool,
+    pub columns: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaForeignKey {
+    pub columns: Vec<String>,
+    pub parent_table: String,
+    /// Empty entries refer to the parent's primary key
+    pub parent_columns: Vec<Option<String>>,
+    pub on_update: String,
+    pub on_delete: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaTable {
+    pub name: String,
+    /// Created with CREATE VIRTUAL TABLE, e.g. an FTS5 index
+    pub is_virtual: bool,
+    pub columns: Vec<SchemaColumn>,
+    pub indexes: Vec<SchemaIndex>,
+    pub foreign_keys: Vec<SchemaForeignKey>,
+}
+
+/// Describe every table in the main database, in name order
+///
+/// SQLite's own tables, FTS shadow tables and the backend's bookkeeping
+/// tables are left out unless `include_internal` is set.
+#[tauri::command]
+fn db_schema(
+    state: State<'_, Arc<AppState>>,
+    include_internal: Option<bool>,
+) -> Result<Vec<SchemaTable>, SidecarError> {
+    state.with_reader(|conn| read_schema(conn, include_internal.unwrap_or(false)))
+}
+
+/// Size and layout of the main database, from `db_get_stats`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DbStats {
+    /// `None` for an in-memory database
+    pub path: Option<String>,
+    pub file_size: u64,
+    /// 0 when there is no WAL file
+    pub wal_size: u64,
+    /// Frames in the WAL file, including ones already checkpointed
+    pub wal_frames: u64,
+    pub page_size: u32,
+    pub page_count: u64,
+    /// `page_count * page_size`: the database's size once checkpointed
+    pub size_bytes: u64,
+    pub freelist_count: u64,
+    /// `PRAGMA cache_size`: pages if positive, KiB if negative
+    pub cache_size: i64,
+    /// Share of page lookups served from the page cache by the connection
+    /// that answered this call, since it was opened; `None` before its
+    /// first lookup
+    pub cache_hit_ratio: Option<f64>,
+    pub journal_mode: String,
+    pub tables: Vec<TableStats>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableStats {
+    pub name: String,
+    /// `None` when no estimate is available without a full count
+    pub rows: Option<u64>,
+    /// Whether `rows` came from `COUNT(*)`
+    pub exact: bool,
+}
+
+/// Report file sizes, page counts and per-table row counts for diagnostics
+///
+/// Row counts are estimates unless `exact` is set: the figure recorded by
+/// the last `ANALYZE`, or else the largest rowid, which overcounts tables
+/// with deleted rows. Counting exactly reads every table in full.
+///
+/// `cacheHitRatio` is per connection: it covers whichever pooled reader
+/// served the call, not the pool as a whole or the writer.
+#[tauri::command]
+fn db_get_stats(
+    state: State<'_, Arc<AppState>>,
+    exact: Option<bool>,
+) -> Result<DbStats, SidecarError> {
+    state.with_reader(|conn| database_stats(conn, exact.unwrap_or(false)))
+}
+
+/// `db_get_stats` under the name it was first added as
+#[tauri::command]
+fn db_stats(state: State<'_, Arc<AppState>>, exact: Option<bool>) -> Result<DbStats, SidecarError> {
+    db_get_stats(state, exact)
+}
+
+/// Check the database for corruption and foreign key violations
+///
+/// Returns an empty list when the database is healthy. Problems are
+/// returned rather than raised so a partially readable database can still
+/// be opened; the caller decides what to do with them.
+#[tauri::command]
+fn db_integrity_check(state: State<'_, Arc<AppState>>) -> Result<Vec<String>, SidecarError> {
+    state.with_conn(integrity_issues)
+}
+
+/// A row that references a missing parent, from `PRAGMA foreign_key_check`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForeignKeyViolation {
+    pub table: String,
+    pub rowid: Option<i64>,
+    pub parent: String,
+    pub constraint_index: i64,
+}
+
+/// Result of `db_check_integrity`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    pub ok: bool,
+    /// Every problem found, as readable messages: `errors` followed by one
+    /// line per foreign key violation
+    pub issues: Vec<String>,
+    /// The `PRAGMA integrity_check` messages on their own
+    pub errors: Vec<String>,
+    pub foreign_key_violations: Vec<ForeignKeyViolation>,
+}
+
+/// Run `PRAGMA integrity_check` and `PRAGMA foreign_key_check` and report
+/// the results
+///
+/// `issues` holds the same messages as `db_integrity_check`; `errors` and
+/// `foreignKeyViolations` break them down by check, with the violations as
+/// structured records. `ok` is true only when both checks are clean.
+#[tauri::command]
+fn db_check_integrity(state: State<'_, Arc<AppState>>) -> Result<IntegrityReport, SidecarError> {
+    state.with_conn(integrity_report)
+}
+
+/// Rows salvaged from one table by `db_recover`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SalvagedTable {
+    pub table: String,
+    pub rows_recovered: u64,
+    /// False when reading stopped early on a damaged page
+    pub complete: bool,
+}
+
+/// Result of `db_recover`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryReport {
+    pub tables: Vec<SalvagedTable>,
+    pub corrupt_path: String,
+}
+
+/// Rebuild a damaged database from whatever rows can still be read
+///
+/// The schema and every readable row are copied into a fresh file, which
+/// then replaces the live one. The damaged original is never deleted: it is
+/// renamed to `<name>.corrupt` (timestamped if that already exists) along
+/// with its WAL. Indexes, triggers and views are recreated after the data;
+/// full-text indexes are rebuilt from their content tables.
+#[tauri::command]
+fn db_recover(state: State<'_, Arc<AppState>>) -> Result<RecoveryReport, SidecarError> {
+    recover_database(&state)
+}
+
+/// Compact the database and return the number of bytes reclaimed
+///
+/// Checkpoints the WAL and runs `VACUUM`. The connection stays locked for
+/// the whole operation, so other commands wait until it finishes.
+#[tauri::command]
+fn db_vacuum(state: State<'_, Arc<AppState>>) -> Result<u64, SidecarError> {
+    state.with_conn(vacuum_database)
+}
+
+/// Refresh the query planner's statistics with `ANALYZE`
+#[tauri::command]
+fn db_analyze(state: State<'_, Arc<AppState>>) -> Result<(), SidecarError> {
+    state.with_conn(|conn| Ok(conn.execute_batch("ANALYZE")?))
+}
+
+/// Progress of `db_maintenance`, emitted as `db:maintenance-progress`
+/// before each step starts
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceProgress {
+    /// `"analyze"`, `"vacuum"` or `"checkpoint"`
+    pub step: &'static str,
+}
+
+/// Result of `db_maintenance`. Sizes are the main file plus its WAL.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceReport {
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// Refresh planner statistics with `ANALYZE`, compact the file with
+/// `VACUUM` when `vacuum` is set, then checkpoint and truncate the WAL
+///
+/// VACUUM rewrites the whole database and can take a while on large
+/// installs, so it is opt-in. Fails with `InvalidState` if a transaction
+/// is open on the connection.
+#[tauri::command]
+fn db_maintenance(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    vacuum: Option<bool>,
+) -> Result<MaintenanceReport, SidecarError> {
+    state.with_conn(|conn| {
+        run_maintenance(conn, vacuum.unwrap_or(false), |progress| {
+            let _ = app.emit("db:maintenance-progress", progress);
+        })
+    })
+}
+
+/// A single schema migration, applied when its version exceeds the
+/// database's `user_version`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Migration {
+    pub version: i64,
+    pub up_sql: String,
+}
+
+/// Apply pending migrations and return the resulting schema version
+///
+/// Each migration runs in its own transaction together with the
+/// `user_version` bump, so a failure leaves the database at the last
+/// successfully applied version. Re-running with the same list is a no-op.
+/// Statements cached against the old schema are dropped afterwards.
+#[tauri::command]
+fn db_migrate(
+    state: State<'_, Arc<AppState>>,
+    migrations: Vec<Migration>,
+) -> Result<i64, SidecarError> {
+    let version = state.with_conn(|conn| run_migrations(conn, &migrations))?;
+    // Readers have caches of their own; a fresh pool starts them empty
+    state.configure_connections()?;
+    Ok(version)
+}
+
+/// Progress of a running backup, emitted as `db:backup-progress`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupProgress {
+    pub pages_done: i32,
+    pub pages_total: i32,
+}
+
+/// Result of a finished backup
+#[derive(Debug)]
+pub struct BackupSummary {
+    pub pages_copied: u64,
+    pub size_bytes: u64,
+}
+
+const BACKUP_PAGES_PER_STEP: i32 = 32;
+const BACKUP_STEP_DELAY: Duration = Duration::from_millis(250);
+const BACKUP_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// How quickly a backup copies pages
+#[derive(Debug, Clone, Copy)]
+struct BackupPacing {
+    pages_per_step: i32,
+    /// Pause between steps, leaving the disk to the app on slow machines
+    step_delay: Duration,
+}
+
+impl Default for BackupPacing {
+    fn default() -> Self {
+        Self {
+            pages_per_step: BACKUP_PAGES_PER_STEP,
+            step_delay: BACKUP_STEP_DELAY,
+        }
+    }
+}
+
+/// Back up the live database to `dest_path` using SQLite's online backup API
+///
+/// The app can keep reading and writing while the backup runs. The copy is
+/// written next to `dest_path` and renamed into place once complete, so
+/// the destination never holds a partial backup. Refuses to replace an
+/// existing file unless `overwrite` is set. Returns the number of pages
+/// copied.
+///
+/// Pages are copied `pages_per_step` at a time (32 by default), pausing
+/// `step_delay_ms` between steps (250 by default) so a large backup doesn't
+/// starve the app's own reads and writes. The backup reads through its own
+/// connection and doesn't wait in the database worker's queue, so other
+/// commands aren't held up behind it.
+#[tauri::command(async)]
+fn db_backup(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    dest_path: String,
+    overwrite: Option<bool>,
+    pages_per_step: Option<u32>,
+    step_delay_ms: Option<u64>,
+) -> Result<u64, SidecarError> {
+    let pages_per_step = match pages_per_step {
+        None => BACKUP_PAGES_PER_STEP,
+        Some(pages @ 1..) => i32::try_from(pages).unwrap_or(i32::MAX),
+        Some(0) => {
+            return Err(SidecarError::InvalidState(
+                "pages_per_step must be at least 1".to_string(),
+            ))
+        }
+    };
+    let pacing = BackupPacing {
+        pages_per_step,
+        step_delay: step_delay_ms.map_or(BACKUP_STEP_DELAY, Duration::from_millis),
+    };
+    let summary = backup_database(
+        &state,
+        Path::new(&dest_path),
+        overwrite.unwrap_or(false),
+        pacing,
+        |progress| {
+            // Progress is informational; a closed window shouldn't fail the backup
+            let _ = app.emit("db:backup-progress", progress);
+        },
+    )?;
+    Ok(summary.pages_copied)
+}
+
+/// How often the auto-backup task checks whether a backup is due
+const AUTO_BACKUP_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Backups made by `configure_auto_backup` are named
+/// `sidecar-backup-<UTC timestamp>.db`
+const AUTO_BACKUP_PREFIX: &str = "sidecar-backup-";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoBackupConfig {
+    pub interval_secs: u64,
+    pub directory: String,
+    /// Older automatic backups in `directory` are deleted beyond this many
+    pub keep_last: usize,
+}
+
+/// Emitted as `db:auto-backup` after each automatic backup attempt
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoBackupEvent {
+    pub path: String,
+    /// `None` when the backup failed
+    pub size_bytes: Option<u64>,
+    pub error: Option<String>,
+}
+
+struct AutoBackup {
+    config: AutoBackupConfig,
+    last_attempt: Instant,
+    /// What the database looked like at the last successful backup
+    last_fingerprint: Option<(i64, i64, i64)>,
+}
+
+/// Back up the database to `directory` every `interval_secs`, or stop if
+/// `config` is `None`
+///
+/// The first backup is made one interval from now. A backup is skipped
+/// when nothing has been written since the previous one. Each attempt
+/// emits `db:auto-backup`.
+#[tauri::command]
+fn configure_auto_backup(
+    state: State<'_, Arc<AppState>>,
+    config: Option<AutoBackupConfig>,
+) -> Result<(), SidecarError> {
+    set_auto_backup(&state, config, Instant::now())
+}
+
+/// Replace the live database with the backup at `src_path`
+///
+/// Call `db_close` first, once every in-flight query has finished; with a
+/// database open this fails with `InvalidState`. The file that was closed
+/// is the one replaced, and it is open again when this returns.
+///
+/// The source is opened read-only first and must pass a full
+/// `PRAGMA integrity_check`; otherwise a `Database` error is returned and
+/// the closed file is left alone. That file is kept next to the new one as
+/// a timestamped `.bak`, and is put back if the restore fails partway.
+/// Returns the restored file's `user_version` so the frontend can run
+/// migrations. Encryption is locked, since the backup may hold a different
+/// key salt; call `init_encryption` again.
+#[tauri::command]
+fn db_restore(state: State<'_, Arc<AppState>>, src_path: String) -> Result<i64, SidecarError> {
+    restore_database(&state, Path::new(&src_path))
+}
+
+/// Change the password of an encrypted database
+///
+/// `old_password` must be the one the database was opened with.
+#[tauri::command]
+fn db_rekey(
+    state: State<'_, Arc<AppState>>,
+    old_password: String,
+    new_password: String,
+) -> Result<(), SidecarError> {
+    rekey_database(&state, &old_password, DbPassphrase::new(new_password))
+}
+
+/// Encrypt the open, unencrypted database with `password`
+///
+/// The contents are exported into a new SQLCipher file with
+/// `sqlcipher_export`, which then replaces the plaintext file. No
+/// unencrypted copy is left behind.
+#[tauri::command]
+fn db_encrypt(state: State<'_, Arc<AppState>>, password: String) -> Result<(), SidecarError> {
+    encrypt_database(&state, DbPassphrase::new(password))
+}
+
+/// Write every row of `table` to `dest_path` as newline-delimited JSON
+///
+/// Rows are written as they are read, so large tables aren't held in
+/// memory. `table` must name an existing table. Returns the number of rows
+/// written; a failed export leaves no file behind.
+#[tauri::command]
+fn db_export_json(
+    state: State<'_, Arc<AppState>>,
+    table: String,
+    dest_path: String,
+) -> Result<u64, SidecarError> {
+    state.with_reader(|conn| export_table_json(conn, &table, Path::new(&dest_path)))
+}
+
+/// Load newline-delimited JSON from `src_path` into `table`
+///
+/// Column names come from the first record; later records may leave
+/// columns out (they become NULL) but not add new ones. All rows go in
+/// one transaction using `INSERT OR <on_conflict>`, where `on_conflict` is
+/// `"fail"`, `"ignore"` or `"replace"`. Returns the number of rows inserted.
+#[tauri::command]
+fn db_import_json(
+    state: State<'_, Arc<AppState>>,
+    table: String,
+    src_path: String,
+    on_conflict: String,
+) -> Result<u64, SidecarError> {
+    state.with_conn(|conn| import_table_json(conn, &table, Path::new(&src_path), &on_conflict))
+}
+
+/// File format for `db_import`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    /// Comma-separated values with a header row of column names
+    Csv,
+    /// A JSON array of objects keyed by column name
+    Json,
+}
+
+/// How `db_import` treats problems in the file
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ImportOptions {
+    /// Drop columns the table doesn't have instead of failing
+    pub ignore_unknown: bool,
+    /// Skip rows that can't be inserted instead of stopping at the first one
+    pub best_effort: bool,
+}
+
+/// A row `db_import` skipped. Rows are counted from 1, not including the
+/// CSV header.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportRowError {
+    pub row: u64,
+    pub message: String,
+}
+
+/// Result of a finished `db_import`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportSummary {
+    pub inserted: u64,
+    pub skipped: u64,
+    /// The first few skipped rows and why
+    pub errors: Vec<ImportRowError>,
+}
+
+const IMPORT_BATCH_SIZE: u64 = 1000;
+const MAX_REPORTED_IMPORT_ERRORS: usize = 10;
+
+/// Load a CSV file or JSON array from `path` into `table`
+///
+/// Columns are matched to the table's by name, ignoring case. Values are
+/// inserted as text and converted by the column's type, so numeric strings
+/// land in INTEGER columns as integers; empty unquoted CSV fields are NULL.
+/// A leading BOM and CRLF line endings are accepted. Rows are committed in
+/// batches of 1000. Without `best_effort` the first bad row stops the
+/// import, keeping the batches committed before it.
+#[tauri::command]
+fn db_import(
+    state: State<'_, Arc<AppState>>,
+    path: String,
+    table: String,
+    format: ImportFormat,
+    options: Option<ImportOptions>,
+) -> Result<ImportSummary, SidecarError> {
+    let options = options.unwrap_or_default();
+    state.with_conn(|conn| import_file(conn, Path::new(&path), &table, format, &options))
+}
+
+/// Create an FTS5 index over `columns` of `table` and return its name
+///
+/// The index is an external-content table named `<table>_fts`: it stores
+/// only the search index and reads column values from `table`. Triggers
+/// keep it in sync with inserts, updates and deletes, and existing rows
+/// are indexed straight away. Calling it again for the same table is a no-op.
+#[tauri::command]
+fn db_create_fts_index(
+    state: State<'_, Arc<AppState>>,
+    table: String,
+    columns: Vec<String>,
+) -> Result<String, SidecarError> {
+    state.with_conn(|conn| create_fts_index(conn, &table, &columns))
+}
+
+/// Create an index named `index_name` over `columns` of `table`
+///
+/// The table and columns are checked first, so a typo fails with
+/// `NotFound` instead of an SQL error. Fails if the index already exists.
+#[tauri::command]
+fn db_create_index(
+    state: State<'_, Arc<AppState>>,
+    index_name: String,
+    table: String,
+    columns: Vec<String>,
+    unique: bool,
+) -> Result<(), SidecarError> {
+    state.with_conn(|conn| create_index(conn, &index_name, &table, &columns, unique))
+}
+
+/// Drop an index made by `db_create_index` or `CREATE INDEX`
+#[tauri::command]
+fn db_drop_index(state: State<'_, Arc<AppState>>, index_name: String) -> Result<(), SidecarError> {
+    state.with_conn(|conn| drop_index(conn, &index_name))
+}
+
+/// Make a built-in transformation callable from SQL as `fn_name`
+///
+/// `body` names the built-in: `sha256(x)` (lowercase hex of text or a
+/// blob), `upper(x)` (Unicode-aware, unlike SQLite's own), `regex_match(x,
+/// pattern)` or `normalize_whitespace(x)`, which trims and collapses runs
+/// of whitespace to one space. Arbitrary code isn't accepted. `n_args`
+/// must match the built-in, and `fn_name` can't be a function SQL already
+/// has, such as `upper` or `regexp`. The function applies to the `main`
+/// database, including ones opened later, and replaces any earlier function
+/// of the same name.
+#[tauri::command]
+fn db_register_function(
+    state: State<'_, Arc<AppState>>,
+    fn_name: String,
+    n_args: i32,
+    body: String,
+) -> Result<(), SidecarError> {
+    register_user_function(&state, &fn_name, n_args, &body)
+}
+
+/// Names added by `db_register_function`, in registration order
+#[tauri::command]
+fn db_list_functions(state: State<'_, Arc<AppState>>) -> Vec<String> {
+    state
+        .sql_functions
+        .lock()
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Full-text search an index made by `db_create_fts_index`
+///
+/// Returns the best matches first. Each row has the indexed columns plus
+/// `rowid`, `rank` (lower is better) and a `snippet` with matches wrapped
+/// in `<mark>`. Queries that aren't valid FTS5 syntax, such as ones with
+/// unbalanced quotes, are searched as a plain phrase instead.
+#[tauri::command]
+fn db_fts_search(
+    state: State<'_, Arc<AppState>>,
+    index: String,
+    query: String,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<serde_json::Value>, SidecarError> {
+    state.with_reader(|conn| fts_search(conn, &index, &query, limit, offset))
+}
+
+fn attach_to(state: &AppState, name: &str, path: &Path, alias: &str) -> Result<(), SidecarError> {
+    if name != MAIN_CONNECTION {
+        return state.with_named_conn(Some(name), |conn| {
+            Ok(attach_database(conn, alias, path, false)?)
+        });
+    }
+
+    let keyed = state.db_key.lock().is_some();
+    state.with_conn(|conn| {
+        if is_attached(conn, alias)? {
+            return Err(SidecarError::InvalidState(format!(
+                "Database '{alias}' is already attached to '{name}'"
+            )));
+        }
+        Ok(attach_database(conn, alias, path, keyed)?)
+    })?;
+    state
+        .attachments
+        .lock()
+        .push((alias.to_string(), path.to_path_buf()));
+    // Rebuild the pool so readers see the attachment too
+    state.configure_connections()
+}
+
+/// `plaintext` makes SQLCipher open the file unencrypted instead of with
+/// the main database's key
+fn attach_database(
+    conn: &Connection,
+    alias: &str,
+    path: &Path,
+    plaintext: bool,
+) -> rusqlite::Result<()> {
+    let key_clause = if plaintext { " KEY ''" } else { "" };
+    conn.execute(
+        &format!(
+            "ATTACH DATABASE ?1 AS {}{key_clause}",
+            quote_identifier(alias)
+        ),
+        [path.to_string_lossy()],
+    )?;
+    Ok(())
+}
+
+fn is_attached(conn: &Connection, alias: &str) -> Result<bool, SidecarError> {
+    let names = conn
+        .prepare("PRAGMA database_list")?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(names.iter().any(|name| name.eq_ignore_ascii_case(alias)))
+}
+
+fn open_connection(path: &Path, key: Option<&str>) -> Result<Connection, SidecarError> {
+    let conn = Connection::open(path)?;
+    apply_db_key(&conn, key)?;
+
+    // Enable WAL mode for better performance
+    conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;")?;
+    register_sql_functions(&conn)?;
+
+    Ok(conn)
+}
+
+/// Compiled REGEXP patterns kept per connection before the cache is reset
+const REGEX_CACHE_SIZE: usize = 32;
+
+/// Add the SQL functions SQLite leaves to the application:
+///
+/// - `x REGEXP pattern`, using the `regex` crate's syntax
+/// - `uuid4()`, a random UUID as text
+/// - `now_ms()`, the current Unix time in milliseconds
+/// - the `UNICODE_NOCASE` collation, which compares text after Unicode
+///   case folding where the built-in `NOCASE` only folds ASCII. Columns can
+///   declare `COLLATE UNICODE_NOCASE`, or a query can ask for it. Folding
+///   ignores locale, so Turkish dotless `ı` stays distinct from `i`.
+///   Databases using it in a schema can't be fully read by tools that
+///   don't define it.
+fn register_sql_functions(conn: &Connection) -> rusqlite::Result<()> {
+    use rusqlite::functions::FunctionFlags;
+
+    conn.create_collation("UNICODE_NOCASE", |a, b| {
+        unicase::UniCase::new(a).cmp(&unicase::UniCase::new(b))
+    })?;
+
+    let mut patterns: HashMap<String, regex::Regex> = HashMap::new();
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        move |ctx| {
+            // `x REGEXP y` calls regexp(y, x)
+            let pattern: String = ctx.get(0)?;
+            let Some(text) = ctx.get::<Option<String>>(1)? else {
+                return Ok(None);
+            };
+            cached_regex_match(&mut patterns, &pattern, &text).map(Some)
+        },
+    )?;
+    conn.create_scalar_function("uuid4", 0, FunctionFlags::SQLITE_UTF8, |_| {
+        Ok(Uuid::new_v4().to_string())
+    })?;
+    conn.create_scalar_function("now_ms", 0, FunctionFlags::SQLITE_UTF8, |_| {
+        Ok(chrono::Utc::now().timestamp_millis())
+    })
+}
+
+/// Whether `text` matches `pattern`, compiling each pattern once and
+/// keeping up to `REGEX_CACHE_SIZE` of them in `patterns`
+fn cached_regex_match(
+    patterns: &mut HashMap<String, regex::Regex>,
+    pattern: &str,
+    text: &str,
+) -> rusqlite::Result<bool> {
+    if !patterns.contains_key(pattern) {
+        let regex = regex::Regex::new(pattern).map_err(|e| {
+            rusqlite::Error::UserFunctionError(
+                format!("Invalid regular expression '{pattern}': {e}").into(),
+            )
+        })?;
+        if patterns.len() >= REGEX_CACHE_SIZE {
+            patterns.clear();
+        }
+        patterns.insert(pattern.to_string(), regex);
+    }
+    Ok(patterns[pattern].is_match(text))
+}
+
+/// Scalar functions `db_register_function` can install
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BuiltinFunction {
+    Sha256,
+    Upper,
+    RegexMatch,
+    NormalizeWhitespace,
+}
+
+impl BuiltinFunction {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Self::Sha256),
+            "upper" => Some(Self::Upper),
+            "regex_match" => Some(Self::RegexMatch),
+            "normalize_whitespace" => Some(Self::NormalizeWhitespace),
+            _ => None,
+        }
+    }
+
+    fn arity(self) -> i32 {
+        match self {
+            Self::RegexMatch => 2,
+            _ => 1,
+        }
+    }
+}
+
+fn register_user_function(
+    state: &AppState,
+    name: &str,
+    n_args: i32,
+    body: &str,
+) -> Result<(), SidecarError> {
+    let builtin = BuiltinFunction::from_name(body).ok_or_else(|| {
+        SidecarError::InvalidState(format!(
+            "Unknown function body '{body}': expected sha256, upper, regex_match or normalize_whitespace"
+        ))
+    })?;
+    if n_args != builtin.arity() {
+        return Err(SidecarError::InvalidState(format!(
+            "{body} takes {} argument(s), not {n_args}",
+            builtin.arity()
+        )));
+    }
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.len() <= 255
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(SidecarError::InvalidState(format!(
+            "Invalid function name '{name}': use letters, digits and underscores"
+        )));
+    }
+    // Overriding one would quietly change what existing SQL means, e.g.
+    // `regexp` as regex_match, which takes its arguments the other way round
+    if is_sql_builtin(name)? {
+        return Err(SidecarError::InvalidState(format!(
+            "'{name}' is already an SQL function; choose another name"
+        )));
+    }
+
+    {
+        let mut functions = state.sql_functions.lock();
+        functions.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        functions.push((name.to_string(), builtin));
+    }
+    // Installs it on the writer and rebuilds the readers with it
+    if state.db.lock().is_some() {
+        state.configure_connections()?;
+    }
+    Ok(())
+}
+
+/// Whether `name` is one of SQLite's functions or one every connection
+/// gets from `register_sql_functions`
+fn is_sql_builtin(name: &str) -> Result<bool, SidecarError> {
+    let conn = Connection::open_in_memory()?;
+    register_sql_functions(&conn)?;
+    Ok(conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_function_list WHERE name = ?1 COLLATE NOCASE)",
+        [name],
+        |row| row.get(0),
+    )?)
+}
+
+fn register_builtin_function(
+    conn: &Connection,
+    name: &str,
+    builtin: BuiltinFunction,
+) -> rusqlite::Result<()> {
+    use rusqlite::functions::FunctionFlags;
+
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    match builtin {
+        BuiltinFunction::Sha256 => conn.create_scalar_function(name, 1, flags, |ctx| {
+            let digest = match ctx.get_raw(0) {
+                ValueRef::Null => return Ok(None),
+                ValueRef::Blob(bytes) | ValueRef::Text(bytes) => Sha256::digest(bytes),
+                ValueRef::Integer(n) => Sha256::digest(n.to_string()),
+                ValueRef::Real(x) => Sha256::digest(x.to_string()),
+            };
+            Ok(Some(hex::encode(digest)))
+        }),
+        BuiltinFunction::Upper => conn.create_scalar_function(name, 1, flags, |ctx| {
+            Ok(ctx
+                .get::<Option<String>>(0)?
+                .map(|text| text.to_uppercase()))
+        }),
+        BuiltinFunction::NormalizeWhitespace => {
+            conn.create_scalar_function(name, 1, flags, |ctx| {
+                Ok(ctx
+                    .get::<Option<String>>(0)?
+                    .map(|text| text.split_whitespace().collect::<Vec<_>>().join(" ")))
+            })
+        }
+        BuiltinFunction::RegexMatch => {
+            let mut patterns: HashMap<String, regex::Regex> = HashMap::new();
+            conn.create_scalar_function(name, 2, flags, move |ctx| {
+                let Some(text) = ctx.get::<Option<String>>(0)? else {
+                    return Ok(None);
+                };
+                let pattern: String = ctx.get(1)?;
+                cached_regex_match(&mut patterns, &pattern, &text).map(Some)
+            })
+        }
+    }
+}
+
+fn require_sqlcipher() -> Result<(), SidecarError> {
+    if cfg!(feature = "sqlcipher") {
+        Ok(())
+    } else {
+        Err(SidecarError::Encryption(
+            "Database encryption needs a build with the sqlcipher feature".to_string(),
+        ))
+    }
+}
+
+/// Unlock `conn` with `key`. Must run before any other statement.
+fn apply_db_key(conn: &Connection, key: Option<&str>) -> Result<(), SidecarError> {
+    let Some(key) = key else {
+        return Ok(());
+    };
+    require_sqlcipher()?;
+    conn.pragma_update(None, "key", key)?;
+
+    // SQLCipher only checks the key once it reads a page
+    conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+        .map_err(|e| match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::NotADatabase) => {
+                SidecarError::EncryptionFailed("Invalid database password".to_string())
+            }
+            _ => e.into(),
+        })
+}
+
+fn close_connection(conn: Connection) -> Result<(), SidecarError> {
+    // Fold the WAL back into the main file so it can be copied or backed up
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    conn.close().map_err(|(_, e)| e.into())
+}
+
+fn query_page(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+    page_size: usize,
+    page: usize,
+    include_count: bool,
+) -> Result<QueryPage, SidecarError> {
+    let base = first_statement(conn, sql)?;
+    let offset = page
+        .checked_mul(page_size)
+        .ok_or(SidecarError::InvalidState(
+            "Page offset is out of range".to_string(),
+        ))?;
+
+    let rows = query_rows(conn, &paged_sql(base, page_size, offset), params)?;
+
+    let total_count = if include_count {
+        Some(count_rows(conn, base, params)? as u64)
+    } else {
+        None
+    };
+
+    Ok(QueryPage {
+        rows,
+        total_count,
+        page,
+        page_size,
+    })
+}
+
+fn query_paginated(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+    limit: u64,
+    offset: u64,
+) -> Result<PaginatedRows, SidecarError> {
+    let base = first_statement(conn, sql)?;
+    let rows = query_rows(conn, &paged_sql(base, limit, offset), params)?;
+    let total = count_rows(conn, base, params)?;
+
+    Ok(PaginatedRows {
+        rows,
+        total,
+        limit,
+        offset,
+    })
+}
+
+/// `sql` up to the end of its first statement, without the `;` and
+/// whatever comments follow it, ready to wrap in another query
+fn first_statement<'s>(conn: &Connection, sql: &'s str) -> Result<&'s str, SidecarError> {
+    let len = std::os::raw::c_int::try_from(sql.len())
+        .map_err(|_| SidecarError::InvalidState("SQL is too long".to_string()))?;
+    let (mut stmt, mut tail) = (std::ptr::null_mut(), std::ptr::null());
+    // SAFETY: `len` bounds what SQLite reads of `sql`, which outlives the
+    // call, and the statement is finalized before anything else runs
+    let rc = unsafe {
+        let rc = rusqlite::ffi::sqlite3_prepare_v2(
+            conn.handle(),
+            sql.as_ptr().cast(),
+            len,
+            &mut stmt,
+            &mut tail,
+        );
+        rusqlite::ffi::sqlite3_finalize(stmt);
+        rc
+    };
+    if rc != rusqlite::ffi::SQLITE_OK {
+        // Prepared again for rusqlite's error, which carries SQLite's message
+        conn.prepare(sql)?;
+        return Err(SidecarError::Database(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rc),
+            None,
+        )));
+    }
+    if has_trailing_statement(conn, sql) {
+        return Err(SidecarError::MultipleStatements(
+            "Expected a single SQL statement; use db_execute_batch for scripts".to_string(),
+        ));
+    }
+    let end = tail as usize - sql.as_ptr() as usize;
+    Ok(sql
+        .get(..end)
+        .unwrap_or(sql)
+        .trim_end()
+        .trim_end_matches(';'))
+}
+
+/// `base` cut down to `limit` rows from `offset`. It goes on lines of its
+/// own so a trailing `--` comment can't swallow the LIMIT.
+fn paged_sql(base: &str, limit: impl std::fmt::Display, offset: impl std::fmt::Display) -> String {
+    // LIMIT/OFFSET are plain integers, so they're inlined rather than bound
+    // to keep the caller's parameter numbering intact
+    format!("SELECT * FROM (\n{base}\n) LIMIT {limit} OFFSET {offset}")
+}
+
+/// Number of rows `base` returns, counted by SQLite
+fn count_rows(conn: &Connection, base: &str, params: &SqlParams) -> Result<i64, SidecarError> {
+    let mut stmt = conn.prepare(&format!("SELECT COUNT(*) FROM (\n{base}\n)"))?;
+    params.bind(&mut stmt)?;
+    let count = stmt.raw_query().next()?.map_or(Ok(0), |row| row.get(0))?;
+    Ok(count)
+}
+
+fn integrity_report(conn: &Connection) -> Result<IntegrityReport, SidecarError> {
+    let mut errors = conn
+        .prepare("PRAGMA integrity_check")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    if errors == ["ok"] {
+        errors.clear();
+    }
+
+    let foreign_key_violations = conn
+        .prepare("PRAGMA foreign_key_check")?
+        .query_map([], |row| {
+            Ok(ForeignKeyViolation {
+                table: row.get(0)?,
+                rowid: row.get(1)?,
+                parent: row.get(2)?,
+                constraint_index: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut issues = errors.clone();
+    issues.extend(foreign_key_violations.iter().map(|v| {
+        let (table, parent) = (&v.table, &v.parent);
+        match v.rowid {
+            Some(rowid) => format!(
+                "Foreign key violation: {table} row {rowid} references missing row in {parent}"
+            ),
+            None => format!("Foreign key violation: {table} references missing row in {parent}"),
+        }
+    }));
+
+    Ok(IntegrityReport {
+        ok: issues.is_empty(),
+        issues,
+        errors,
+        foreign_key_violations,
+    })
+}
+
+fn integrity_issues(conn: &Connection) -> Result<Vec<String>, SidecarError> {
+    Ok(integrity_report(conn)?.issues)
+}
+
+fn vacuum_database(conn: &Connection) -> Result<u64, SidecarError> {
+    let database_size = |conn: &Connection| -> Result<u64, SidecarError> {
+        let page_count: u64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        Ok(page_count * page_size)
+    };
+
+    ensure_no_transaction(conn)?;
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    let before = database_size(conn)?;
+    conn.execute_batch("VACUUM")?;
+    Ok(before.saturating_sub(database_size(conn)?))
+}
+
+fn run_maintenance(
+    conn: &Connection,
+    vacuum: bool,
+    mut on_progress: impl FnMut(MaintenanceProgress),
+) -> Result<MaintenanceReport, SidecarError> {
+    ensure_no_transaction(conn)?;
+    let size_before = database_file_size(conn)?;
+
+    on_progress(MaintenanceProgress { step: "analyze" });
+    conn.execute_batch("ANALYZE")?;
+    if vacuum {
+        on_progress(MaintenanceProgress { step: "vacuum" });
+        conn.execute_batch("VACUUM")?;
+    }
+    // Last, so the WAL written by the steps above is folded in as well
+    on_progress(MaintenanceProgress { step: "checkpoint" });
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+
+    Ok(MaintenanceReport {
+        size_before,
+        size_after: database_file_size(conn)?,
+    })
+}
+
+/// Statements like VACUUM can't run inside a transaction
+fn ensure_no_transaction(conn: &Connection) -> Result<(), SidecarError> {
+    if conn.is_autocommit() {
+        Ok(())
+    } else {
+        Err(SidecarError::InvalidState(
+            "A transaction is open; commit or roll it back first".to_string(),
+        ))
+    }
+}
+
+/// Bytes on disk for the main database file and its WAL, or the page total
+/// for in-memory databases
+fn database_file_size(conn: &Connection) -> Result<u64, SidecarError> {
+    let Some(path) = conn.path().filter(|p| !p.is_empty()).map(PathBuf::from) else {
+        let page_count: u64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        return Ok(page_count * page_size);
+    };
+    let wal = std::fs::metadata(sibling_path(&path, "-wal")).map_or(0, |m| m.len());
+    Ok(std::fs::metadata(&path)?.len() + wal)
+}
+
+/// Share of `conn`'s page lookups that hit its page cache, or `None`
+/// before its first lookup
+fn cache_hit_ratio(conn: &Connection) -> Option<f64> {
+    let counter = |op| {
+        let (mut current, mut highwater) = (0, 0);
+        // SAFETY: the handle stays valid while `conn` is borrowed, and
+        // sqlite3_db_status only reads the connection's counters
+        let rc = unsafe {
+            rusqlite::ffi::sqlite3_db_status(conn.handle(), op, &mut current, &mut highwater, 0)
+        };
+        (rc == rusqlite::ffi::SQLITE_OK).then(|| f64::from(current))
+    };
+    let hits = counter(rusqlite::ffi::SQLITE_DBSTATUS_CACHE_HIT)?;
+    let misses = counter(rusqlite::ffi::SQLITE_DBSTATUS_CACHE_MISS)?;
+    (hits + misses > 0.0).then(|| hits / (hits + misses))
+}
+
+fn database_stats(conn: &Connection, exact: bool) -> Result<DbStats, SidecarError> {
+    // Read before the queries below so they don't count towards it
+    let cache_hit_ratio = cache_hit_ratio(conn);
+    let pragma = |name: &str| -> Result<u64, SidecarError> {
+        Ok(conn.query_row(&format!("PRAGMA {name}"), [], |row| row.get(0))?)
+    };
+    let path = conn.path().filter(|p| !p.is_empty()).map(PathBuf::from);
+    let page_size: u32 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+    let page_count = pragma("page_count")?;
+    let size_bytes = u64::from(page_size) * page_count;
+    let (file_size, wal_size) = match &path {
+        Some(path) => (
+            std::fs::metadata(path)?.len(),
+            std::fs::metadata(sibling_path(path, "-wal")).map_or(0, |m| m.len()),
+        ),
+        None => (size_bytes, 0),
+    };
+
+    let names = conn
+        .prepare(
+            "SELECT name FROM sqlite_master
+             WHERE type = 'table' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+               AND coalesce(sql, '') NOT LIKE 'CREATE VIRTUAL%'
+             ORDER BY name",
+        )?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let analyzed = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'sqlite_stat1'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+
+    let mut tables = Vec::with_capacity(names.len());
+    for name in names {
+        let quoted = quote_identifier(&name);
+        let (rows, counted) = if exact {
+            let rows = conn.query_row(&format!("SELECT count(*) FROM {quoted}"), [], |row| {
+                row.get(0)
+            })?;
+            (Some(rows), true)
+        } else {
+            // The first number in each stat is the row count of its table
+            let estimate = if analyzed {
+                conn.query_row(
+                    "SELECT max(CAST(stat AS INTEGER)) FROM sqlite_stat1 WHERE tbl = ?1",
+                    [&name],
+                    |row| row.get::<_, Option<u64>>(0),
+                )?
+            } else {
+                None
+            };
+            // A WITHOUT ROWID table has no rowid to look at
+            let estimate = match estimate {
+                Some(rows) => Some(rows),
+                None => conn
+                    .query_row(&format!("SELECT max(rowid) FROM {quoted}"), [], |row| {
+                        row.get::<_, Option<u64>>(0)
+                    })
+                    .map(|max| Some(max.unwrap_or(0)))
+                    .unwrap_or(None),
+            };
+            (estimate, false)
+        };
+        tables.push(TableStats {
+            name,
+            rows,
+            exact: counted,
+        });
+    }
+
+    // A 32-byte header, then a 24-byte header before each page
+    let wal_frames = wal_size.saturating_sub(32) / (u64::from(page_size) + 24);
+    Ok(DbStats {
+        path: path.map(|p| p.to_string_lossy().into_owned()),
+        file_size,
+        wal_size,
+        wal_frames,
+        page_size,
+        page_count,
+        size_bytes,
+        freelist_count: pragma("freelist_count")?,
+        cache_size: conn.query_row("PRAGMA cache_size", [], |row| row.get(0))?,
+        cache_hit_ratio,
+        journal_mode: conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?,
+        tables,
+    })
+}
+
+fn run_migrations(conn: &Connection, migrations: &[Migration]) -> Result<i64, SidecarError> {
+    for pair in migrations.windows(2) {
+        if pair[1].version <= pair[0].version {
+            return Err(SidecarError::InvalidState(format!(
+                "Migration versions must be strictly increasing: {} follows {}",
+                pair[1].version, pair[0].version
+            )));
+        }
+    }
+
+    let current: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let mut version = current;
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(&migration.up_sql)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        version = migration.version;
+    }
+    if version != current {
+        conn.flush_prepared_statement_cache();
+    }
+
+    Ok(version)
+}
+
+fn is_virtual_table_sql(sql: &str) -> bool {
+    sql.trim_start()
+        .get(..14)
+        .is_some_and(|head| head.eq_ignore_ascii_case("CREATE VIRTUAL"))
+}
+
+/// Whether `name` is one of the tables a virtual table keeps its data in,
+/// such as `notes_fts_data` for `notes_fts`
+fn is_shadow_table(name: &str, virtual_tables: &[&str]) -> bool {
+    virtual_tables.iter().any(|v| {
+        name.strip_prefix(v)
+            .is_some_and(|rest| rest.starts_with('_'))
+    })
+}
+
+/// Tables the backend creates for its own bookkeeping
+const INTERNAL_TABLES: &[&str] = &[
+    "sidecar_state",
+    "credential_accounts",
+    "credential_meta",
+    "kv_store",
+];
+
+fn read_schema(
+    conn: &Connection,
+    include_internal: bool,
+) -> Result<Vec<SchemaTable>, SidecarError> {
+    let entries = conn
+        .prepare(
+            "SELECT name, coalesce(sql, '') FROM sqlite_master WHERE type = 'table' ORDER BY name",
+        )?
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let virtual_tables: Vec<&str> = entries
+        .iter()
+        .filter(|(_, sql)| is_virtual_table_sql(sql))
+        .map(|(name, _)| name.as_str())
+        .collect();
+
+    let mut tables = Vec::new();
+    for (name, sql) in &entries {
+        let internal = name.starts_with("sqlite_")
+            || is_shadow_table(name, &virtual_tables)
+            || INTERNAL_TABLES.contains(&name.as_str());
+        if internal && !include_internal {
+            continue;
+        }
+
+        let columns = conn
+            .prepare("SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?1)")?
+            .query_map([name], |row| {
+                Ok(SchemaColumn {
+                    name: row.get(0)?,
+                    declared_type: row.get(1)?,
+                    not_null: row.get(2)?,
+                    default_value: row.get(3)?,
+                    primary_key: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let mut indexes = Vec::new();
+        let listed = conn
+            .prepare(
+                "SELECT name, \"unique\", origin, partial FROM pragma_index_list(?1) ORDER BY name",
+            )?
+            .query_map([name], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (index, unique, origin, partial) in listed {
+            let columns = conn
+                .prepare("SELECT coalesce(name, '') FROM pragma_index_info(?1) ORDER BY seqno")?
+                .query_map([&index], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            indexes.push(SchemaIndex {
+                name: index,
+                unique,
+                origin,
+                partial,
+                columns,
+            });
+        }
+
+        // Composite keys come back as one row per column, sharing an id
+        let mut foreign_keys: Vec<(i64, SchemaForeignKey)> = Vec::new();
+        let mut stmt = conn.prepare(
+            "SELECT id, \"table\", \"from\", \"to\", on_update, on_delete
+             FROM pragma_foreign_key_list(?1) ORDER BY id, seq",
+        )?;
+        let mut rows = stmt.query([name])?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            if foreign_keys.last().is_none_or(|(last, _)| *last != id) {
+                foreign_keys.push((
+                    id,
+                    SchemaForeignKey {
+                        columns: Vec::new(),
+                        parent_table: row.get(1)?,
+                        parent_columns: Vec::new(),
+                        on_update: row.get(4)?,
+                        on_delete: row.get(5)?,
+                    },
+                ));
+            }
+            let (_, key) = foreign_keys.last_mut().expect("a key was just pushed");
+            key.columns.push(row.get(2)?);
+            key.parent_columns.push(row.get(3)?);
+        }
+
+        tables.push(SchemaTable {
+            name: name.clone(),
+            is_virtual: is_virtual_table_sql(sql),
+            columns,
+            indexes,
+            foreign_keys: foreign_keys.into_iter().map(|(_, key)| key).collect(),
+        });
+    }
+    Ok(tables)
+}
+
+/// Fail with `NotFound` unless `table` is an existing table
+fn ensure_table_exists(conn: &Connection, table: &str) -> Result<(), SidecarError> {
+    let known: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        [table],
+        |row| row.get(0),
+    )?;
+    if !known {
+        return Err(SidecarError::NotFound(format!("Table '{table}'")));
+    }
+    Ok(())
+}
+
+/// Quote an identifier for interpolation into SQL
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn export_table_json(conn: &Connection, table: &str, dest: &Path) -> Result<u64, SidecarError> {
+    ensure_table_exists(conn, table)?;
+
+    let sql = format!("SELECT * FROM {}", quote_identifier(table));
+    let mut out = std::io::BufWriter::new(std::fs::File::create(dest)?);
+    let mut write_error = None;
+    let written = visit_rows(conn, &sql, &SqlParams::Positional(vec![]), |row| {
+        let line = serde_json::to_writer(&mut out, &row)
+            .map_err(SidecarError::from)
+            .and_then(|_| Ok(out.write_all(b"\n")?));
+        match line {
+            Ok(()) => true,
+            Err(err) => {
+                write_error = Some(err);
+                false
+            }
+        }
+    });
+
+    let result = match (written, write_error) {
+        (_, Some(err)) | (Err(err), None) => Err(err),
+        (Ok(written), None) => out.flush().map(|_| written).map_err(SidecarError::from),
+    };
+    if result.is_err() {
+        drop(out);
+        let _ = std::fs::remove_file(dest);
+    }
+    result
+}
+
+fn import_table_json(
+    conn: &Connection,
+    table: &str,
+    src: &Path,
+    on_conflict: &str,
+) -> Result<u64, SidecarError> {
+    let conflict = match on_conflict {
+        "fail" => "FAIL",
+        "ignore" => "IGNORE",
+        "replace" => "REPLACE",
+        other => {
+            return Err(SidecarError::InvalidState(format!(
+                "on_conflict must be \"fail\", \"ignore\" or \"replace\", got \"{other}\""
+            )))
+        }
+    };
+    ensure_table_exists(conn, table)?;
+
+    let reader = std::io::BufReader::new(std::fs::File::open(src)?);
+    let tx = conn.unchecked_transaction()?;
+    let mut columns: Option<Vec<String>> = None;
+    let mut stmt: Option<Statement<'_>> = None;
+    let mut inserted = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_error = |msg: String| {
+            SidecarError::Serialization(serde::de::Error::custom(format!("Line {}: {msg}", i + 1)))
+        };
+        let record = match serde_json::from_str(&line).map_err(|e| line_error(e.to_string()))? {
+            serde_json::Value::Object(record) => record,
+            _ => return Err(line_error("expected a JSON object".to_string())),
+        };
+
+        // The first record decides which columns are imported
+        let columns = columns.get_or_insert_with(|| record.keys().cloned().collect());
+        if let Some(extra) = record.keys().find(|key| !columns.contains(key)) {
+            return Err(line_error(format!(
+                "column '{extra}' is not in the first record"
+            )));
+        }
+        let stmt = match &mut stmt {
+            Some(stmt) => stmt,
+            None => {
+                let placeholders = vec!["?"; columns.len()].join(", ");
+                let names: Vec<String> = columns.iter().map(|c| quote_identifier(c)).collect();
+                stmt.insert(tx.prepare(&format!(
+                    "INSERT OR {conflict} INTO {} ({}) VALUES ({placeholders})",
+                    quote_identifier(table),
+                    names.join(", ")
+                ))?)
+            }
+        };
+
+        for (idx, column) in columns.iter().enumerate() {
+            let value = record.get(column).unwrap_or(&serde_json::Value::Null);
+            stmt.raw_bind_parameter(idx + 1, json_to_sql(value, column)?)?;
+        }
+        inserted += stmt.raw_execute()? as u64;
+    }
+
+    drop(stmt);
+    tx.commit()?;
+    Ok(inserted)
+}
+
+/// One record from an import file, as `(table column, value)` pairs
+type ImportRecord = Vec<(String, Box<dyn rusqlite::ToSql>)>;
+
+fn import_file(
+    conn: &Connection,
+    path: &Path,
+    table: &str,
+    format: ImportFormat,
+    options: &ImportOptions,
+) -> Result<ImportSummary, SidecarError> {
+    ensure_table_exists(conn, table)?;
+    let columns: Vec<String> = conn
+        .prepare(&format!("PRAGMA table_info({})", quote_identifier(table)))?
+        .query_map([], |row| row.get(1))?
+        .collect::<Result<_, _>>()?;
+    // Column names are case-insensitive in SQLite
+    let column_for = |name: &str| -> Result<Option<String>, SidecarError> {
+        match columns.iter().find(|c| c.eq_ignore_ascii_case(name)) {
+            Some(column) => Ok(Some(column.clone())),
+            None if options.ignore_unknown => Ok(None),
+            None => Err(SidecarError::NotFound(format!(
+                "Column '{name}' in table '{table}'"
+            ))),
+        }
+    };
+
+    let text = std::fs::read_to_string(path)?;
+    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+    let records: Box<dyn Iterator<Item = Result<ImportRecord, SidecarError>> + '_> = match format {
+        ImportFormat::Csv => {
+            let mut lines = CsvRecords::new(text);
+            let header = match lines.next() {
+                Some(header) => header?,
+                None => Vec::new(),
+            };
+            let targets = header
+                .iter()
+                .map(|name| column_for(name.as_deref().unwrap_or("")))
+                .collect::<Result<Vec<_>, _>>()?;
+            Box::new(lines.map(move |fields| {
+                let fields = fields?;
+                if fields.len() != targets.len() {
+                    return Err(import_error(format!(
+                        "expected {} fields, found {}",
+                        targets.len(),
+                        fields.len()
+                    )));
+                }
+                Ok(targets
+                    .iter()
+                    .zip(fields)
+                    .filter_map(|(column, value)| {
+                        let value: Box<dyn rusqlite::ToSql> = Box::new(value);
+                        Some((column.clone()?, value))
+                    })
+                    .collect())
+            }))
+        }
+        ImportFormat::Json => {
+            let rows: Vec<serde_json::Value> = serde_json::from_str(text)?;
+            Box::new(rows.into_iter().map(|row| {
+                let serde_json::Value::Object(record) = row else {
+                    return Err(import_error("expected a JSON object".to_string()));
+                };
+                let mut values = ImportRecord::new();
+                for (key, value) in &record {
+                    if let Some(column) = column_for(key)? {
+                        values.push((column, json_to_sql(value, key)?));
+                    }
+                }
+                Ok(values)
+            }))
+        }
+    };
+
+    let mut summary = ImportSummary {
+        inserted: 0,
+        skipped: 0,
+        errors: Vec::new(),
+    };
+    let mut tx = conn.unchecked_transaction()?;
+    for (i, record) in records.enumerate() {
+        let row = i as u64 + 1;
+        match record.and_then(|record| insert_import_record(&tx, table, &record)) {
+            Ok(()) => summary.inserted += 1,
+            Err(err) if options.best_effort => {
+                summary.skipped += 1;
+                if summary.errors.len() < MAX_REPORTED_IMPORT_ERRORS {
+                    summary.errors.push(ImportRowError {
+                        row,
+                        message: err.to_string(),
+                    });
+                }
+            }
+            Err(err) => {
+                let kept = summary.inserted / IMPORT_BATCH_SIZE * IMPORT_BATCH_SIZE;
+                return Err(SidecarError::InvalidState(format!(
+                    "Row {row}: {err} ({kept} rows were imported before it)"
+                )));
+            }
+        }
+        if row.is_multiple_of(IMPORT_BATCH_SIZE) {
+            tx.commit()?;
+            tx = conn.unchecked_transaction()?;
+        }
+    }
+    tx.commit()?;
+    Ok(summary)
+}
+
+fn import_error(message: String) -> SidecarError {
+    SidecarError::Serialization(serde::de::Error::custom(message))
+}
+
+fn insert_import_record(
+    conn: &Connection,
+    table: &str,
+    record: &ImportRecord,
+) -> Result<(), SidecarError> {
+    if record.is_empty() {
+        conn.prepare_cached(&format!(
+            "INSERT INTO {} DEFAULT VALUES",
+            quote_identifier(table)
+        ))?
+        .raw_execute()?;
+        return Ok(());
+    }
+    let names: Vec<String> = record.iter().map(|(c, _)| quote_identifier(c)).collect();
+    let placeholders = vec!["?"; record.len()].join(", ");
+    let mut stmt = conn.prepare_cached(&format!(
+        "INSERT INTO {} ({}) VALUES ({placeholders})",
+        quote_identifier(table),
+        names.join(", ")
+    ))?;
+    for (idx, (_, value)) in record.iter().enumerate() {
+        stmt.raw_bind_parameter(idx + 1, value)?;
+    }
+    stmt.raw_execute()?;
+    Ok(())
+}
+
+/// Records of an RFC 4180 CSV document. Empty unquoted fields are `None`;
+/// blank lines are skipped.
+struct CsvRecords<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> CsvRecords<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            chars: text.chars().peekable(),
+        }
+    }
+}
+
+impl Iterator for CsvRecords<'_> {
+    type Item = Result<Vec<Option<String>>, SidecarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.chars.peek()?;
+            let mut fields = Vec::new();
+            let mut field = String::new();
+            let (mut quoted, mut in_quotes) = (false, false);
+            loop {
+                match self.chars.next() {
+                    None if in_quotes => {
+                        return Some(Err(import_error("unterminated quoted field".to_string())))
+                    }
+                    Some('"') if in_quotes => {
+                        if self.chars.next_if_eq(&'"').is_some() {
+                            field.push('"');
+                        } else {
+                            in_quotes = false;
+                        }
+                    }
+                    Some(c) if in_quotes => field.push(c),
+                    Some('"') if field.is_empty() && !quoted => (quoted, in_quotes) = (true, true),
+                    Some(',') => {
+                        let field = std::mem::take(&mut field);
+                        fields.push((quoted || !field.is_empty()).then_some(field));
+                        quoted = false;
+                    }
+                    Some('\r') if matches!(self.chars.peek(), Some('\n') | None) => {}
+                    None | Some('\n') => break,
+                    Some(c) => field.push(c),
+                }
+            }
+            if fields.is_empty() && field.is_empty() && !quoted {
+                continue;
+            }
+            fields.push((quoted || !field.is_empty()).then_some(field));
+            return Some(Ok(fields));
+        }
+    }
+}
+
+fn create_index(
+    conn: &Connection,
+    index: &str,
+    table: &str,
+    columns: &[String],
+    unique: bool,
+) -> Result<(), SidecarError> {
+    ensure_table_exists(conn, table)?;
+    if columns.is_empty() {
+        return Err(SidecarError::InvalidState(
+            "An index needs at least one column".to_string(),
+        ));
+    }
+    let known: Vec<String> = conn
+        .prepare("SELECT name FROM pragma_table_info(?1)")?
+        .query_map([table], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    if let Some(missing) = columns.iter().find(|c| !known.contains(c)) {
+        return Err(SidecarError::NotFound(format!(
+            "Column '{missing}' in table '{table}'"
+        )));
+    }
+
+    let cols = columns
+        .iter()
+        .map(|c| quote_identifier(c))
+        .collect::<Vec<_>>()
+        .join(", ");
+    conn.execute_batch(&format!(
+        "CREATE {}INDEX {} ON {} ({cols})",
+        if unique { "UNIQUE " } else { "" },
+        quote_identifier(index),
+        quote_identifier(table),
+    ))?;
+    Ok(())
+}
+
+fn drop_index(conn: &Connection, index: &str) -> Result<(), SidecarError> {
+    let known: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = ?1)",
+        [index],
+        |row| row.get(0),
+    )?;
+    if !known {
+        return Err(SidecarError::NotFound(format!("Index '{index}'")));
+    }
+    conn.execute_batch(&format!("DROP INDEX {}", quote_identifier(index)))?;
+    Ok(())
+}
+
+fn create_fts_index(
+    conn: &Connection,
+    table: &str,
+    columns: &[String],
+) -> Result<String, SidecarError> {
+    ensure_table_exists(conn, table)?;
+    if columns.is_empty() {
+        return Err(SidecarError::InvalidState(
+            "An FTS index needs at least one column".to_string(),
+        ));
+    }
+    let known: Vec<String> = conn
+        .prepare(&format!("PRAGMA table_info({})", quote_identifier(table)))?
+        .query_map([], |row| row.get(1))?
+        .collect::<Result<_, _>>()?;
+    if let Some(missing) = columns.iter().find(|c| !known.contains(c)) {
+        return Err(SidecarError::NotFound(format!(
+            "Column '{missing}' in table '{table}'"
+        )));
+    }
+
+    let index = format!("{table}_fts");
+    let (t, fts) = (quote_identifier(table), quote_identifier(&index));
+    let cols = columns
+        .iter()
+        .map(|c| quote_identifier(c))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let new_cols = columns
+        .iter()
+        .map(|c| format!("new.{}", quote_identifier(c)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let old_cols = columns
+        .iter()
+        .map(|c| format!("old.{}", quote_identifier(c)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let trigger = |suffix: &str| quote_identifier(&format!("{index}_{suffix}"));
+    let (ai, ad, au) = (trigger("ai"), trigger("ad"), trigger("au"));
+    let content = table.replace('\'', "''");
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS {fts} USING fts5({cols}, content='{content}', content_rowid='rowid');
+         CREATE TRIGGER IF NOT EXISTS {ai} AFTER INSERT ON {t} BEGIN
+             INSERT INTO {fts}(rowid, {cols}) VALUES (new.rowid, {new_cols});
+         END;
+         CREATE TRIGGER IF NOT EXISTS {ad} AFTER DELETE ON {t} BEGIN
+             INSERT INTO {fts}({fts}, rowid, {cols}) VALUES ('delete', old.rowid, {old_cols});
+         END;
+         CREATE TRIGGER IF NOT EXISTS {au} AFTER UPDATE ON {t} BEGIN
+             INSERT INTO {fts}({fts}, rowid, {cols}) VALUES ('delete', old.rowid, {old_cols});
+             INSERT INTO {fts}(rowid, {cols}) VALUES (new.rowid, {new_cols});
+         END;
+         INSERT INTO {fts}({fts}) VALUES ('rebuild');"
+    ))?;
+    tx.commit()?;
+
+    Ok(index)
+}
+
+fn fts_search(
+    conn: &Connection,
+    index: &str,
+    query: &str,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<serde_json::Value>, SidecarError> {
+    ensure_table_exists(conn, index)?;
+    let fts = quote_identifier(index);
+    let sql = format!(
+        "SELECT rowid, *, snippet({fts}, -1, '<mark>', '</mark>', '…', 12) AS snippet, rank
+         FROM {fts} WHERE {fts} MATCH ?1 ORDER BY rank LIMIT {limit} OFFSET {offset}"
+    );
+    let search = |q: &str| query_rows(conn, &sql, &SqlParams::Positional(vec![q.into()]));
+
+    match search(query) {
+        Err(SidecarError::Database(rusqlite::Error::SqliteFailure(_, Some(msg))))
+            if msg.starts_with("fts5:") || msg.contains("unterminated string") =>
+        {
+            // Treat whatever the user typed as one literal phrase
+            search(&format!("\"{}\"", query.replace('"', "\"\"")))
+        }
+        result => result,
+    }
+}
+
+fn backup_database(
+    state: &AppState,
+    dest: &Path,
+    overwrite: bool,
+    pacing: BackupPacing,
+    progress: impl FnMut(BackupProgress),
+) -> Result<BackupSummary, SidecarError> {
+    let source =
+        state.with_conn(|conn| Ok(conn.path().filter(|p| !p.is_empty()).map(PathBuf::from)))?;
+    // SQLCipher only backs up between databases that share a key
+    let key = state.db_key.lock().clone();
+
+    if dest.exists() && !overwrite {
+        return Err(SidecarError::InvalidState(format!(
+            "Backup destination already exists: {}",
+            dest.display()
+        )));
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut partial_name = dest.file_name().unwrap_or_default().to_os_string();
+    partial_name.push(".partial");
+    let partial = dest.with_file_name(partial_name);
+    if partial.exists() {
+        std::fs::remove_file(&partial)?;
+    }
+
+    let copied = match source {
+        // A separate read-only connection lets the backup run without
+        // holding the app's connection lock for its whole duration
+        Some(path) => Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(SidecarError::from)
+            .and_then(|src| {
+                apply_db_key(&src, key.as_deref())?;
+                backup_to(&src, &partial, key.as_deref(), pacing, progress)
+            }),
+        None => state.with_conn(|conn| backup_to(conn, &partial, key.as_deref(), pacing, progress)),
+    };
+    let pages_copied = match copied {
+        Ok(pages) => pages,
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+
+    std::fs::rename(&partial, dest)?;
+    Ok(BackupSummary {
+        pages_copied,
+        size_bytes: std::fs::metadata(dest)?.len(),
+    })
+}
+
+fn set_auto_backup(
+    state: &AppState,
+    config: Option<AutoBackupConfig>,
+    now: Instant,
+) -> Result<(), SidecarError> {
+    if config
+        .as_ref()
+        .is_some_and(|c| c.interval_secs == 0 || c.keep_last == 0)
+    {
+        return Err(SidecarError::InvalidState(
+            "Automatic backups need an interval and keep_last of at least 1".to_string(),
+        ));
+    }
+    *state.auto_backup.lock() = config.map(|config| AutoBackup {
+        config,
+        last_attempt: now,
+        last_fingerprint: None,
+    });
+    Ok(())
+}
+
+/// Make an automatic backup if one is due and the database has changed
+/// since the last one. Returns the event to emit, if a backup was tried.
+fn run_auto_backup(state: &AppState, now: Instant) -> Option<AutoBackupEvent> {
+    let (config, last_fingerprint) = {
+        let mut auto = state.auto_backup.lock();
+        let auto = auto.as_mut()?;
+        let interval = Duration::from_secs(auto.config.interval_secs);
+        if now.saturating_duration_since(auto.last_attempt) < interval {
+            return None;
+        }
+        auto.last_attempt = now;
+        (auto.config.clone(), auto.last_fingerprint)
+    };
+
+    // `data_version` only moves for commits made by other connections, so
+    // the writer's own changes and schema edits are counted separately
+    let fingerprint = state.db.lock().as_ref().map(|conn| {
+        conn.query_row(
+            "SELECT data_version, total_changes(), schema_version
+             FROM pragma_data_version, pragma_schema_version",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+    });
+    let fingerprint = match fingerprint {
+        None => return None,
+        Some(Ok(fingerprint)) if Some(fingerprint) == last_fingerprint => return None,
+        Some(fingerprint) => fingerprint.ok(),
+    };
+
+    let dir = Path::new(&config.directory);
+    let dest = dir.join(format!(
+        "{AUTO_BACKUP_PREFIX}{}.db",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+    ));
+    let result = backup_database(state, &dest, false, BackupPacing::default(), |_| {})
+        .and_then(|summary| prune_auto_backups(dir, config.keep_last).map(|_| summary));
+    let path = dest.to_string_lossy().into_owned();
+    Some(match result {
+        Ok(summary) => {
+            if let Some(auto) = state.auto_backup.lock().as_mut() {
+                auto.last_fingerprint = fingerprint;
+            }
+            AutoBackupEvent {
+                path,
+                size_bytes: Some(summary.size_bytes),
+                error: None,
+            }
+        }
+        Err(e) => AutoBackupEvent {
+            path,
+            size_bytes: None,
+            error: Some(e.to_string()),
+        },
+    })
+}
+
+/// Delete all but the newest `keep_last` automatic backups in `dir`.
+/// Other files are left alone.
+fn prune_auto_backups(dir: &Path, keep_last: usize) -> Result<(), SidecarError> {
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(AUTO_BACKUP_PREFIX) && name.ends_with(".db") {
+            backups.push(name);
+        }
+    }
+    // Timestamps sort in the order they were taken
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep_last);
+    for name in &backups[..excess] {
+        std::fs::remove_file(dir.join(name))?;
+    }
+    Ok(())
+}
+
+fn backup_to(
+    src: &Connection,
+    dest: &Path,
+    key: Option<&str>,
+    pacing: BackupPacing,
+    mut progress: impl FnMut(BackupProgress),
+) -> Result<u64, SidecarError> {
+    let mut dst = Connection::open(dest)?;
+    apply_db_key(&dst, key)?;
+    let pages_copied = {
+        let backup = Backup::new(src, &mut dst)?;
+        loop {
+            let step = backup.step(pacing.pages_per_step)?;
+            let p = backup.progress();
+            progress(BackupProgress {
+                pages_done: p.pagecount - p.remaining,
+                pages_total: p.pagecount,
+            });
+            match step {
+                StepResult::Done => break p.pagecount as u64,
+                StepResult::More => std::thread::sleep(pacing.step_delay),
+                _ => std::thread::sleep(BACKUP_RETRY_DELAY),
+            }
+        }
+    };
+
+    // Leave a single self-contained file rather than a WAL-mode database
+    dst.query_row("PRAGMA journal_mode=DELETE", [], |_| Ok(()))?;
+    dst.close().map_err(|(_, e)| SidecarError::from(e))?;
+
+    Ok(pages_copied)
+}
+
+/// Check that `path` is a readable SQLite database and return its `user_version`
+fn validate_database_file(path: &Path, key: Option<&str>) -> Result<i64, SidecarError> {
+    let mut header = [0u8; 16];
+    std::fs::File::open(path)?
+        .read_exact(&mut header)
+        .map_err(|_| corrupt_database_error(rusqlite::ffi::SQLITE_NOTADB, "file is too short"))?;
+    // Encrypted databases have no plaintext header; the key check stands in
+    if key.is_none() && &header != b"SQLite format 3\0" {
+        return Err(corrupt_database_error(
+            rusqlite::ffi::SQLITE_NOTADB,
+            "missing SQLite header",
+        ));
+    }
+
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    apply_db_key(&conn, key)?;
+    // Checking an index that uses our collation needs it defined
+    register_sql_functions(&conn)?;
+    let issues = conn
+        .prepare("PRAGMA integrity_check")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    if issues != ["ok"] {
+        return Err(corrupt_database_error(
+            rusqlite::ffi::SQLITE_CORRUPT,
+            &issues.join("; "),
+        ));
+    }
+
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+fn corrupt_database_error(code: std::os::raw::c_int, message: &str) -> SidecarError {
+    SidecarError::Database(rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(code),
+        Some(message.to_string()),
+    ))
+}
+
+fn restore_database(state: &AppState, src: &Path) -> Result<i64, SidecarError> {
+    let key = state.db_key.lock().clone();
+    let version = validate_database_file(src, key.as_deref())?;
+    state.lock_key();
+    let swapped = swap_database_file(state, src, key.as_deref());
+    forget_nonce_reservation(state);
+    // Readers went away with the old file; point new ones at whatever is live now
+    let readers = state.configure_connections();
+    swapped?;
+    readers?;
+    Ok(version)
+}
+
+fn swap_database_file(state: &AppState, src: &Path, key: Option<&str>) -> Result<(), SidecarError> {
+    let mut db = state.db.lock();
+    if db.is_some() {
+        return Err(SidecarError::InvalidState(
+            "Close the database with db_close before restoring over it".to_string(),
+        ));
+    }
+    let mut closed_path = state.closed_path.lock();
+    let live_path = closed_path.clone().ok_or(SidecarError::InvalidState(
+        "No closed database to restore over".to_string(),
+    ))?;
+
+    let mut backup_name = live_path.file_name().unwrap_or_default().to_os_string();
+    backup_name.push(format!(
+        ".{}.bak",
+        chrono::Utc::now().format("%Y%m%d%H%M%S")
+    ));
+    let backup_path = live_path.with_file_name(backup_name);
+    std::fs::rename(&live_path, &backup_path)?;
+
+    let restored = std::fs::copy(src, &live_path)
+        .map_err(SidecarError::from)
+        .and_then(|_| open_connection(&live_path, key));
+    match restored {
+        Ok(conn) => {
+            *db = Some(conn);
+            closed_path.take();
+            Ok(())
+        }
+        Err(e) => {
+            // Put the original back so the user is never left without a database
+            let _ = std::fs::remove_file(&live_path);
+            std::fs::rename(&backup_path, &live_path)?;
+            Err(e)
+        }
+    }
+}
+
+fn relocate_database(
+    state: &AppState,
+    new_dir: &Path,
+    location_file: &Path,
+) -> Result<PathBuf, SidecarError> {
+    let key = state.db_key.lock().clone();
+    let moved = {
+        let mut db = state.db.lock();
+        let conn = db.as_ref().ok_or(SidecarError::InvalidState(
+            "Database not initialized".to_string(),
+        ))?;
+        let Some(live_path) = conn.path().filter(|p| !p.is_empty()).map(PathBuf::from) else {
+            return Err(SidecarError::InvalidState(
+                "Cannot relocate an in-memory database".to_string(),
+            ));
+        };
+        let same_dir = match (
+            live_path.parent().map(Path::canonicalize),
+            new_dir.canonicalize(),
+        ) {
+            (Some(Ok(current)), Ok(new)) => current == new,
+            _ => false,
+        };
+        if same_dir {
+            return Ok(live_path);
+        }
+
+        let new_path = new_dir.join(live_path.file_name().unwrap_or_default());
+        if new_path.exists() {
+            return Err(SidecarError::InvalidState(format!(
+                "A database already exists at {}",
+                new_path.display()
+            )));
+        }
+        std::fs::create_dir_all(new_dir)?;
+
+        state.readers.lock().take();
+        let conn = db.take().expect("checked above");
+        if let Err(e) = close_connection(conn) {
+            *db = Some(open_connection(&live_path, key.as_deref())?);
+            return Err(e);
+        }
+
+        let copied = std::fs::copy(&live_path, &new_path)
+            .map_err(SidecarError::from)
+            .and_then(|_| validate_database_file(&new_path, key.as_deref()))
+            .and_then(|_| open_connection(&new_path, key.as_deref()))
+            .and_then(|conn| {
+                let location = serde_json::to_vec(&DatabaseLocation {
+                    path: new_path.clone(),
+                })?;
+                write_through_partial(location_file, |out| Ok(out.write_all(&location)?))?;
+                Ok(conn)
+            });
+        match copied {
+            Ok(conn) => {
+                *db = Some(conn);
+                for suffix in ["", "-wal", "-shm"] {
+                    let _ = std::fs::remove_file(sibling_path(&live_path, suffix));
+                }
+                Ok(new_path)
+            }
+            Err(e) => {
+                for suffix in ["", "-wal", "-shm"] {
+                    let _ = std::fs::remove_file(sibling_path(&new_path, suffix));
+                }
+                *db = Some(open_connection(&live_path, key.as_deref())?);
+                Err(e)
+            }
+        }
+    };
+    // Readers went away with the old connection
+    let readers = state.configure_connections();
+    let moved = moved?;
+    readers?;
+    state.lock_key();
+    Ok(moved)
+}
+
+/// `path` with `suffix` appended to its file name
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn recover_database(state: &AppState) -> Result<RecoveryReport, SidecarError> {
+    let key = state.db_key.lock().clone();
+    let recovered = {
+        let mut db = state.db.lock();
+        state.readers.lock().take();
+        recover_live_database(&mut db, key.as_deref())
+    };
+    forget_nonce_reservation(state);
+    let readers = state.configure_connections();
+    let report = recovered?;
+    readers?;
+    Ok(report)
+}
+
+fn recover_live_database(
+    db: &mut Option<Connection>,
+    key: Option<&str>,
+) -> Result<RecoveryReport, SidecarError> {
+    let conn = db.as_ref().ok_or(SidecarError::InvalidState(
+        "Database not initialized".to_string(),
+    ))?;
+    let Some(live_path) = conn.path().filter(|p| !p.is_empty()).map(PathBuf::from) else {
+        return Err(SidecarError::InvalidState(
+            "Cannot recover an in-memory database".to_string(),
+        ));
+    };
+
+    let tmp_path = sibling_path(&live_path, ".recovering");
+    if tmp_path.exists() {
+        std::fs::remove_file(&tmp_path)?;
+    }
+    let tables = match salvage_into(conn, &tmp_path, key) {
+        Ok(tables) => tables,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+
+    let mut corrupt_path = sibling_path(&live_path, ".corrupt");
+    if corrupt_path.exists() {
+        let stamp = chrono::Utc::now().format("%Y%m%d%H%M%S");
+        corrupt_path = sibling_path(&live_path, &format!(".{stamp}.corrupt"));
+    }
+
+    if let Some(conn) = db.take() {
+        // A damaged file may refuse to checkpoint; its WAL moves with it below
+        let _ = close_connection(conn);
+    }
+    if let Err(e) = std::fs::rename(&live_path, &corrupt_path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        *db = Some(open_connection(&live_path, key)?);
+        return Err(e.into());
+    }
+    for suffix in ["-wal", "-shm"] {
+        let side = sibling_path(&live_path, suffix);
+        if side.exists() {
+            std::fs::rename(&side, sibling_path(&corrupt_path, suffix))?;
+        }
+    }
+
+    let reopened = std::fs::rename(&tmp_path, &live_path)
+        .map_err(SidecarError::from)
+        .and_then(|_| open_connection(&live_path, key));
+    match reopened {
+        Ok(conn) => *db = Some(conn),
+        Err(e) => {
+            // Put the damaged file back rather than leave nothing in place
+            let _ = std::fs::remove_file(&live_path);
+            std::fs::rename(&corrupt_path, &live_path)?;
+            *db = Some(open_connection(&live_path, key)?);
+            return Err(e);
+        }
+    }
+
+    Ok(RecoveryReport {
+        tables,
+        corrupt_path: corrupt_path.to_string_lossy().into_owned(),
+    })
+}
+
+/// Copy the schema and every readable row of `src` into a new database at `dest`
+fn salvage_into(
+    src: &Connection,
+    dest: &Path,
+    key: Option<&str>,
+) -> Result<Vec<SalvagedTable>, SidecarError> {
+    let schema = src
+        .prepare(
+            "SELECT type, name, sql FROM sqlite_master
+             WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+             ORDER BY rowid",
+        )?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let is_virtual = |sql: &str| is_virtual_table_sql(sql);
+    let virtual_tables: Vec<&str> = schema
+        .iter()
+        .filter(|(kind, _, sql)| kind == "table" && is_virtual(sql))
+        .map(|(_, name, _)| name.as_str())
+        .collect();
+    // Shadow tables are recreated by their virtual table
+    let is_shadow = |name: &str| is_shadow_table(name, &virtual_tables);
+
+    let out = Connection::open(dest)?;
+    apply_db_key(&out, key)?;
+    register_sql_functions(&out)?;
+    // Rows are copied table by table, so parents may arrive after children
+    out.execute_batch("PRAGMA foreign_keys=OFF;")?;
+    let version: i64 = src.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    out.pragma_update(None, "user_version", version)?;
+
+    let mut tables = Vec::new();
+    for (_, name, sql) in schema
+        .iter()
+        .filter(|(kind, name, sql)| kind == "table" && !is_virtual(sql) && !is_shadow(name))
+    {
+        out.execute_batch(sql)?;
+        let (rows_recovered, complete) = copy_readable_rows(src, &out, name)?;
+        tables.push(SalvagedTable {
+            table: name.clone(),
+            rows_recovered,
+            complete,
+        });
+    }
+
+    for (kind, name, sql) in &schema {
+        if kind == "table" && (!is_virtual(sql) || is_shadow(name)) {
+            continue;
+        }
+        // A damaged index definition shouldn't cost the salvaged rows
+        if out.execute_batch(sql).is_ok() && kind == "table" {
+            let rebuild = format!(
+                "INSERT INTO {0}({0}) VALUES('rebuild')",
+                quote_identifier(name)
+            );
+            let _ = out.execute_batch(&rebuild);
+        }
+    }
+
+    out.close().map_err(|(_, e)| SidecarError::from(e))?;
+    Ok(tables)
+}
+
+/// Copy rows of `table` until the end or the first unreadable row. Returns
+/// the number copied and whether the whole table was read.
+fn copy_readable_rows(
+    src: &Connection,
+    out: &Connection,
+    table: &str,
+) -> Result<(u64, bool), SidecarError> {
+    let quoted = quote_identifier(table);
+    let mut select = match src.prepare(&format!("SELECT * FROM {quoted}")) {
+        Ok(stmt) => stmt,
+        Err(_) => return Ok((0, false)),
+    };
+    let width = select.column_count();
+    let columns = select
+        .column_names()
+        .into_iter()
+        .map(quote_identifier)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let placeholders = vec!["?"; width].join(", ");
+    let tx = out.unchecked_transaction()?;
+    let mut insert = tx.prepare(&format!(
+        "INSERT INTO {quoted} ({columns}) VALUES ({placeholders})"
+    ))?;
+
+    let mut rows = select.query([])?;
+    let mut copied = 0;
+    let complete = loop {
+        let row = match rows.next() {
+            Ok(Some(row)) => row,
+            Ok(None) => break true,
+            Err(_) => break false,
+        };
+        let values = (0..width)
+            .map(|i| row.get::<_, rusqlite::types::Value>(i))
+            .collect::<Result<Vec<_>, _>>();
+        let Ok(values) = values else {
+            break false;
+        };
+        insert.execute(rusqlite::params_from_iter(values))?;
+        copied += 1;
+    };
+
+    drop(insert);
+    tx.commit()?;
+    Ok((copied, complete))
+}
+
+fn rekey_database(
+    state: &AppState,
+    old_password: &str,
+    new_key: DbPassphrase,
+) -> Result<(), SidecarError> {
+    require_sqlcipher()?;
+    match state.db_key.lock().as_deref() {
+        Some(active) if active == old_password => {}
+        Some(_) => {
+            return Err(SidecarError::EncryptionFailed(
+                "Old password does not match the database's".to_string(),
+            ))
+        }
+        None => {
+            return Err(SidecarError::InvalidState(
+                "Database is not encrypted; use db_encrypt first".to_string(),
+            ))
+        }
+    }
+
+    state.with_conn(|conn| Ok(conn.pragma_update(None, "rekey", &*new_key)?))?;
+    *state.db_key.lock() = Some(new_key);
+    // Pooled readers still hold the old key
+    state.configure_connections()
+}
+
+fn encrypt_database(state: &AppState, key: DbPassphrase) -> Result<(), SidecarError> {
+    require_sqlcipher()?;
+    if state.db_key.lock().is_some() {
+        return Err(SidecarError::InvalidState(
+            "Database is already encrypted".to_string(),
+        ));
+    }
+
+    {
+        let mut db = state.db.lock();
+        let conn = db.as_ref().ok_or(SidecarError::InvalidState(
+            "Database not initialized".to_string(),
+        ))?;
+        let Some(live_path) = conn.path().filter(|p| !p.is_empty()).map(PathBuf::from) else {
+            return Err(SidecarError::InvalidState(
+                "Cannot encrypt an in-memory database".to_string(),
+            ));
+        };
+
+        let mut tmp_name = live_path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".encrypting");
+        let tmp_path = live_path.with_file_name(tmp_name);
+        if tmp_path.exists() {
+            std::fs::remove_file(&tmp_path)?;
+        }
+
+        let exported = (|| -> Result<(), SidecarError> {
+            conn.execute(
+                "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+                rusqlite::params![tmp_path.to_string_lossy(), &*key],
+            )?;
+            let exported = conn
+                .query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))
+                .and_then(|_| {
+                    let version: i64 =
+                        conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+                    conn.pragma_update(
+                        Some(rusqlite::DatabaseName::Attached("encrypted")),
+                        "user_version",
+                        version,
+                    )
+                });
+            conn.execute_batch("DETACH DATABASE encrypted")?;
+            Ok(exported?)
+        })();
+        if let Err(e) = exported {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        state.readers.lock().take();
+        if let Some(conn) = db.take() {
+            close_connection(conn)?;
+        }
+        std::fs::rename(&tmp_path, &live_path)?;
+        *db = Some(open_connection(&live_path, Some(&*key))?);
+    }
+
+    *state.db_key.lock() = Some(key);
+    state.configure_connections()
+}
+
+/// Statement parameters, bound either by position (`?`, `?1`) or by name
+/// (`:name`, `@name`, `$name`)
+enum SqlParams {
+    Positional(Vec<serde_json::Value>),
+    Named(serde_json::Map<String, serde_json::Value>),
+}
+
+impl SqlParams {
+    fn from_args(
+        params: Option<Vec<serde_json::Value>>,
+        params_named: Option<serde_json::Map<String, serde_json::Value>>,
+    ) -> Result<Self, SidecarError> {
+        match (params, params_named) {
+            (Some(_), Some(_)) => Err(SidecarError::InvalidState(
+                "Provide either params or params_named, not both".to_string(),
+            )),
+            (_, Some(named)) => Ok(SqlParams::Named(named)),
+            (params, None) => Ok(SqlParams::Positional(params.unwrap_or_default())),
+        }
+    }
+
+    fn bind(&self, stmt: &mut Statement<'_>) -> Result<(), SidecarError> {
+        match self {
+            SqlParams::Positional(values) => {
+                let expected = stmt.parameter_count();
+                if values.len() != expected {
+                    return Err(
+                        rusqlite::Error::InvalidParameterCount(values.len(), expected).into(),
+                    );
+                }
+                for (i, value) in values.iter().enumerate() {
+                    stmt.raw_bind_parameter(i + 1, json_to_sql(value, i + 1)?)?;
+                }
+            }
+            SqlParams::Named(values) => {
+                let mut bound = vec![false; stmt.parameter_count()];
+                for (name, value) in values {
+                    let idx = named_parameter_index(stmt, name)?.ok_or_else(|| {
+                        SidecarError::InvalidState(format!(
+                            "Unknown named parameter '{name}': no matching placeholder in statement"
+                        ))
+                    })?;
+                    stmt.raw_bind_parameter(idx, json_to_sql(value, name)?)?;
+                    bound[idx - 1] = true;
+                }
+                // Unbound parameters would silently become NULL
+                if let Some(idx) = bound.iter().position(|b| !b) {
+                    return Err(SidecarError::InvalidState(
+                        match stmt.parameter_name(idx + 1) {
+                            Some(name) => format!("Missing value for named parameter {name}"),
+                            None => format!(
+                                "Positional placeholder ?{} cannot be bound from params_named",
+                                idx + 1
+                            ),
+                        },
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a named parameter to its index. Keys may include the SQL prefix
+/// (`":id"`) or omit it (`"id"`), in which case `:`, `@` and `$` are tried.
+fn named_parameter_index(stmt: &Statement<'_>, name: &str) -> Result<Option<usize>, SidecarError> {
+    if name.starts_with([':', '@', '$']) {
+        return Ok(stmt.parameter_index(name)?);
+    }
+    for prefix in [':', '@', '$'] {
+        if let Some(idx) = stmt.parameter_index(&format!("{prefix}{name}"))? {
+            return Ok(Some(idx));
+        }
+    }
+    Ok(None)
+}
+
+/// Pauses between attempts when a statement keeps hitting a locked database
+const BUSY_RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(200),
+];
+
+/// Run `f`, trying again with backoff while it fails with `Busy`
+///
+/// The busy timeout already waits inside SQLite; this covers locks held
+/// longer than that, and the cases where WAL mode reports `SQLITE_BUSY`
+/// without waiting at all.
+fn retry_busy<T>(mut f: impl FnMut() -> Result<T, SidecarError>) -> Result<T, SidecarError> {
+    for delay in BUSY_RETRY_DELAYS {
+        match f() {
+            Err(SidecarError::Busy(_)) => std::thread::sleep(delay),
+            result => return result,
+        }
+    }
+    f()
+}
+
+/// Run `f`, interrupting it from a watchdog thread once `timeout_ms` elapses
+fn with_deadline<T>(
+    conn: &Connection,
+    timeout_ms: Option<u64>,
+    f: impl FnOnce(&Connection) -> Result<T, SidecarError>,
+) -> Result<T, SidecarError> {
+    let Some(timeout_ms) = timeout_ms else {
+        return f(conn);
+    };
+
+    let interrupt = conn.get_interrupt_handle();
+    let (done, finished) = std::sync::mpsc::channel::<()>();
+    let watchdog = std::thread::spawn(move || {
+        let expired = finished
+            .recv_timeout(Duration::from_millis(timeout_ms))
+            .is_err();
+        if expired {
+            interrupt.interrupt();
+        }
+        expired
+    });
+
+    let result = f(conn);
+    drop(done);
+    let expired = watchdog.join().unwrap_or(false);
+
+    match result {
+        Err(SidecarError::Cancelled(_)) if expired => Err(SidecarError::Timeout(format!(
+            "Query exceeded {timeout_ms} ms and was interrupted"
+        ))),
+        result => result,
+    }
+}
+
+/// Run one statement through the connection's statement cache
+///
+/// SQLite re-prepares a cached statement itself when the schema changes
+/// under it, but the cache is flushed whenever a statement here changes
+/// `schema_version`, so statements against dropped tables aren't kept.
+fn execute_statement(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+) -> Result<usize, SidecarError> {
+    let schema = schema_version(conn)?;
+    let changed = {
+        let mut stmt = prepare_counted(conn, sql)?;
+        params.bind(&mut stmt)?;
+        stmt.raw_execute()?
+    };
+    if schema_version(conn)? != schema {
+        conn.flush_prepared_statement_cache();
+    }
+    Ok(changed)
+}
+
+/// Returns the number of statements run
+fn execute_script(conn: &Connection, script: &str) -> Result<usize, SidecarError> {
+    let tx = conn.unchecked_transaction()?;
+    let mut batch = rusqlite::Batch::new(&tx, script);
+    let mut count = 0;
+    // Each statement is prepared only once the previous one has run, so
+    // later ones can use tables created earlier in the script
+    while let Some(mut stmt) = batch.next()? {
+        let mut rows = stmt.raw_query();
+        while rows.next()?.is_some() {}
+        count += 1;
+    }
+    tx.commit()?;
+    // Scripts are mostly DDL; drop statements prepared against the old schema
+    conn.flush_prepared_statement_cache();
+    Ok(count)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SavepointAction {
+    Open,
+    Release,
+    RollbackTo,
+}
+
+fn run_savepoint(
+    conn: &Connection,
+    action: SavepointAction,
+    name: &str,
+) -> Result<(), SidecarError> {
+    // The name is interpolated, so only plain identifiers are accepted
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(SidecarError::InvalidState(format!(
+            "Invalid savepoint name '{name}': use letters, digits and underscores"
+        )));
+    }
+
+    let sql = match action {
+        SavepointAction::Open => format!("SAVEPOINT {name}"),
+        _ if conn.is_autocommit() => {
+            return Err(SidecarError::InvalidState(format!(
+                "No transaction is open, so there is no savepoint '{name}'"
+            )))
+        }
+        SavepointAction::Release => format!("RELEASE SAVEPOINT {name}"),
+        SavepointAction::RollbackTo => format!("ROLLBACK TO SAVEPOINT {name}"),
+    };
+    conn.execute_batch(&sql).map_err(|e| match &e {
+        rusqlite::Error::SqliteFailure(_, Some(message))
+            if message.starts_with("no such savepoint") =>
+        {
+            SidecarError::NotFound(format!("Savepoint '{name}'"))
+        }
+        _ => e.into(),
+    })
+}
+
+/// `prepare_cached`, counting whether the statement was already cached
+fn prepare_counted<'c>(
+    conn: &'c Connection,
+    sql: &str,
+) -> Result<CachedStatement<'c>, SidecarError> {
+    let stmt = conn.prepare_cached(sql)?;
+    // A statement fresh from the parser has never been run
+    let counter = if stmt.get_status(StatementStatus::Run) > 0 {
+        &STATEMENT_CACHE_HITS
+    } else {
+        // SQLite only prepares up to the first statement and would quietly
+        // drop the rest; run ones were checked on their first use
+        if has_trailing_statement(conn, sql) {
+            stmt.discard();
+            return Err(SidecarError::MultipleStatements(
+                "Expected a single SQL statement; use db_execute_batch for scripts".to_string(),
+            ));
+        }
+        &STATEMENT_CACHE_MISSES
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    Ok(stmt)
+}
+
+/// Whether `sql` has anything but comments and semicolons after its first
+/// statement. Text SQLite can't parse counts, since it isn't a comment.
+fn has_trailing_statement(conn: &Connection, sql: &str) -> bool {
+    let mut batch = rusqlite::Batch::new(conn, sql);
+    // The caller has already prepared the first statement
+    let _ = batch.next();
+    !matches!(batch.next(), Ok(None))
+}
+
+fn schema_version(conn: &Connection) -> Result<i64, SidecarError> {
+    Ok(conn
+        .prepare_cached("PRAGMA schema_version")?
+        .query_row([], |row| row.get(0))?)
+}
+
+/// Run a query as a stream of `StreamEvent`s. `emit` returns false once
+/// the receiving window is gone, which ends the stream with an error.
+fn stream_query(
+    state: &AppState,
+    stream_id: String,
+    sql: &str,
+    params: &SqlParams,
+    batch_size: usize,
+    mut emit: impl FnMut(StreamEvent) -> bool,
+) -> Result<u64, SidecarError> {
+    if batch_size == 0 {
+        return Err(SidecarError::InvalidState(
+            "batch_size must be at least 1".to_string(),
+        ));
+    }
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    {
+        let mut streams = state.query_streams.lock();
+        if streams.contains_key(&stream_id) {
+            return Err(SidecarError::InvalidState(format!(
+                "Stream '{stream_id}' is already running"
+            )));
+        }
+        streams.insert(stream_id.clone(), cancelled.clone());
+    }
+
+    let mut receiver_gone = false;
+    let delivered = state.with_reader(|conn| {
+        stream_rows(conn, sql, params, batch_size, |rows| {
+            if cancelled.load(Ordering::Relaxed) {
+                return false;
+            }
+            let batch = QueryBatch {
+                stream_id: stream_id.clone(),
+                rows,
+            };
+            receiver_gone = !emit(StreamEvent::Batch(batch));
+            !receiver_gone
+        })
+    });
+    state.query_streams.lock().remove(&stream_id);
+
+    let result = match delivered {
+        Ok(_) if receiver_gone => Err(SidecarError::InvalidState(format!(
+            "Stream '{stream_id}' stopped: the window is no longer listening"
+        ))),
+        result => result,
+    };
+    emit(match &result {
+        Ok(total_rows) => StreamEvent::Done(QueryDone {
+            stream_id,
+            total_rows: *total_rows,
+            cancelled: cancelled.load(Ordering::Relaxed),
+        }),
+        Err(err) => StreamEvent::Error(QueryError {
+            stream_id,
+            error: err.to_string(),
+        }),
+    });
+    result
+}
+
+fn execute_returning(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+) -> Result<ExecuteResult, SidecarError> {
+    let rows_affected = execute_statement(conn, sql, params)?;
+    let keyword = sql.split_whitespace().next().unwrap_or("");
+    let inserts = ["INSERT", "REPLACE"]
+        .iter()
+        .any(|k| keyword.eq_ignore_ascii_case(k));
+
+    Ok(ExecuteResult {
+        rows_affected,
+        last_insert_rowid: (inserts && rows_affected > 0).then(|| conn.last_insert_rowid()),
+    })
+}
+
+fn query_rows(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+) -> Result<Vec<serde_json::Value>, SidecarError> {
+    let mut results = Vec::new();
+    visit_rows(conn, sql, params, |row| {
+        results.push(row);
+        true
+    })?;
+    Ok(results)
+}
+
+fn query_with_meta(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+) -> Result<QueryWithMeta, SidecarError> {
+    let rows = query_rows(conn, sql, params)?;
+    // The statement is in the cache now, so this doesn't parse it again
+    let columns = conn
+        .prepare_cached(sql)?
+        .columns()
+        .iter()
+        .map(|column| ColumnMeta {
+            name: column.name().to_string(),
+            decl_type: column.decl_type().map(str::to_string),
+        })
+        .collect();
+    Ok(QueryWithMeta { columns, rows })
+}
+
+fn query_one(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+    strict: bool,
+) -> Result<Option<serde_json::Value>, SidecarError> {
+    let mut first = None;
+    let visited = visit_rows(conn, sql, params, |row| {
+        let is_first = first.is_none();
+        first.get_or_insert(row);
+        // Strict mode reads one row further to check there isn't another
+        strict && is_first
+    })?;
+    if strict && visited > 1 {
+        return Err(SidecarError::InvalidState(
+            "Query returned more than one row".to_string(),
+        ));
+    }
+    Ok(first)
+}
+
+/// Feed each result row to `f` as a JSON object without collecting them.
+/// Stops early when `f` returns false. Returns the number of rows visited.
+fn visit_rows(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+    mut f: impl FnMut(serde_json::Value) -> bool,
+) -> Result<u64, SidecarError> {
+    let mut stmt = prepare_counted(conn, sql)?;
+    params.bind(&mut stmt)?;
+
+    let column_names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+
+    let mut rows = stmt.raw_query();
+    let mut visited = 0;
+    while let Some(row) = rows.next()? {
+        let mut map = serde_json::Map::new();
+        for (i, name) in column_names.iter().enumerate() {
+            map.insert(name.clone(), row_value_to_json(row, i)?);
+        }
+        visited += 1;
+        if !f(serde_json::Value::Object(map)) {
+            break;
+        }
+    }
+    Ok(visited)
+}
+
+fn export_query(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+    path: &Path,
+    format: ExportFormat,
+    mut progress: impl FnMut(ExportProgress),
+) -> Result<ExportSummary, SidecarError> {
+    let mut stmt = prepare_counted(conn, sql)?;
+    params.bind(&mut stmt)?;
+    let column_names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+
+    let mut rows_written = 0;
+    write_through_partial(path, |out| {
+        if let ExportFormat::Csv = format {
+            let header: Vec<_> = column_names.iter().map(|name| csv_field(name)).collect();
+            writeln!(out, "{}", header.join(","))?;
+        }
+
+        let mut rows = stmt.raw_query();
+        while let Some(row) = rows.next()? {
+            match format {
+                ExportFormat::Csv => {
+                    let fields = (0..column_names.len())
+                        .map(|i| Ok(csv_field(&csv_value(row.get_ref(i)?)).into_owned()))
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+                    writeln!(out, "{}", fields.join(","))?;
+                }
+                ExportFormat::Jsonl => {
+                    let mut map = serde_json::Map::new();
+                    for (i, name) in column_names.iter().enumerate() {
+                        map.insert(name.clone(), row_value_to_json(row, i)?);
+                    }
+                    serde_json::to_writer(&mut *out, &map)?;
+                    writeln!(out)?;
+                }
+            }
+            rows_written += 1;
+            if rows_written % EXPORT_PROGRESS_INTERVAL == 0 {
+                progress(ExportProgress { rows_written });
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(ExportSummary {
+        rows_written,
+        size_bytes: std::fs::metadata(path)?.len(),
+    })
+}
+
+/// Text of a column for CSV. NULL is an empty field and BLOBs are base64.
+fn csv_value(value: ValueRef<'_>) -> String {
+    match value {
+        ValueRef::Null => String::new(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => f.to_string(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+        ValueRef::Blob(bytes) => BASE64.encode(bytes),
+    }
+}
+
+/// Quote a CSV field if it contains a delimiter, quote or line break
+/// (RFC 4180)
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
+
+/// Run a query and hand its rows to `on_batch` in chunks of `batch_size`.
+/// Stops after any batch for which `on_batch` returns false. Returns the
+/// number of rows delivered.
+fn stream_rows(
+    conn: &Connection,
+    sql: &str,
+    params: &SqlParams,
+    batch_size: usize,
+    mut on_batch: impl FnMut(Vec<serde_json::Value>) -> bool,
+) -> Result<u64, SidecarError> {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut delivered = 0;
+    let mut open = true;
+    visit_rows(conn, sql, params, |row| {
+        batch.push(row);
+        if batch.len() < batch_size {
+            return true;
+        }
+        delivered += batch.len() as u64;
+        open = on_batch(std::mem::replace(
+            &mut batch,
+            Vec::with_capacity(batch_size),
+        ));
+        open
+    })?;
+
+    if open && !batch.is_empty() {
+        delivered += batch.len() as u64;
+        on_batch(batch);
+    }
+    Ok(delivered)
+}
+
+/// Convert a JSON parameter for binding. `{ "$blob": "<base64>" }` (or
+/// `{ "__blob_base64": "<base64>" }`) binds as a BLOB; `param` names the
+/// parameter in decoding errors.
+fn json_to_sql(
+    value: &serde_json::Value,
+    param: impl std::fmt::Display,
+) -> Result<Box<dyn rusqlite::ToSql>, SidecarError> {
+    if let Some(encoded) = blob_tag(value) {
+        let bytes = encoded
+            .as_str()
+            .and_then(|s| BASE64.decode(s).ok())
+            .ok_or_else(|| {
+                SidecarError::Serialization(serde::de::Error::custom(format!(
+                    "Parameter {param}: blob value must be a base64 string"
+                )))
+            })?;
+        return Ok(Box::new(bytes));
+    }
+
+    Ok(match value {
+        serde_json::Value::Null => Box::new(rusqlite::types::Null),
+        serde_json::Value::Bool(b) => Box::new(*b),
+        serde_json::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Box::new(i)
+            } else if n.is_u64() {
+                // SQLite integers are signed 64-bit; binding this as REAL
+                // would quietly round it
+                return Err(SidecarError::InvalidState(format!(
+                    "Parameter {param}: {n} is outside the 64-bit integer range; pass it as a string"
+                )));
+            } else if let Some(f) = n.as_f64() {
+                Box::new(f)
+            } else {
+                Box::new(n.to_string())
+            }
+        }
+        serde_json::Value::String(s) => Box::new(s.clone()),
+        _ => Box::new(value.to_string()),
+    })
+}
+
+/// The payload of a `{ "$blob": ... }` or `{ "__blob_base64": ... }`
+/// wrapper, if `value` is one
+fn blob_tag(value: &serde_json::Value) -> Option<&serde_json::Value> {
+    match value {
+        serde_json::Value::Object(map) if map.len() == 1 => {
+            map.get(BLOB_TAG).or_else(|| map.get(BLOB_TAG_ALIAS))
+        }
+        _ => None,
+    }
+}
+
+/// Key of the object that wraps BLOB values, so they can't be mistaken for
+/// ordinary strings: `{ "$blob": "<base64>" }`
+const BLOB_TAG: &str = "$blob";
+
+/// Also accepted when binding parameters
+const BLOB_TAG_ALIAS: &str = "__blob_base64";
+
+/// Convert a column to JSON according to its storage class
+///
+/// INTEGER values are emitted as exact JSON numbers; JavaScript's
+/// `JSON.parse` will round anything beyond 2^53, so callers storing larger
+/// ids should keep them in TEXT columns. Non-finite REALs become `null`.
+fn row_value_to_json(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<serde_json::Value> {
+    Ok(match row.get_ref(idx)? {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => serde_json::Value::Number(i.into()),
+        ValueRef::Real(f) => serde_json::json!(f),
+        ValueRef::Text(text) => {
+            serde_json::Value::String(String::from_utf8_lossy(text).into_owned())
+        }
+        ValueRef::Blob(bytes) => serde_json::json!({ BLOB_TAG: BASE64.encode(bytes) }),
+    })
+}
+
+// ============================================================================
+// Change Notifications
+// ============================================================================
+
+/// More changes than this in one commit are announced per table and
+/// operation instead of per row
+const CHANGE_BATCH_LIMIT: usize = 256;
+
+/// A committed row change, emitted in batches as `db:changed`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableChange {
+    pub table: String,
+    /// `"insert"`, `"update"` or `"delete"`
+    pub op: &'static str,
+    /// `None` when a large commit was summarised; reload the whole table
+    pub rowid: Option<i64>,
+}
+
+type ChangeListener = Arc<dyn Fn(Vec<TableChange>) + Send + Sync>;
+
+/// A committed row change in any table, emitted one at a time as
+/// `db:change` while change notifications are enabled
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RowChange {
+    /// `"insert"`, `"update"` or `"delete"`
+    pub operation: &'static str,
+    pub table: String,
+    pub rowid: i64,
+}
+
+type RowChangeListener = Arc<dyn Fn(RowChange) + Send + Sync>;
+
+/// Row changes seen by the writer's update hook, held until the transaction
+/// commits
+#[derive(Default)]
+struct ChangeTracker {
+    subscribed: HashSet<String>,
+    pending: Vec<TableChange>,
+    listener: Option<ChangeListener>,
+    /// Set while `db_set_change_notifications` is on
+    row_listener: Option<RowChangeListener>,
+    row_pending: Vec<RowChange>,
+}
+
+/// Start announcing committed changes to `table` as `db:changed` events
+#[tauri::command]
+fn db_subscribe_changes(state: State<'_, Arc<AppState>>, table: String) {
+    state.changes.lock().subscribed.insert(table);
+}
+
+/// Stop announcing changes to `table`
+#[tauri::command]
+fn db_unsubscribe_changes(state: State<'_, Arc<AppState>>, table: String) {
+    state.changes.lock().subscribed.remove(&table);
+}
+
+/// Emit a `db:change` event for every committed row change in the main
+/// database, in any table, or stop doing so
+///
+/// Every row is its own event, so a bulk import produces one per row;
+/// `db_subscribe_changes` batches them instead.
+///
+/// This never touches the connection. The SQLite hooks are installed once
+/// per writer connection by `configure_connections` and live exactly as
+/// long as it does: `db_close` and `db_init` drop them with the old
+/// connection and the new one gets its own. They hold only an `Arc` of the
+/// tracker, never the connection, so they are `'static` and the listener
+/// set here outlives any number of reopens. The hooks run on whichever
+/// thread is executing the statement, while that thread holds the `db`
+/// lock, so a listener must not call back into `AppState`.
+#[tauri::command]
+fn db_set_change_notifications(
+    state: State<'_, Arc<AppState>>,
+    app_handle: AppHandle,
+    enabled: bool,
+) -> Result<(), SidecarError> {
+    let listener: Option<RowChangeListener> = enabled.then(|| {
+        Arc::new(move |change: RowChange| {
+            let _ = app_handle.emit("db:change", change);
+        }) as RowChangeListener
+    });
+    set_row_change_listener(&state, listener);
+    Ok(())
+}
+
+fn set_row_change_listener(state: &AppState, listener: Option<RowChangeListener>) {
+    let mut changes = state.changes.lock();
+    if listener.is_none() {
+        // Don't deliver rows from a transaction still open when this was
+        // turned off
+        changes.row_pending.clear();
+    }
+    changes.row_listener = listener;
+}
+
+fn install_change_hooks(conn: &Connection, tracker: &Arc<Mutex<ChangeTracker>>) {
+    let changes = tracker.clone();
+    conn.update_hook(Some(
+        move |action: rusqlite::hooks::Action, _db: &str, table: &str, rowid: i64| {
+            let op = match action {
+                rusqlite::hooks::Action::SQLITE_INSERT => "insert",
+                rusqlite::hooks::Action::SQLITE_UPDATE => "update",
+                rusqlite::hooks::Action::SQLITE_DELETE => "delete",
+                _ => return,
+            };
+            let mut changes = changes.lock();
+            if changes.subscribed.contains(table) {
+                changes.pending.push(TableChange {
+                    table: table.to_string(),
+                    op,
+                    rowid: Some(rowid),
+                });
+            }
+            if changes.row_listener.is_some() {
+                changes.row_pending.push(RowChange {
+                    operation: op,
+                    table: table.to_string(),
+                    rowid,
+                });
+            }
+        },
+    ));
+
+    let changes = tracker.clone();
+    conn.commit_hook(Some(move || {
+        let (batch, listener, rows, row_listener) = {
+            let mut changes = changes.lock();
+            (
+                std::mem::take(&mut changes.pending),
+                changes.listener.clone(),
+                std::mem::take(&mut changes.row_pending),
+                changes.row_listener.clone(),
+            )
+        };
+        if let Some(listener) = listener.filter(|_| !batch.is_empty()) {
+            listener(summarize_changes(batch));
+        }
+        if let Some(row_listener) = row_listener {
+            rows.into_iter().for_each(|row| row_listener(row));
+        }
+        // Returning true would turn the commit into a rollback
+        false
+    }));
+
+    let changes = tracker.clone();
+    conn.rollback_hook(Some(move || {
+        let mut changes = changes.lock();
+        changes.pending.clear();
+        changes.row_pending.clear();
+    }));
+}
+
+/// Collapse an oversized batch to one entry per table and operation
+fn summarize_changes(batch: Vec<TableChange>) -> Vec<TableChange> {
+    if batch.len() <= CHANGE_BATCH_LIMIT {
+        return batch;
+    }
+    let mut summary: Vec<TableChange> = Vec::new();
+    for change in batch {
+        if !summary
+            .iter()
+            .any(|s| s.table == change.table && s.op == change.op)
+        {
+            summary.push(TableChange {
+                rowid: None,
+                ..change
+            });
+        }
+    }
+    summary
+}
+
+// ============================================================================
+// Encryption Commands
+// ============================================================================
+
+/// Initialize encryption with a password-derived key
+///
+/// The key is derived with Argon2id, using a salt and cost stored in the
+/// open database, so a database must be open first. The first time this
+/// runs against a database, the parameters are generated from `kdf` (or
+/// the default cost, about a quarter second on a laptop) and a known value
+/// is stored encrypted under the key so `verify_password` can check the
+/// password later.
+///
+/// Databases set up before Argon2id used a single SHA-256 pass. For those
+/// the values in `fields` and in the key-value store are re-encrypted from
+/// the old key to the new one in one transaction. Pass every encrypted
+/// column so none is left behind; in databases older than the stored
+/// check value, they are also what the password is checked against. A
+/// wrong password fails with `EncryptionFailed` and changes nothing.
+#[tauri::command]
+fn init_encryption(
+    state: State<'_, Arc<AppState>>,
+    password: String,
+    kdf: Option<KdfCost>,
+    fields: Option<Vec<EncryptedField>>,
+) -> Result<(), SidecarError> {
+    if let Some(cost) = kdf {
+        cost.argon2()?;
+        *state.kdf_cost.lock() = cost;
+    }
+    unlock_with_password(&state, &password, &fields.unwrap_or_default())
+}
+
+/// Whether `password` matches the one the database's encryption was set up
+/// with. Needs an open database that `init_encryption` has run against.
+#[tauri::command]
+fn verify_password(
+    state: State<'_, Arc<AppState>>,
+    password: String,
+) -> Result<bool, SidecarError> {
+    check_password(&state, &password)
+}
+
+/// Hash a login password with Argon2id, returning a PHC string such as
+/// `$argon2id$v=19$...` that embeds its salt and parameters
+///
+/// This is for checking who is at the keyboard and is unrelated to the
+/// encryption key; the hash can be stored anywhere.
+#[tauri::command(async)]
+fn hash_password(password: String) -> Result<String, SidecarError> {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    argon2::Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| SidecarError::Encryption(e.to_string()))
+}
+
+/// Whether `password` matches a hash from `hash_password`. A hash that
+/// can't be parsed is an `Encryption` error rather than a mismatch.
+#[tauri::command(async)]
+fn verify_password_hash(password: String, hash: String) -> Result<bool, SidecarError> {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+
+    let parsed = PasswordHash::new(&hash)
+        .map_err(|e| SidecarError::Encryption(format!("Invalid password hash: {e}")))?;
+    // The hashes are compared in constant time
+    match argon2::Argon2::default().verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(SidecarError::Encryption(e.to_string())),
+    }
+}
+
+/// Encrypt data for storage
+///
+/// `aad` is authenticated but not encrypted: pass something like the
+/// record ID to bind the ciphertext to it. The same `aad` must be given
+/// to `decrypt_data`.
+#[tauri::command]
+fn encrypt_data(
+    state: State<'_, Arc<AppState>>,
+    plaintext: String,
+    aad: Option<String>,
+) -> Result<String, SidecarError> {
+    let aad = aad.unwrap_or_default();
+    state.with_key(|key| encrypt_with_key(key, &state.next_nonce()?, &plaintext, aad.as_bytes()))
+}
+
+/// Decrypt data from storage. Fails if `aad` differs from the one used to
+/// encrypt.
+#[tauri::command]
+fn decrypt_data(
+    state: State<'_, Arc<AppState>>,
+    ciphertext: String,
+    aad: Option<String>,
+) -> Result<String, SidecarError> {
+    let aad = aad.unwrap_or_default();
+    state.with_key(|key| decrypt_with_key(key, &ciphertext, aad.as_bytes()))
+}
+
+/// Encrypt binary data, returning raw `nonce || ciphertext` bytes that can
+/// be stored directly in a BLOB column
+#[tauri::command]
+fn encrypt_bytes(state: State<'_, Arc<AppState>>, data: Vec<u8>) -> Result<Vec<u8>, SidecarError> {
+    state.with_key(|key| encrypt_bytes_with_key(key, &state.next_nonce()?, &data, b""))
+}
+
+/// Decrypt bytes produced by `encrypt_bytes`
+#[tauri::command]
+fn decrypt_bytes(state: State<'_, Arc<AppState>>, data: Vec<u8>) -> Result<Vec<u8>, SidecarError> {
+    state.with_key(|key| decrypt_bytes_with_key(key, &data, b""))
+}
+
+/// Encrypt a single JSON value, such as one sensitive field of a record
+///
+/// The value is serialized before encryption, so numbers, objects and
+/// `null` come back from `decrypt_field` exactly as given. Returns base64
+/// like `encrypt_data`.
+#[tauri::command]
+fn encrypt_field(
+    state: State<'_, Arc<AppState>>,
+    value: serde_json::Value,
+) -> Result<String, SidecarError> {
+    state.with_key(|key| encrypt_field_with_key(key, &state.next_nonce()?, &value))
+}
+
+/// Decrypt a value produced by `encrypt_field`
+#[tauri::command]
+fn decrypt_field(
+    state: State<'_, Arc<AppState>>,
+    ciphertext: String,
+) -> Result<serde_json::Value, SidecarError> {
+    state.with_key(|key| decrypt_field_with_key(key, &ciphertext))
+}
+
+/// Encrypt a whole JSON document. Same format as `encrypt_field`, under the
+/// name callers look for when sealing an object rather than one field.
+#[tauri::command]
+fn encrypt_json(
+    state: State<'_, Arc<AppState>>,
+    value: serde_json::Value,
+) -> Result<String, SidecarError> {
+    encrypt_field(state, value)
+}
+
+/// Decrypt a document from `encrypt_json` or `encrypt_field`. Plaintext
+/// that isn't JSON is a `Serialization` error.
+#[tauri::command]
+fn decrypt_json(
+    state: State<'_, Arc<AppState>>,
+    ciphertext: String,
+) -> Result<serde_json::Value, SidecarError> {
+    decrypt_field(state, ciphertext)
+}
+
+/// Encrypt the file at `src_path` into `dest_path`
+///
+/// The file is processed in 64 KiB chunks, so attachments of any size can
+/// be encrypted without loading them into memory. The output starts with a
+/// header (magic bytes, format version and a base nonce); each chunk uses
+/// the base nonce combined with its index. `dest_path` only appears once
+/// the whole file has been written.
+#[tauri::command(async)]
+fn encrypt_file(
+    state: State<'_, Arc<AppState>>,
+    src_path: String,
+    dest_path: String,
+) -> Result<(), SidecarError> {
+    // A copy of the key, so a large file doesn't hold up other encryption
+    let key = state.with_key(|key| Ok(ZeroizeKey(*key)))?;
+    encrypt_file_with_key(
+        &key,
+        &state.next_nonce()?,
+        Path::new(&src_path),
+        Path::new(&dest_path),
+    )
+}
+
+/// Decrypt a file written by `encrypt_file`
+///
+/// Every chunk is authenticated, and the last one is marked as such, so a
+/// modified, reordered or truncated file fails with an `Encryption` error
+/// and no output is left behind.
+#[tauri::command(async)]
+fn decrypt_file(
+    state: State<'_, Arc<AppState>>,
+    src_path: String,
+    dest_path: String,
+) -> Result<(), SidecarError> {
+    let key = state.with_key(|key| Ok(ZeroizeKey(*key)))?;
+    decrypt_file_with_key(&key, Path::new(&src_path), Path::new(&dest_path))
+}
+
+/// Ciphertext paired with an HMAC tag over it, so integrity can be checked
+/// with `is_data_intact` without decrypting
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatedBlob {
+    pub ciphertext_b64: String,
+    pub hmac_b64: String,
+}
+
+/// HMAC-SHA256 `data` with the encryption key and return the base64 tag
+#[tauri::command]
+fn hmac_sign(state: State<'_, Arc<AppState>>, data: String) -> Result<String, SidecarError> {
+    state.with_key(|key| Ok(BASE64.encode(hmac_tag(key, data.as_bytes())?)))
+}
+
+/// Check a tag from `hmac_sign`. A tag that isn't valid base64 doesn't match.
+#[tauri::command]
+fn hmac_verify(
+    state: State<'_, Arc<AppState>>,
+    data: String,
+    tag: String,
+) -> Result<bool, SidecarError> {
+    state.with_key(|key| hmac_matches(key, data.as_bytes(), &tag))
+}
+
+/// Whether `blob.hmac_b64` is the tag for `blob.ciphertext_b64`
+#[tauri::command]
+fn is_data_intact(
+    state: State<'_, Arc<AppState>>,
+    blob: AuthenticatedBlob,
+) -> Result<bool, SidecarError> {
+    state.with_key(|key| hmac_matches(key, blob.ciphertext_b64.as_bytes(), &blob.hmac_b64))
+}
+
+/// Forget the encryption key (e.g. when the app locks). Same as
+/// `lock_encryption`: the key is zeroed, and encrypt/decrypt fail until
+/// `init_encryption` is called again.
+#[tauri::command]
+fn clear_encryption_key(state: State<'_, Arc<AppState>>) {
+    state.lock_key();
+}
+
+/// Lock encryption: zero the key in memory and forget it, so
+/// `init_encryption` has to be called with the password again
+#[tauri::command]
+fn lock_encryption(state: State<'_, Arc<AppState>>) -> Result<(), SidecarError> {
+    state.lock_key();
+    Ok(())
+}
+
+/// Lock encryption automatically once the key has gone unused for
+/// `seconds`. `None` turns auto-lock off. An `encryption:locked` event is
+/// emitted when it fires.
+#[tauri::command]
+fn set_auto_lock_timeout(state: State<'_, Arc<AppState>>, seconds: Option<u64>) {
+    *state.key_last_used.lock() = Instant::now();
+    *state.auto_lock_after.lock() = seconds.map(Duration::from_secs);
+}
+
+/// The next nonce counter value, for diagnostics
+#[tauri::command]
+fn get_nonce_counter(state: State<'_, Arc<AppState>>) -> u64 {
+    state.nonce_counter.lock().next
+}
+
+/// Change the encryption password, re-encrypting `ciphertexts` under the new key
+///
+/// Data lives in arbitrary tables, so the frontend passes in the values it
+/// holds and writes the returned ciphertexts back (in the same order) within
+/// one transaction. The active key is only swapped once every value has
+/// been re-encrypted. Values stored with `kv_set` are re-encrypted in place.
+///
+/// The new key gets a fresh salt and the current Argon2id cost, which is
+/// how to raise the cost of an existing database.
+#[tauri::command]
+fn rotate_encryption_key(
+    state: State<'_, Arc<AppState>>,
+    old_password: String,
+    new_password: String,
+    ciphertexts: Vec<String>,
+) -> Result<Vec<String>, SidecarError> {
+    rotate_key(&state, &old_password, &new_password, &ciphertexts)
+}
+
+/// A column holding values from `encrypt_data` or `encrypt_bytes`, with the
+/// column that identifies each row
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptedField {
+    pub table: String,
+    pub column: String,
+    pub id_column: String,
+    /// Column holding the `aad` each value was encrypted with, if any. Its
+    /// text is used as the `aad`; NULL means none.
+    #[serde(default)]
+    pub aad_column: Option<String>,
+}
+
+/// Change the encryption password and re-encrypt `fields` in place
+///
+/// Every non-NULL value in each field is decrypted with the old key,
+/// re-encrypted with the new one and written back, all in one transaction.
+/// Text values are treated as `encrypt_data` output and blobs as
+/// `encrypt_bytes` output; give a field's `aadColumn` if its values were
+/// encrypted with an `aad`. Nothing changes unless every value succeeds.
+/// Values stored with `kv_set` are always included. Returns the number of
+/// values re-encrypted.
+#[tauri::command]
+fn rotate_encrypted_fields(
+    state: State<'_, Arc<AppState>>,
+    old_password: String,
+    new_password: String,
+    fields: Vec<EncryptedField>,
+) -> Result<u32, SidecarError> {
+    rotate_fields(&state, &old_password, &new_password, &fields)
+}
+
+/// The field key as derived before Argon2id: one SHA-256 pass with a fixed
+/// salt. Only used to read data from databases without stored
+/// `KdfParams`, on the way to re-encrypting it.
+fn derive_key(password: &str) -> ZeroizeKey {
+    derive_key_with_salt(password, b"sidecar-encryption-salt-v1")
+}
+
+/// Argon2id cost for the field key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KdfCost {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfCost {
+    /// RFC 9106's second recommended option, single-threaded
+    fn default() -> Self {
+        KdfCost {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfCost {
+    fn argon2(&self) -> Result<argon2::Argon2<'static>, SidecarError> {
+        let params =
+            argon2::Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+                .map_err(|e| SidecarError::Encryption(format!("Invalid KDF cost: {e}")))?;
+        Ok(argon2::Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            params,
+        ))
+    }
+}
+
+/// How a database's field key is derived from the password, kept as JSON
+/// in `sidecar_state`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KdfParams {
+    algorithm: String,
+    #[serde(flatten)]
+    cost: KdfCost,
+    /// Base64
+    salt: String,
+}
+
+const KDF_PARAMS_KEY: &str = "kdf_params";
+const KDF_ALGORITHM: &str = "argon2id";
+
+impl KdfParams {
+    /// Parameters with a new random salt
+    fn generate(cost: KdfCost) -> Self {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill(&mut salt);
+        KdfParams {
+            algorithm: KDF_ALGORITHM.to_string(),
+            cost,
+            salt: BASE64.encode(salt),
+        }
+    }
+
+    fn derive(&self, password: &str) -> Result<ZeroizeKey, SidecarError> {
+        if self.algorithm != KDF_ALGORITHM {
+            return Err(SidecarError::Encryption(format!(
+                "Unsupported key derivation '{}'",
+                self.algorithm
+            )));
+        }
+        let salt = BASE64
+            .decode(&self.salt)
+            .map_err(|e| SidecarError::Encryption(format!("Invalid KDF salt: {e}")))?;
+        let mut key = ZeroizeKey([0u8; 32]);
+        self.cost
+            .argon2()?
+            .hash_password_into(password.as_bytes(), &salt, &mut key.0)
+            .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+        Ok(key)
+    }
+}
+
+fn stored_kdf_params(conn: &Connection) -> Result<Option<KdfParams>, SidecarError> {
+    read_state_value::<String>(conn, KDF_PARAMS_KEY)?
+        .map(|json| Ok(serde_json::from_str(&json)?))
+        .transpose()
+}
+
+fn store_kdf_params(conn: &Connection, params: &KdfParams) -> Result<(), SidecarError> {
+    write_state_value(conn, KDF_PARAMS_KEY, serde_json::to_string(params)?)
+}
+
+/// The field key for `password` under `params`, or the legacy derivation
+/// when the database has none
+fn password_key(params: Option<&KdfParams>, password: &str) -> Result<ZeroizeKey, SidecarError> {
+    match params {
+        Some(params) => params.derive(password),
+        None => Ok(derive_key(password)),
+    }
+}
+
+fn derive_key_with_salt(password: &str, salt: &[u8]) -> ZeroizeKey {
+    let mut hasher = Sha256::new();
+    hasher.update(password.as_bytes());
+    hasher.update(salt);
+    let mut result = hasher.finalize();
+
+    let mut key = ZeroizeKey([0u8; 32]);
+    key.0.copy_from_slice(&result);
+    result.as_mut_slice().zeroize();
+    key
+}
+
+fn encrypt_with_key(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    plaintext: &str,
+    aad: &[u8],
+) -> Result<String, SidecarError> {
+    Ok(BASE64.encode(encrypt_bytes_with_key(
+        key,
+        nonce,
+        plaintext.as_bytes(),
+        aad,
+    )?))
+}
+
+fn decrypt_with_key(key: &[u8; 32], ciphertext: &str, aad: &[u8]) -> Result<String, SidecarError> {
+    let combined = BASE64
+        .decode(ciphertext)
+        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+
+    String::from_utf8(decrypt_bytes_with_key(key, &combined, aad)?).map_err(|e| {
+        let message = e.to_string();
+        e.into_bytes().zeroize();
+        SidecarError::EncryptionFailed(message)
+    })
+}
+
+fn encrypt_field_with_key(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    value: &serde_json::Value,
+) -> Result<String, SidecarError> {
+    let plaintext = Zeroizing::new(serde_json::to_string(value)?);
+    encrypt_with_key(key, nonce, &plaintext, b"")
+}
+
+fn decrypt_field_with_key(
+    key: &[u8; 32],
+    ciphertext: &str,
+) -> Result<serde_json::Value, SidecarError> {
+    let plaintext = Zeroizing::new(decrypt_with_key(key, ciphertext, b"")?);
+    Ok(serde_json::from_str(&plaintext)?)
+}
+
+const FILE_MAGIC: &[u8; 4] = b"SCEF";
+const FILE_FORMAT_VERSION: u8 = 1;
+const FILE_HEADER_LEN: usize = FILE_MAGIC.len() + 1 + 12;
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+/// AES-GCM tag appended to every chunk
+const FILE_TAG_LEN: usize = 16;
+
+fn encrypt_file_with_key(
+    key: &[u8; 32],
+    base_nonce: &[u8; 12],
+    src: &Path,
+    dest: &Path,
+) -> Result<(), SidecarError> {
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN);
+    header.extend_from_slice(FILE_MAGIC);
+    header.push(FILE_FORMAT_VERSION);
+    header.extend_from_slice(base_nonce);
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(src)?);
+    write_through_partial(dest, |out| {
+        out.write_all(&header)?;
+        let mut chunk = Zeroizing::new(vec![0u8; FILE_CHUNK_SIZE]);
+        for index in 0u32.. {
+            let len = read_full(&mut reader, &mut chunk)?;
+            let last = reader.fill_buf()?.is_empty();
+            let sealed = seal_file_chunk(key, base_nonce, &header, index, last, &chunk[..len])?;
+            out.write_all(&sealed)?;
+            if last {
+                return Ok(());
+            }
+        }
+        Err(SidecarError::Encryption(
+            "File is too large to encrypt".to_string(),
+        ))
+    })
+}
+
+fn decrypt_file_with_key(key: &[u8; 32], src: &Path, dest: &Path) -> Result<(), SidecarError> {
+    let corrupt = || SidecarError::Encryption("Encrypted file is truncated or corrupt".to_string());
+    let mut reader = std::io::BufReader::new(std::fs::File::open(src)?);
+
+    let mut header = [0u8; FILE_HEADER_LEN];
+    if read_full(&mut reader, &mut header)? < FILE_HEADER_LEN
+        || &header[..FILE_MAGIC.len()] != FILE_MAGIC
+    {
+        return Err(SidecarError::Encryption(
+            "Not a file written by encrypt_file".to_string(),
+        ));
+    }
+    let version = header[FILE_MAGIC.len()];
+    if version != FILE_FORMAT_VERSION {
+        return Err(SidecarError::Encryption(format!(
+            "Unsupported encrypted file version {version}"
+        )));
+    }
+    let mut base_nonce = [0u8; 12];
+    base_nonce.copy_from_slice(&header[FILE_MAGIC.len() + 1..]);
+
+    write_through_partial(dest, |out| {
+        let mut chunk = vec![0u8; FILE_CHUNK_SIZE + FILE_TAG_LEN];
+        for index in 0u32.. {
+            let len = read_full(&mut reader, &mut chunk)?;
+            let last = reader.fill_buf()?.is_empty();
+            if len < FILE_TAG_LEN {
+                return Err(corrupt());
+            }
+            let plaintext = open_file_chunk(key, &base_nonce, &header, index, last, &chunk[..len])
+                .map_err(|_| corrupt())?;
+            out.write_all(&plaintext)?;
+            if last {
+                return Ok(());
+            }
+        }
+        Err(corrupt())
+    })
+}
+
+/// The nonce for chunk `index`: the base nonce with the index XORed into
+/// its last four bytes
+fn file_chunk_nonce(base_nonce: &[u8; 12], index: u32) -> [u8; 12] {
+    let mut nonce = *base_nonce;
+    for (byte, counter) in nonce[8..].iter_mut().zip(index.to_be_bytes()) {
+        *byte ^= counter;
+    }
+    nonce
+}
+
+/// Each chunk authenticates the header and whether it is the final chunk,
+/// which is how truncation at a chunk boundary is caught
+fn file_chunk_aad(header: &[u8], last: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.push(u8::from(last));
+    aad
+}
+
+fn seal_file_chunk(
+    key: &[u8; 32],
+    base_nonce: &[u8; 12],
+    header: &[u8],
+    index: u32,
+    last: bool,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, SidecarError> {
+    let nonce = file_chunk_nonce(base_nonce, index);
+    let sealed = encrypt_bytes_with_key(key, &nonce, plaintext, &file_chunk_aad(header, last))?;
+    // The nonce is derivable from the header, so only the ciphertext is stored
+    Ok(sealed[nonce.len()..].to_vec())
+}
+
+fn open_file_chunk(
+    key: &[u8; 32],
+    base_nonce: &[u8; 12],
+    header: &[u8],
+    index: u32,
+    last: bool,
+    ciphertext: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, SidecarError> {
+    let mut combined = file_chunk_nonce(base_nonce, index).to_vec();
+    combined.extend_from_slice(ciphertext);
+    decrypt_bytes_with_key(key, &combined, &file_chunk_aad(header, last)).map(Zeroizing::new)
+}
+
+/// Fill `buf` as far as the reader allows and return how much was read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize, SidecarError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Write `dest` via a `.partial` file that is renamed into place on success
+/// and removed on failure
+fn write_through_partial(
+    dest: &Path,
+    write: impl FnOnce(&mut std::io::BufWriter<std::fs::File>) -> Result<(), SidecarError>,
+) -> Result<(), SidecarError> {
+    let partial = sibling_path(dest, ".partial");
+    let written = std::fs::File::create(&partial)
+        .map_err(SidecarError::from)
+        .and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            write(&mut out)?;
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| Ok(std::fs::rename(&partial, dest)?));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    written
+}
+
+fn hmac_tag(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, SidecarError> {
+    Ok(keyed_hmac(key, data)?.finalize().into_bytes().to_vec())
+}
+
+fn hmac_matches(key: &[u8; 32], data: &[u8], tag_b64: &str) -> Result<bool, SidecarError> {
+    let Ok(tag) = BASE64.decode(tag_b64) else {
+        return Ok(false);
+    };
+    // Constant-time comparison
+    Ok(keyed_hmac(key, data)?.verify_slice(&tag).is_ok())
+}
+
+fn keyed_hmac(key: &[u8; 32], data: &[u8]) -> Result<Hmac<Sha256>, SidecarError> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+    mac.update(data);
+    Ok(mac)
+}
+
+/// Encrypt `plaintext` and return `nonce || ciphertext`. `nonce` must never
+/// be reused with the same key; take it from `AppState::next_nonce`. An
+/// empty `aad` is the same as none.
+fn encrypt_bytes_with_key(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, SidecarError> {
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+
+    let mut combined = nonce.to_vec();
+    combined.extend(ciphertext);
+    Ok(combined)
+}
+
+fn decrypt_bytes_with_key(
+    key: &[u8; 32],
+    combined: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, SidecarError> {
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+
+    if combined.len() < 12 {
+        return Err(SidecarError::Encryption("Invalid ciphertext".to_string()));
+    }
+
+    let (nonce_bytes, ciphertext_bytes) = combined.split_at(12);
+    let nonce = Nonce::from_slice(nonce_bytes);
+
+    cipher
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext_bytes,
+                aad,
+            },
+        )
+        .map_err(|_| {
+            SidecarError::EncryptionFailed(
+                "Ciphertext failed authentication; the key or aad is wrong, or the data is corrupt"
+                    .to_string(),
+            )
+        })
+}
+
+const PASSWORD_SENTINEL: &str = "sidecar-verify";
+const PASSWORD_SENTINEL_KEY: &str = "password_sentinel";
+
+/// `init_encryption`: derive the key for the open database, upgrading a
+/// database from the legacy derivation on the way
+fn unlock_with_password(
+    state: &AppState,
+    password: &str,
+    fields: &[EncryptedField],
+) -> Result<(), SidecarError> {
+    let mut encryption_key = state.encryption_key.lock();
+    let key = {
+        let db = state.db.lock();
+        let conn = db.as_ref().ok_or(SidecarError::InvalidState(
+            "Open the database before init_encryption; it holds the key's salt".to_string(),
+        ))?;
+        let sentinel = read_state_value::<String>(conn, PASSWORD_SENTINEL_KEY)?;
+        match stored_kdf_params(conn)? {
+            Some(params) => {
+                let key = params.derive(password)?;
+                match sentinel {
+                    Some(sentinel) if !sentinel_matches(&key, &sentinel) => {
+                        return Err(SidecarError::EncryptionFailed(
+                            "Wrong password for this database".to_string(),
+                        ))
+                    }
+                    Some(_) => {}
+                    None => store_password_sentinel(state, conn, &key)?,
+                }
+                key
+            }
+            None => {
+                let legacy = derive_key(password);
+                if sentinel.is_some_and(|sentinel| !sentinel_matches(&legacy, &sentinel)) {
+                    return Err(SidecarError::EncryptionFailed(
+                        "Wrong password for this database".to_string(),
+                    ));
+                }
+                let params = KdfParams::generate(*state.kdf_cost.lock());
+                let key = params.derive(password)?;
+                let tx = conn.unchecked_transaction()?;
+                // Databases from before the sentinel have only their
+                // ciphertexts to check the password against: one that
+                // doesn't decrypt under the legacy key fails the upgrade
+                let kv_store = kv_store_field();
+                let fields = fields_with_kv_store(&tx, fields, &kv_store)?;
+                reencrypt_fields(state, &tx, &legacy, &key, &fields)?;
+                store_password_sentinel(state, &tx, &key)?;
+                store_kdf_params(&tx, &params)?;
+                tx.commit()?;
+                key
+            }
+        }
+    };
+    *encryption_key = Some(key);
+    drop(encryption_key);
+    *state.key_last_used.lock() = Instant::now();
+    Ok(())
+}
+
+fn check_password(state: &AppState, password: &str) -> Result<bool, SidecarError> {
+    let (params, sentinel) = state.with_conn(|conn| {
+        Ok((
+            stored_kdf_params(conn)?,
+            read_state_value::<String>(conn, PASSWORD_SENTINEL_KEY)?,
+        ))
+    })?;
+    let sentinel = sentinel.ok_or(SidecarError::InvalidState(
+        "No password has been set for this database".to_string(),
+    ))?;
+    let key = password_key(params.as_ref(), password)?;
+    Ok(sentinel_matches(&key, &sentinel))
+}
+
+fn sentinel_matches(key: &[u8; 32], sentinel: &str) -> bool {
+    decrypt_with_key(key, sentinel, b"").is_ok_and(|plain| plain == PASSWORD_SENTINEL)
+}
+
+fn store_password_sentinel(
+    state: &AppState,
+    conn: &Connection,
+    key: &[u8; 32],
+) -> Result<(), SidecarError> {
+    let nonce = state.next_nonce_on(Some(conn))?;
+    let sealed = encrypt_with_key(key, &nonce, PASSWORD_SENTINEL, b"")?;
+    write_state_value(conn, PASSWORD_SENTINEL_KEY, sealed)
+}
+
+fn rotate_key(
+    state: &AppState,
+    old_password: &str,
+    new_password: &str,
+    ciphertexts: &[String],
+) -> Result<Vec<String>, SidecarError> {
+    let mut encryption_key = state.encryption_key.lock();
+    let db = state.db.lock();
+    let conn = db.as_ref().ok_or(SidecarError::InvalidState(
+        "Database not initialized".to_string(),
+    ))?;
+    let old_key = password_key(stored_kdf_params(conn)?.as_ref(), old_password)?;
+    ensure_old_password(conn, encryption_key.as_deref(), &old_key)?;
+    let params = KdfParams::generate(*state.kdf_cost.lock());
+    let new_key = params.derive(new_password)?;
+
+    let rotated = ciphertexts
+        .iter()
+        .map(|ciphertext| {
+            let plaintext = Zeroizing::new(decrypt_with_key(&old_key, ciphertext, b"")?);
+            encrypt_with_key(&new_key, &state.next_nonce_on(Some(conn))?, &plaintext, b"")
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let tx = conn.unchecked_transaction()?;
+    let kv_store = kv_store_field();
+    let fields = fields_with_kv_store(&tx, &[], &kv_store)?;
+    reencrypt_fields(state, &tx, &old_key, &new_key, &fields)?;
+    store_password_sentinel(state, &tx, &new_key)?;
+    store_kdf_params(&tx, &params)?;
+    tx.commit()?;
+    drop(db);
+    *encryption_key = Some(new_key);
+    Ok(rotated)
+}
+
+fn rotate_fields(
+    state: &AppState,
+    old_password: &str,
+    new_password: &str,
+    fields: &[EncryptedField],
+) -> Result<u32, SidecarError> {
+    let mut encryption_key = state.encryption_key.lock();
+    state.with_conn(|conn| {
+        let old_key = password_key(stored_kdf_params(conn)?.as_ref(), old_password)?;
+        ensure_old_password(conn, encryption_key.as_deref(), &old_key)?;
+        let params = KdfParams::generate(*state.kdf_cost.lock());
+        let new_key = params.derive(new_password)?;
+
+        let tx = conn.unchecked_transaction()?;
+        let kv_store = kv_store_field();
+        let fields = fields_with_kv_store(&tx, fields, &kv_store)?;
+        let rotated = reencrypt_fields(state, &tx, &old_key, &new_key, &fields)?;
+        store_password_sentinel(state, &tx, &new_key)?;
+        store_kdf_params(&tx, &params)?;
+        tx.commit()?;
+
+        *encryption_key = Some(new_key);
+        Ok(rotated)
+    })
+}
+
+/// The backend's own `kv_store` values
+fn kv_store_field() -> EncryptedField {
+    EncryptedField {
+        table: "kv_store".to_string(),
+        column: "value".to_string(),
+        id_column: "key".to_string(),
+        aad_column: None,
+    }
+}
+
+/// `fields` plus `kv_store`, which has to follow every key change whether
+/// or not the caller lists it. Creates `kv_store` if it's missing.
+fn fields_with_kv_store<'a>(
+    tx: &Connection,
+    fields: &'a [EncryptedField],
+    kv_store: &'a EncryptedField,
+) -> Result<Vec<&'a EncryptedField>, SidecarError> {
+    ensure_kv_store(tx)?;
+    let mut all: Vec<&EncryptedField> = fields
+        .iter()
+        .filter(|field| {
+            !(field.table.eq_ignore_ascii_case(&kv_store.table)
+                && field.column.eq_ignore_ascii_case(&kv_store.column))
+        })
+        .collect();
+    all.push(kv_store);
+    Ok(all)
+}
+
+/// Re-encrypt every value in `fields` from `old_key` to `new_key`, within
+/// the caller's transaction `tx`
+fn reencrypt_fields(
+    state: &AppState,
+    tx: &Connection,
+    old_key: &[u8; 32],
+    new_key: &[u8; 32],
+    fields: &[&EncryptedField],
+) -> Result<u32, SidecarError> {
+    let mut rotated = 0;
+    for field in fields {
+        ensure_table_exists(tx, &field.table)?;
+        let (table, column, id_column) = (
+            quote_identifier(&field.table),
+            quote_identifier(&field.column),
+            quote_identifier(&field.id_column),
+        );
+        let aad = match &field.aad_column {
+            Some(aad_column) => format!(
+                "CAST(coalesce({}, '') AS BLOB)",
+                quote_identifier(aad_column)
+            ),
+            None => "X''".to_string(),
+        };
+        let rows = tx
+            .prepare(&format!(
+                "SELECT {id_column}, {column}, {aad} FROM {table} WHERE {column} IS NOT NULL"
+            ))?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, rusqlite::types::Value>(0)?,
+                    row.get::<_, rusqlite::types::Value>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut update = tx.prepare(&format!(
+            "UPDATE {table} SET {column} = ?1 WHERE {id_column} = ?2"
+        ))?;
+        for (id, value, aad) in rows {
+            let value = match value {
+                rusqlite::types::Value::Text(ciphertext) => {
+                    let plaintext = Zeroizing::new(decrypt_with_key(old_key, &ciphertext, &aad)?);
+                    let nonce = state.next_nonce_on(Some(tx))?;
+                    rusqlite::types::Value::Text(encrypt_with_key(
+                        new_key, &nonce, &plaintext, &aad,
+                    )?)
+                }
+                rusqlite::types::Value::Blob(ciphertext) => {
+                    let plaintext =
+                        Zeroizing::new(decrypt_bytes_with_key(old_key, &ciphertext, &aad)?);
+                    let nonce = state.next_nonce_on(Some(tx))?;
+                    rusqlite::types::Value::Blob(encrypt_bytes_with_key(
+                        new_key, &nonce, &plaintext, &aad,
+                    )?)
+                }
+                _ => {
+                    return Err(SidecarError::Encryption(format!(
+                        "{}.{} holds a value that isn't ciphertext",
+                        field.table, field.column
+                    )))
+                }
+            };
+            update.execute(rusqlite::params![value, id])?;
+            rotated += 1;
+        }
+    }
+    Ok(rotated)
+}
+
+/// After the database file is swapped out, the new one doesn't record how
+/// far the counter has been reserved
+fn forget_nonce_reservation(state: &AppState) {
+    let mut counter = state.nonce_counter.lock();
+    counter.reserved = counter.next;
+}
+
+fn take_nonce(counter: &mut NonceCounter) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&counter.next.to_be_bytes());
+    rand::thread_rng().fill(&mut nonce[8..]);
+    counter.next += 1;
+    nonce
+}
+
+const NONCE_COUNTER_KEY: &str = "nonce_counter";
+
+/// Read the persisted nonce counter, or 0 if none has been stored
+fn load_nonce_counter(conn: &Connection) -> Result<u64, SidecarError> {
+    let stored: Option<i64> = read_state_value(conn, NONCE_COUNTER_KEY)?;
+    Ok(stored.map_or(0, |value| value as u64))
+}
+
+fn store_nonce_counter(conn: &Connection, value: u64) -> Result<(), SidecarError> {
+    write_state_value(conn, NONCE_COUNTER_KEY, value as i64)
+}
+
+/// Read `key` from the `sidecar_state` table the backend keeps its own
+/// bookkeeping in
+fn read_state_value<T: rusqlite::types::FromSql>(
+    conn: &Connection,
+    key: &str,
+) -> Result<Option<T>, SidecarError> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'sidecar_state')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(None);
+    }
+    Ok(conn
+        .query_row(
+            "SELECT value FROM sidecar_state WHERE key = ?1",
+            [key],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+fn write_state_value(
+    conn: &Connection,
+    key: &str,
+    value: impl rusqlite::ToSql,
+) -> Result<(), SidecarError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS sidecar_state (key TEXT PRIMARY KEY, value NOT NULL)",
+    )?;
+    conn.execute(
+        "INSERT INTO sidecar_state (key, value) VALUES (?1, ?2)
+         ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        rusqlite::params![key, value],
+    )?;
+    Ok(())
+}
+
+/// Refuse to rotate away from a key other than the one in use, or one the
+/// database's password sentinel doesn't accept. The active key alone isn't enough: while locked there
+/// is none, and rotating would overwrite the salt the real key comes from.
+fn ensure_old_password(
+    conn: &Connection,
+    active: Option<&[u8; 32]>,
+    old_key: &[u8; 32],
+) -> Result<(), SidecarError> {
+    if active.is_some_and(|active| active != old_key) {
+        return Err(SidecarError::EncryptionFailed(
+            "Old password does not match the active key".to_string(),
+        ));
+    }
+    if let Some(sentinel) = read_state_value::<String>(conn, PASSWORD_SENTINEL_KEY)? {
+        if !sentinel_matches(old_key, &sentinel) {
+            return Err(SidecarError::EncryptionFailed(
+                "Wrong password for this database".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Encrypted Key-Value Store
+// ============================================================================
+
+/// Store `value` under `key`, encrypted with the active key
+///
+/// Values are encrypted like `encrypt_data` without `aad`, and are
+/// re-encrypted by both key rotation commands.
+#[tauri::command]
+fn kv_set(state: State<'_, Arc<AppState>>, key: String, value: String) -> Result<(), SidecarError> {
+    kv_store_set(&state, &key, &value)
+}
+
+/// Read the value stored under `key`, or `None` if there is none
+#[tauri::command]
+fn kv_get(state: State<'_, Arc<AppState>>, key: String) -> Result<Option<String>, SidecarError> {
+    kv_store_get(&state, &key)
+}
+
+/// Remove `key`. Returns whether it existed.
+#[tauri::command]
+fn kv_delete(state: State<'_, Arc<AppState>>, key: String) -> Result<bool, SidecarError> {
+    kv_store_delete(&state, &key)
+}
+
+fn kv_store_set(state: &AppState, key: &str, value: &str) -> Result<(), SidecarError> {
+    state.with_key(|enc_key| {
+        state.with_conn(|conn| {
+            ensure_kv_store(conn)?;
+            let ciphertext =
+                encrypt_with_key(enc_key, &state.next_nonce_on(Some(conn))?, value, b"")?;
+            conn.execute(
+                "INSERT INTO kv_store (key, value) VALUES (?1, ?2)
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                [key, &ciphertext],
+            )?;
+            Ok(())
+        })
+    })
+}
+
+fn kv_store_get(state: &AppState, key: &str) -> Result<Option<String>, SidecarError> {
+    state.with_key(|enc_key| {
+        let ciphertext: Option<String> = state.with_conn(|conn| {
+            ensure_kv_store(conn)?;
+            Ok(conn
+                .query_row("SELECT value FROM kv_store WHERE key = ?1", [key], |row| {
+                    row.get(0)
+                })
+                .optional()?)
+        })?;
+        ciphertext
+            .map(|ciphertext| decrypt_with_key(enc_key, &ciphertext, b""))
+            .transpose()
+    })
+}
+
+fn kv_store_delete(state: &AppState, key: &str) -> Result<bool, SidecarError> {
+    state.with_conn(|conn| {
+        ensure_kv_store(conn)?;
+        Ok(conn.execute("DELETE FROM kv_store WHERE key = ?1", [key])? > 0)
+    })
+}
+
+fn ensure_kv_store(conn: &Connection) -> Result<(), SidecarError> {
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS kv_store (key TEXT PRIMARY KEY, value TEXT)")?;
+    Ok(())
+}
+
+// ============================================================================
+// Credential Storage Commands (System Keychain)
+// ============================================================================
+
+const DEFAULT_KEYRING_SERVICE: &str = "sidecar-app";
+
+/// OAuth tokens live under their own service so they can't collide with
+/// raw credentials for the same provider
+const KEYRING_OAUTH_SUFFIX: &str = "-oauth";
+
+/// Keep this app's keychain entries under `name` instead of `sidecar-app`,
+/// so apps built on this backend don't read each other's credentials
+///
+/// Call it once at startup, before any credential command. OAuth tokens
+/// move to `<name>-oauth`. Calling it again with another name fails, since
+/// entries stored under the first name would silently disappear.
+#[tauri::command]
+fn set_keyring_service(state: State<'_, Arc<AppState>>, name: String) -> Result<(), SidecarError> {
+    set_service_name(&state, name)
+}
+
+fn set_service_name(state: &AppState, name: String) -> Result<(), SidecarError> {
+    if name.trim().is_empty() {
+        return Err(SidecarError::InvalidState(
+            "Keyring service name must not be empty".to_string(),
+        ));
+    }
+    let mut service = state.keyring_service.lock();
+    match service.as_deref() {
+        Some(current) if current != name => Err(SidecarError::InvalidState(format!(
+            "Keyring service is already set to '{current}'"
+        ))),
+        _ => {
+            *service = Some(name);
+            Ok(())
+        }
+    }
+}
+
+fn keyring_service(state: &AppState) -> String {
+    state
+        .keyring_service
+        .lock()
+        .clone()
+        .unwrap_or_else(|| DEFAULT_KEYRING_SERVICE.to_string())
+}
+
+/// Store credentials in system keychain
+///
+/// With `account`, the entry is kept under `provider:account`. Entries are
+/// recorded in a database index so `list_credentials` and `list_accounts`
+/// can find them.
+///
+/// `namespace` keeps workspaces that use the same provider apart: the entry
+/// name becomes `namespace/provider`. Every credential command takes it.
+///
+/// `label` is a name to show for the credential in the UI. It is kept with
+/// the index, not the secret; omitting it keeps the previous label.
+#[tauri::command]
+fn store_credentials(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    credentials: String,
+    account: Option<String>,
+    namespace: Option<String>,
+    label: Option<String>,
+) -> Result<(), SidecarError> {
+    let provider = scoped_provider(&provider, namespace.as_deref())?;
+    store_labelled_credentials(
+        &state,
+        &provider,
+        account.as_deref(),
+        &credentials,
+        label.as_deref(),
+        chrono::Utc::now().timestamp(),
+    )
+}
+
+/// Get credentials from system keychain
+#[tauri::command]
+fn get_credentials(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    account: Option<String>,
+    namespace: Option<String>,
+) -> Result<Option<String>, SidecarError> {
+    let provider = scoped_provider(&provider, namespace.as_deref())?;
+    get_account_credentials(&state, &provider, account.as_deref())
+}
+
+/// Delete credentials from system keychain
+#[tauri::command]
+fn delete_credentials(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    account: Option<String>,
+    namespace: Option<String>,
+) -> Result<(), SidecarError> {
+    let provider = scoped_provider(&provider, namespace.as_deref())?;
+    delete_account_credentials(&state, &provider, account.as_deref())
+}
+
+/// Replace the credentials stored for `provider` without a moment where
+/// neither value is stored
+///
+/// The new value is first written to a `<provider>_pending` entry, then
+/// over the real one, and the pending entry is removed last. If the app
+/// stops part way, the next `db_init` finishes the rotation.
+#[tauri::command]
+fn rotate_credentials(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    new_credentials: String,
+    account: Option<String>,
+    namespace: Option<String>,
+) -> Result<(), SidecarError> {
+    let provider = scoped_provider(&provider, namespace.as_deref())?;
+    rotate_account_credentials(&state, &provider, account.as_deref(), &new_credentials)
+}
+
+/// Finish rotations interrupted before their pending entry was removed and
+/// return how many were completed. `db_init` already does this when it
+/// opens the main database; call it again to retry after a keychain error.
+#[tauri::command]
+fn cleanup_pending_credentials(state: State<'_, Arc<AppState>>) -> Result<u32, SidecarError> {
+    complete_pending_rotations(&state)
+}
+
+/// List the accounts stored for `provider`, in name order
+#[tauri::command]
+fn list_accounts(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    namespace: Option<String>,
+) -> Result<Vec<String>, SidecarError> {
+    let provider = scoped_provider(&provider, namespace.as_deref())?;
+    state.with_conn(|conn| accounts_for(conn, &provider))
+}
+
+/// Keychain name for `provider` within `namespace`
+fn scoped_provider(provider: &str, namespace: Option<&str>) -> Result<String, SidecarError> {
+    check_namespace(namespace)?;
+    Ok(match namespace {
+        Some(namespace) => format!("{namespace}/{provider}"),
+        None => provider.to_string(),
+    })
+}
+
+fn check_namespace(namespace: Option<&str>) -> Result<(), SidecarError> {
+    match namespace {
+        Some(namespace) if namespace.is_empty() || namespace.contains('/') => Err(
+            SidecarError::InvalidState(format!("Invalid credential namespace '{namespace}'")),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Token set returned by an OAuth provider
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Unix timestamp in seconds
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub token_type: String,
+}
+
+/// Store an OAuth token for `provider` in the system keychain, replacing
+/// any previous one
+#[tauri::command]
+fn store_oauth_token(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    token: OAuthToken,
+    namespace: Option<String>,
+) -> Result<(), SidecarError> {
+    store_token(
+        &state,
+        &scoped_provider(&provider, namespace.as_deref())?,
+        &token,
+    )
+}
+
+/// Get the OAuth token stored for `provider`
+#[tauri::command]
+fn get_oauth_token(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    namespace: Option<String>,
+) -> Result<Option<OAuthToken>, SidecarError> {
+    load_token(&state, &scoped_provider(&provider, namespace.as_deref())?)
+}
+
+/// Delete the OAuth token stored for `provider`
+#[tauri::command]
+fn delete_oauth_token(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    namespace: Option<String>,
+) -> Result<(), SidecarError> {
+    let provider = scoped_provider(&provider, namespace.as_deref())?;
+    match oauth_token_entry(&state, &provider)?.delete_password() {
+        Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(SidecarError::Keyring(e.to_string())),
+    }
+}
+
+fn oauth_token_entry(state: &AppState, provider: &str) -> Result<keyring::Entry, SidecarError> {
+    let service = keyring_service(state) + KEYRING_OAUTH_SUFFIX;
+    keyring::Entry::new(&service, provider).map_err(|e| SidecarError::Keyring(e.to_string()))
+}
+
+fn store_token(state: &AppState, provider: &str, token: &OAuthToken) -> Result<(), SidecarError> {
+    oauth_token_entry(state, provider)?
+        .set_password(&serde_json::to_string(token)?)
+        .map_err(|e| SidecarError::Keyring(e.to_string()))
+}
+
+fn load_token(state: &AppState, provider: &str) -> Result<Option<OAuthToken>, SidecarError> {
+    match oauth_token_entry(state, provider)?.get_password() {
+        Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(SidecarError::Keyring(e.to_string())),
+    }
+}
+
+/// List the providers with stored credentials, in name order
+///
+/// The keychain backends can't enumerate entries (see
+/// `credentials_enumerate_supported`), so this reads the index kept in the
+/// database alongside them. Credentials stored while no database was open
+/// aren't listed. Only providers in `namespace` are listed, or only those
+/// outside any namespace without one.
+#[tauri::command]
+fn list_credentials(
+    state: State<'_, Arc<AppState>>,
+    namespace: Option<String>,
+) -> Result<Vec<String>, SidecarError> {
+    check_namespace(namespace.as_deref())?;
+    let providers = state.with_conn(credential_providers)?;
+    Ok(providers_in(providers, namespace.as_deref()))
+}
+
+/// Label and timestamps recorded for a stored credential. Never includes
+/// the secret.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialMeta {
+    pub provider: String,
+    pub account: Option<String>,
+    pub label: Option<String>,
+    /// Unix timestamps in seconds
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// List the credentials in `namespace` with their labels and when they
+/// were stored, ordered by provider then account
+///
+/// Like `list_credentials`, this reads the database index, so credentials
+/// stored while no database was open are missing.
+#[tauri::command]
+fn list_credentials_with_meta(
+    state: State<'_, Arc<AppState>>,
+    namespace: Option<String>,
+) -> Result<Vec<CredentialMeta>, SidecarError> {
+    check_namespace(namespace.as_deref())?;
+    let all = state.with_conn(credential_metadata)?;
+    Ok(all
+        .into_iter()
+        .filter_map(|meta| {
+            let provider = unscoped_provider(&meta.provider, namespace.as_deref())?;
+            Some(CredentialMeta { provider, ..meta })
+        })
+        .collect())
+}
+
+fn credential_metadata(conn: &Connection) -> Result<Vec<CredentialMeta>, SidecarError> {
+    ensure_account_index(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT provider, account, label, created_at, updated_at FROM credential_meta
+         ORDER BY provider, account",
+    )?;
+    let meta = stmt
+        .query_map([], |row| {
+            Ok(CredentialMeta {
+                provider: row.get(0)?,
+                account: Some(row.get::<_, String>(1)?).filter(|a| !a.is_empty()),
+                label: row.get(2)?,
+                created_at: row.get(3)?,
+                updated_at: row.get(4)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(meta)
+}
+
+/// The providers from `scoped` that belong to `namespace`, without the
+/// namespace prefix
+fn providers_in(scoped: Vec<String>, namespace: Option<&str>) -> Vec<String> {
+    scoped
+        .iter()
+        .filter_map(|name| unscoped_provider(name, namespace))
+        .collect()
+}
+
+/// `scoped` without its namespace prefix, if it belongs to `namespace`
+fn unscoped_provider(scoped: &str, namespace: Option<&str>) -> Option<String> {
+    match (namespace, scoped.split_once('/')) {
+        (None, None) => Some(scoped.to_string()),
+        (Some(namespace), Some((ns, provider))) if ns == namespace => Some(provider.to_string()),
+        _ => None,
+    }
+}
+
+/// Whether `list_credentials` can ask the system keychain directly. None
+/// of the supported backends can yet, so it always uses the database index.
+#[tauri::command]
+fn credentials_enumerate_supported() -> bool {
+    false
+}
+
+fn credential_providers(conn: &Connection) -> Result<Vec<String>, SidecarError> {
+    ensure_account_index(conn)?;
+    let mut stmt =
+        conn.prepare("SELECT DISTINCT provider FROM credential_accounts ORDER BY provider")?;
+    let providers = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(providers)
+}
+
+/// Keychain entry for `provider`, or for one of its accounts
+fn credential_entry(
+    state: &AppState,
+    provider: &str,
+    account: Option<&str>,
+) -> Result<keyring::Entry, SidecarError> {
+    let user = match account {
+        Some(account) => format!("{provider}:{account}"),
+        None => provider.to_string(),
+    };
+    keyring::Entry::new(&keyring_service(state), &user)
+        .map_err(|e| SidecarError::Keyring(e.to_string()))
+}
+
+fn store_account_credentials(
+    state: &AppState,
+    provider: &str,
+    account: Option<&str>,
+    credentials: &str,
+) -> Result<(), SidecarError> {
+    let now = chrono::Utc::now().timestamp();
+    store_labelled_credentials(state, provider, account, credentials, None, now)
+}
+
+fn store_labelled_credentials(
+    state: &AppState,
+    provider: &str,
+    account: Option<&str>,
+    credentials: &str,
+    label: Option<&str>,
+    now: i64,
+) -> Result<(), SidecarError> {
+    // Account entries can't be indexed without a database, so fail before
+    // the keychain write rather than leave the secret unlisted
+    if account.is_some() {
+        state.with_conn(|_| Ok(()))?;
+    }
+    credential_entry(state, provider, account)?
+        .set_password(credentials)
+        .map_err(|e| SidecarError::Keyring(e.to_string()))?;
+
+    index_credential(state, account, |conn, account| {
+        conn.execute(
+            "INSERT OR IGNORE INTO credential_accounts (provider, account) VALUES (?1, ?2)",
+            [provider, account],
+        )?;
+        conn.execute(
+            "INSERT INTO credential_meta (provider, account, label, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT (provider, account) DO UPDATE SET
+                 label = coalesce(excluded.label, label),
+                 updated_at = excluded.updated_at",
+            rusqlite::params![provider, account, label, now],
+        )?;
+        Ok(())
+    })
+}
+
+fn pending_provider(provider: &str) -> String {
+    format!("{provider}_pending")
+}
+
+/// Keychain entry listing the rotations in flight, so an interrupted one
+/// is found again even if it never made it into the credential index
+const PENDING_ROTATIONS_ENTRY: &str = "sidecar_pending_rotations";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PendingRotation {
+    provider: String,
+    account: Option<String>,
+}
+
+fn pending_rotations(state: &AppState) -> Result<Vec<PendingRotation>, SidecarError> {
+    match credential_entry(state, PENDING_ROTATIONS_ENTRY, None)?.get_password() {
+        Ok(json) => Ok(serde_json::from_str(&json)?),
+        Err(keyring::Error::NoEntry) => Ok(Vec::new()),
+        Err(e) => Err(SidecarError::Keyring(e.to_string())),
+    }
+}
+
+fn store_pending_rotations(
+    state: &AppState,
+    rotations: &[PendingRotation],
+) -> Result<(), SidecarError> {
+    let entry = credential_entry(state, PENDING_ROTATIONS_ENTRY, None)?;
+    let stored = if rotations.is_empty() {
+        entry.delete_password()
+    } else {
+        entry.set_password(&serde_json::to_string(rotations)?)
+    };
+    match stored {
+        Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(SidecarError::Keyring(e.to_string())),
+    }
+}
+
+fn rotate_account_credentials(
+    state: &AppState,
+    provider: &str,
+    account: Option<&str>,
+    credentials: &str,
+) -> Result<(), SidecarError> {
+    let rotation = PendingRotation {
+        provider: provider.to_string(),
+        account: account.map(str::to_string),
+    };
+    let mut rotations = pending_rotations(state)?;
+    if !rotations.contains(&rotation) {
+        rotations.push(rotation.clone());
+        store_pending_rotations(state, &rotations)?;
+    }
+
+    let pending = credential_entry(state, &pending_provider(provider), account)?;
+    pending
+        .set_password(credentials)
+        .map_err(|e| SidecarError::Keyring(e.to_string()))?;
+    store_account_credentials(state, provider, account, credentials)?;
+    match pending.delete_password() {
+        Ok(_) | Err(keyring::Error::NoEntry) => {}
+        Err(e) => return Err(SidecarError::Keyring(e.to_string())),
+    }
+
+    let mut rotations = pending_rotations(state)?;
+    rotations.retain(|r| *r != rotation);
+    store_pending_rotations(state, &rotations)
+}
+
+/// Finish every rotation whose pending entry is still there: those listed
+/// in the keychain, and any indexed credentials when a database is open
+fn complete_pending_rotations(state: &AppState) -> Result<u32, SidecarError> {
+    let mut candidates = pending_rotations(state)?;
+    let indexed = state
+        .db
+        .lock()
+        .as_ref()
+        .map(|conn| {
+            ensure_account_index(conn)?;
+            let mut stmt = conn.prepare("SELECT provider, account FROM credential_accounts")?;
+            let rows = stmt
+                .query_map([], |row| {
+                    let account: String = row.get(1)?;
+                    Ok(PendingRotation {
+                        provider: row.get(0)?,
+                        account: Some(account).filter(|a| !a.is_empty()),
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, SidecarError>(rows)
+        })
+        .transpose()?
+        .unwrap_or_default();
+    for rotation in indexed {
+        if !candidates.contains(&rotation) {
+            candidates.push(rotation);
+        }
+    }
+
+    let mut completed = 0;
+    for rotation in &candidates {
+        let (provider, account) = (&rotation.provider, rotation.account.as_deref());
+        if let Some(credentials) =
+            get_account_credentials(state, &pending_provider(provider), account)?
+        {
+            rotate_account_credentials(state, provider, account, &credentials)?;
+            completed += 1;
+        }
+    }
+    // Rotations that crashed before writing their pending entry have
+    // nothing left to finish
+    let mut rotations = pending_rotations(state)?;
+    rotations.retain(|rotation| !candidates.contains(rotation));
+    store_pending_rotations(state, &rotations)?;
+    Ok(completed)
+}
+
+fn get_account_credentials(
+    state: &AppState,
+    provider: &str,
+    account: Option<&str>,
+) -> Result<Option<String>, SidecarError> {
+    match credential_entry(state, provider, account)?.get_password() {
+        Ok(password) => Ok(Some(password)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(SidecarError::Keyring(e.to_string())),
+    }
+}
+
+fn delete_account_credentials(
+    state: &AppState,
+    provider: &str,
+    account: Option<&str>,
+) -> Result<(), SidecarError> {
+    match credential_entry(state, provider, account)?.delete_password() {
+        Ok(_) => {}
+        Err(keyring::Error::NoEntry) => {} // Already deleted
+        Err(e) => return Err(SidecarError::Keyring(e.to_string())),
+    }
+
+    index_credential(state, account, |conn, account| {
+        conn.execute(
+            "DELETE FROM credential_accounts WHERE provider = ?1 AND account = ?2",
+            [provider, account],
+        )?;
+        conn.execute(
+            "DELETE FROM credential_meta WHERE provider = ?1 AND account = ?2",
+            [provider, account],
+        )?;
+        Ok(())
+    })
+}
+
+/// Run `update` against the credential index for one keychain entry. The
+/// entry without an account is indexed under the empty account name.
+///
+/// Per-account entries need the database; an entry without an account is
+/// left out of the index while no database is open, so it can still be
+/// stored before `db_init`.
+fn index_credential(
+    state: &AppState,
+    account: Option<&str>,
+    update: impl FnOnce(&Connection, &str) -> Result<(), SidecarError>,
+) -> Result<(), SidecarError> {
+    let update = |conn: &Connection| {
+        ensure_account_index(conn)?;
+        update(conn, account.unwrap_or(""))
+    };
+    match account {
+        Some(_) => state.with_conn(update),
+        None => state.db.lock().as_ref().map_or(Ok(()), update),
+    }
+}
+
+/// The keychain can't always enumerate its entries, so known accounts are
+/// indexed in the database, with their labels and timestamps alongside
+fn ensure_account_index(conn: &Connection) -> Result<(), SidecarError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS credential_accounts (
+             provider TEXT NOT NULL,
+             account TEXT NOT NULL,
+             PRIMARY KEY (provider, account)
+         );
+         CREATE TABLE IF NOT EXISTS credential_meta (
+             provider TEXT NOT NULL,
+             account TEXT NOT NULL,
+             label TEXT,
+             created_at INTEGER NOT NULL,
+             updated_at INTEGER NOT NULL,
+             PRIMARY KEY (provider, account)
+         );",
+    )?;
+    Ok(())
+}
+
+fn accounts_for(conn: &Connection, provider: &str) -> Result<Vec<String>, SidecarError> {
+    ensure_account_index(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT account FROM credential_accounts WHERE provider = ?1 AND account <> '' ORDER BY account",
+    )?;
+    let accounts = stmt
+        .query_map([provider], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(accounts)
+}
+
+const DEFAULT_TOTP_DIGITS: u32 = 6;
+const DEFAULT_TOTP_PERIOD: u64 = 30;
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpCode {
+    pub code: String,
+    /// Seconds until `code` stops being valid
+    pub seconds_remaining: u64,
+}
+
+/// Generate the current RFC 6238 code for a base32 TOTP secret, as shown
+/// by authenticator apps. Defaults to 6 digits and a 30 second period.
+#[tauri::command]
+fn generate_totp(
+    secret_base32: String,
+    digits: Option<u32>,
+    period: Option<u64>,
+) -> Result<TotpCode, SidecarError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| SidecarError::InvalidState(e.to_string()))?
+        .as_secs();
+    totp_at(
+        &secret_base32,
+        digits.unwrap_or(DEFAULT_TOTP_DIGITS),
+        period.unwrap_or(DEFAULT_TOTP_PERIOD),
+        now,
+    )
+}
+
+fn totp_at(
+    secret_base32: &str,
+    digits: u32,
+    period: u64,
+    unix_time: u64,
+) -> Result<TotpCode, SidecarError> {
+    if !(6..=8).contains(&digits) {
+        return Err(SidecarError::InvalidState(format!(
+            "TOTP codes must have 6 to 8 digits, not {digits}"
+        )));
+    }
+    if period == 0 {
+        return Err(SidecarError::InvalidState(
+            "TOTP period must be at least one second".to_string(),
+        ));
+    }
+    let secret = Zeroizing::new(decode_base32(secret_base32)?);
+
+    let mut mac = <Hmac<sha1::Sha1> as Mac>::new_from_slice(&secret)
+        .map_err(|e| SidecarError::Encryption(e.to_string()))?;
+    mac.update(&(unix_time / period).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let binary = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    Ok(TotpCode {
+        code: format!(
+            "{:0width$}",
+            binary % 10u32.pow(digits),
+            width = digits as usize
+        ),
+        seconds_remaining: period - unix_time % period,
+    })
+}
+
+/// Decode RFC 4648 base32, ignoring case, spaces, dashes and padding as
+/// authenticator setup keys are often written with them
+fn decode_base32(encoded: &str) -> Result<Vec<u8>, SidecarError> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for c in encoded.chars().filter(|c| !matches!(c, ' ' | '-' | '=')) {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => {
+                return Err(SidecarError::InvalidState(format!(
+                    "TOTP secret is not valid base32: unexpected {c:?}"
+                )))
+            }
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    if bytes.is_empty() {
+        return Err(SidecarError::InvalidState(
+            "TOTP secret is empty".to_string(),
+        ));
+    }
+    Ok(bytes)
+}
+
+// ============================================================================
+// OAuth State Management
+// ============================================================================
+
+/// How long a stored OAuth state stays valid unless configured otherwise
+const DEFAULT_OAUTH_STATE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Store OAuth state for CSRF protection
+#[tauri::command]
+fn store_oauth_state(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    oauth_state: String,
+) -> Result<(), SidecarError> {
+    store_oauth_state_at(&state, provider, oauth_state, Instant::now());
+    Ok(())
+}
+
+/// Validate OAuth state
+///
+/// States older than the configured TTL are rejected and discarded.
+#[tauri::command]
+fn validate_oauth_state(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    oauth_state: String,
+) -> Result<bool, SidecarError> {
+    Ok(validate_oauth_state_at(
+        &state,
+        &provider,
+        &oauth_state,
+        Instant::now(),
+    ))
+}
+
+/// Set how long stored OAuth states remain valid
+#[tauri::command]
+fn set_oauth_state_ttl(state: State<'_, Arc<AppState>>, ttl_seconds: u64) {
+    *state.oauth_state_ttl.lock() = Duration::from_secs(ttl_seconds);
+}
+
+/// Drop every stored OAuth state older than the TTL and return how many
+/// were removed
+///
+/// Expired states are already rejected and cleared as flows are started;
+/// this clears the ones left by flows that were abandoned.
+#[tauri::command]
+fn purge_expired_oauth_states(state: State<'_, Arc<AppState>>) -> usize {
+    purge_oauth_states_at(&state, Instant::now())
+}
+
+/// PKCE verifier/challenge pair (RFC 7636)
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PkcePair {
+    pub verifier: String,
+    pub challenge: String,
+    pub method: String,
+}
+
+/// Generate a PKCE pair for `provider`
+///
+/// The verifier is kept in state, with the same TTL as OAuth states, until
+/// it is fetched for the token exchange.
+#[tauri::command]
+fn generate_pkce_pair(state: State<'_, Arc<AppState>>, provider: String) -> PkcePair {
+    generate_pkce_pair_at(&state, provider, Instant::now())
+}
+
+/// Take the PKCE verifier stored for `provider`
+///
+/// Verifiers are single-use; expired ones are discarded and return `None`.
+#[tauri::command]
+fn get_pkce_verifier(state: State<'_, Arc<AppState>>, provider: String) -> Option<String> {
+    get_pkce_verifier_at(&state, &provider, Instant::now())
+}
+
+/// Check `verifier` against the PKCE verifier stored for `provider`
+///
+/// Compares the S256 challenges of both values. The stored verifier is left
+/// in place for `get_pkce_verifier`.
+#[tauri::command]
+fn verify_oauth_pkce_verifier(
+    state: State<'_, Arc<AppState>>,
+    provider: String,
+    verifier: String,
+) -> bool {
+    verify_pkce_verifier_at(&state, &provider, &verifier, Instant::now())
+}
+
+fn store_oauth_state_at(state: &AppState, provider: String, oauth_state: String, now: Instant) {
+    // Evict abandoned flows so the map can't grow without bound
+    purge_oauth_states_at(state, now);
+    state
+        .oauth_states
+        .lock()
+        .insert(provider, (oauth_state, now));
+}
+
+fn purge_oauth_states_at(state: &AppState, now: Instant) -> usize {
+    let ttl = *state.oauth_state_ttl.lock();
+    let mut states = state.oauth_states.lock();
+    let before = states.len();
+    states.retain(|_, (_, created_at)| now.saturating_duration_since(*created_at) < ttl);
+    before - states.len()
+}
+
+fn validate_oauth_state_at(
+    state: &AppState,
+    provider: &str,
+    oauth_state: &str,
+    now: Instant,
+) -> bool {
+    let ttl = *state.oauth_state_ttl.lock();
+    let mut states = state.oauth_states.lock();
+    match states.get(provider) {
+        Some((_, created_at)) if now.saturating_duration_since(*created_at) >= ttl => {
+            states.remove(provider);
+            false
+        }
+        Some((stored, _)) if stored == oauth_state => {
+            states.remove(provider);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn generate_pkce_pair_at(state: &AppState, provider: String, now: Instant) -> PkcePair {
+    // 32 random bytes encode to a 43 character verifier
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes[..]);
+    let verifier = BASE64_URL.encode(bytes);
+    let challenge = pkce_challenge(&verifier);
+
+    let ttl = *state.oauth_state_ttl.lock();
+    let mut verifiers = state.pkce_verifiers.lock();
+    verifiers.retain(|_, (_, created_at)| now.saturating_duration_since(*created_at) < ttl);
+    verifiers.insert(provider, (verifier.clone(), now));
+
+    PkcePair {
+        verifier,
+        challenge,
+        method: "S256".to_string(),
+    }
+}
+
+fn get_pkce_verifier_at(state: &AppState, provider: &str, now: Instant) -> Option<String> {
+    let ttl = *state.oauth_state_ttl.lock();
+    let (verifier, created_at) = state.pkce_verifiers.lock().remove(provider)?;
+    (now.saturating_duration_since(created_at) < ttl).then_some(verifier)
+}
+
+fn verify_pkce_verifier_at(state: &AppState, provider: &str, verifier: &str, now: Instant) -> bool {
+    let ttl = *state.oauth_state_ttl.lock();
+    match state.pkce_verifiers.lock().get(provider) {
+        Some((stored, created_at)) if now.saturating_duration_since(*created_at) < ttl => {
+            pkce_challenge(stored) == pkce_challenge(verifier)
+        }
+        _ => false,
+    }
+}
+
+/// S256 code challenge: base64url(SHA-256(verifier)) without padding
+fn pkce_challenge(verifier: &str) -> String {
+    BASE64_URL.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+// ============================================================================
+// Utility Commands
+// ============================================================================
+
+/// Generate a random string for OAuth state
+#[tauri::command]
+fn generate_random_string(length: usize) -> String {
+    const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    let mut rng = rand::thread_rng();
+
+    (0..length)
+        .map(|_| {
+            let idx = rng.gen_range(0..CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect()
+}
+
+/// Generate a secure ID (UUID v4)
+#[tauri::command]
+fn generate_secure_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Open a URL in the system browser
+#[tauri::command]
+fn open_browser(url: String) -> Result<(), SidecarError> {
+    open::that(&url).map_err(|e| SidecarError::InvalidState(e.to_string()))?;
+    Ok(())
+}
+
+/// Get the app data directory
+#[tauri::command]
+fn get_app_data_dir() -> Result<String, SidecarError> {
+    let mut path = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push("sidecar");
+    std::fs::create_dir_all(&path)
+        .map_err(|e| SidecarError::InvalidState(e.to_string()))?;
+
+    path.to_str()
+        .map(|s| s.to_string())
+        .ok_or(SidecarError::InvalidState("Invalid path".to_string()))
+}
+
+// ============================================================================
+// Command Dispatch
+// ============================================================================
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs jobs one at a time, in the order they were submitted, on a
+/// dedicated thread
+struct SerialWorker {
+    jobs: std::sync::mpsc::Sender<Job>,
+}
+
+impl SerialWorker {
+    fn spawn(name: &str) -> std::io::Result<Self> {
+        let (jobs, queue) = std::sync::mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                for job in queue {
+                    // A panicking command shouldn't take the queue down with it
+                    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                }
+            })?;
+        Ok(Self { jobs })
+    }
+
+    /// Queue `job`, or run it here if the worker thread is gone
+    fn submit(&self, job: impl FnOnce() + Send + 'static) {
+        if let Err(std::sync::mpsc::SendError(job)) = self.jobs.send(Box::new(job)) {
+            job();
+        }
+    }
+}
+
+/// Queries served by the reader pool, and backups, which read through a
+/// connection of their own. They run on Tauri's async runtime instead of
+/// the database worker, so concurrent reads don't queue behind each other
+/// or delay writes. They may overtake a write sent just before them; await
+/// the write first to read its result.
+const POOLED_READ_COMMANDS: &[&str] = &[
+    "db_query",
+    "db_query_with_meta",
+    "db_query_one",
+    "db_query_page",
+    "db_query_paginated",
+    "db_query_stream",
+    "db_backup",
+];
+
+/// Commands outside `db_` that use the database connection. They run on
+/// the worker as well, so they stay in order with the database commands
+/// around them and never wait for the connection on the IPC thread.
+const DB_WORKER_COMMANDS: &[&str] = &[
+    // The key's salt and sentinel live in the database, and nonce
+    // reservations are written to it
+    "init_encryption",
+    "verify_password",
+    "encrypt_data",
+    "encrypt_bytes",
+    "encrypt_field",
+    "encrypt_json",
+    "rotate_encryption_key",
+    "rotate_encrypted_fields",
+    "kv_set",
+    "kv_get",
+    "kv_delete",
+    // The credential account index
+    "store_credentials",
+    "delete_credentials",
+    "rotate_credentials",
+    "cleanup_pending_credentials",
+    "list_accounts",
+    "list_credentials",
+    "list_credentials_with_meta",
+    // Ordered with db_init, which resets the backup schedule's fingerprint
+    "configure_auto_backup",
+];
+
+/// Whether a command is dispatched to the database worker instead of
+/// running on the IPC thread. Cancelling must not wait behind the query it
+/// cancels.
+fn runs_on_db_worker(command: &str) -> bool {
+    (command.starts_with("db_") || DB_WORKER_COMMANDS.contains(&command))
+        && !matches!(command, "db_cancel" | "db_cancel_stream")
+        && !POOLED_READ_COMMANDS.contains(&command)
+}
+
+/// Wrap `handler` so database commands run on their own thread
+///
+/// Commands that only burn CPU, like password hashing and file
+/// encryption, are `async` instead and run on Tauri's runtime. A slow
+/// query can't stall the IPC thread, and the worker takes commands
+/// in the order they arrive, so calls made back-to-back from the frontend
+/// still apply in order. Pooled reads skip the queue; see
+/// `POOLED_READ_COMMANDS`. Commands on the worker must be sync: an async
+/// command only spawns its future there and returns, losing the ordering.
+fn route_commands<R: tauri::Runtime>(
+    handler: impl Fn(tauri::ipc::Invoke<R>) -> bool + Send + Sync + 'static,
+) -> impl Fn(tauri::ipc::Invoke<R>) -> bool + Send + Sync {
+    let db_worker = SerialWorker::spawn("sidecar-db").expect("failed to start database worker");
+    let handler = Arc::new(handler);
+    move |invoke| {
+        if !runs_on_db_worker(invoke.message.command()) {
+            return handler(invoke);
+        }
+        let handler = handler.clone();
+        db_worker.submit(move || {
+            handler(invoke);
+        });
+        true
+    }
+}
+
+// ============================================================================
+// Tauri App Entry Point
+// ============================================================================
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    let app_state = Arc::new(AppState::new());
+    let changes = app_state.changes.clone();
+    let locker = app_state.clone();
+    let backups = app_state.clone();
+
+    let builder = tauri::Builder::default()
+        .plugin(tauri_plugin_opener::init())
+        .setup(move |app| {
+            let handle = app.handle().clone();
+            changes.lock().listener = Some(Arc::new(move |batch| {
+                let _ = handle.emit("db:changed", batch);
+            }));
+
+            let handle = app.handle().clone();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(AUTO_LOCK_POLL_INTERVAL);
+                if locker.lock_if_idle(Instant::now()) {
+                    let _ = handle.emit("encryption:locked", ());
+                }
+            });
+
+            let handle = app.handle().clone();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(AUTO_BACKUP_POLL_INTERVAL);
+                if let Some(event) = run_auto_backup(&backups, Instant::now()) {
+                    let _ = handle.emit("db:auto-backup", event);
+                }
+            });
+            Ok(())
+        })
+        .manage(app_state);
+
+    let handler: Arc<dyn Fn(tauri::ipc::Invoke) -> bool + Send + Sync> =
+        Arc::new(tauri::generate_handler![
+            // Database
+            db_init,
+            db_close,
+            db_attach,
+            db_is_open,
+            db_execute,
+            db_execute_batch,
+            db_execute_script,
+            db_savepoint,
+            db_release,
+            db_rollback_to,
+            db_insert,
+            db_execute_returning,
+            db_query,
+            db_query_with_meta,
+            db_query_one,
+            db_query_stream,
+            db_cancel_stream,
+            db_cancel,
+            db_export,
+            db_set_busy_timeout,
+            db_set_statement_cache_size,
+            db_statement_cache_stats,
+            db_subscribe_changes,
+            db_unsubscribe_changes,
+            db_set_change_notifications,
+            db_query_page,
+            db_query_paginated,
+            db_migrate,
+            db_schema,
+            db_get_stats,
+            db_stats,
+            db_backup,
+            configure_auto_backup,
+            db_restore,
+            db_relocate,
+            db_rekey,
+            db_encrypt,
+            db_export_json,
+            db_import_json,
+            db_import,
+            db_create_fts_index,
+            db_create_index,
+            db_drop_index,
+            db_register_function,
+            db_list_functions,
+            db_fts_search,
+            db_integrity_check,
+            db_check_integrity,
+            db_recover,
+            db_vacuum,
+            db_analyze,
+            db_maintenance,
+            // Encryption
+            init_encryption,
+            verify_password,
+            hash_password,
+            verify_password_hash,
+            encrypt_data,
+            decrypt_data,
+            encrypt_bytes,
+            decrypt_bytes,
+            encrypt_field,
+            decrypt_field,
+            encrypt_json,
+            decrypt_json,
+            encrypt_file,
+            decrypt_file,
+            hmac_sign,
+            hmac_verify,
+            is_data_intact,
+            clear_encryption_key,
+            lock_encryption,
+            set_auto_lock_timeout,
+            get_nonce_counter,
+            rotate_encryption_key,
+            rotate_encrypted_fields,
+            // Key-value store
+            kv_set,
+            kv_get,
+            kv_delete,
+            // Credentials
+            set_keyring_service,
+            store_credentials,
+            get_credentials,
+            delete_credentials,
+            list_accounts,
+            rotate_credentials,
+            cleanup_pending_credentials,
+            list_credentials,
+            list_credentials_with_meta,
+            generate_totp,
+            credentials_enumerate_supported,
+            store_oauth_token,
+            get_oauth_token,
+            delete_oauth_token,
+            // OAuth
+            store_oauth_state,
+            validate_oauth_state,
+            set_oauth_state_ttl,
+            purge_expired_oauth_states,
+            generate_pkce_pair,
+            get_pkce_verifier,
+            verify_oauth_pkce_verifier,
+            // Utilities
+            generate_random_string,
+            generate_secure_id,
+            open_browser,
+            get_app_data_dir,
+        ]);
+
+    builder
+        .invoke_handler(route_commands(move |invoke| handler(invoke)))
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(pairs: &[(&str, serde_json::Value)]) -> SqlParams {
+        SqlParams::Named(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    /// Argon2id's minimum cost, so tests don't spend a second per key
+    const TEST_KDF_COST: KdfCost = KdfCost {
+        memory_kib: 8,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    /// The key `password` derives under the parameters stored in `state`'s
+    /// database
+    fn stored_key(state: &AppState, password: &str) -> ZeroizeKey {
+        let params = state.with_conn(stored_kdf_params).unwrap();
+        password_key(params.as_ref(), password).unwrap()
+    }
+
+    fn test_conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT, age INTEGER);")
+            .unwrap();
+        conn
+    }
+
+    #[test]
+    fn named_insert_and_select() {
+        let conn = test_conn();
+
+        let affected = execute_statement(
+            &conn,
+            "INSERT INTO people (name, age) VALUES (:name, $age)",
+            &named(&[("name", "Ada".into()), ("$age", 36.into())]),
+        )
+        .unwrap();
+        assert_eq!(affected, 1);
+
+        let rows = query_rows(
+            &conn,
+            "SELECT name, age FROM people WHERE name = @name",
+            &named(&[("name", "Ada".into())]),
+        )
+        .unwrap();
+        assert_eq!(rows, vec![serde_json::json!({ "name": "Ada", "age": 36 })]);
+    }
+
+    #[test]
+    fn positional_and_named_are_exclusive() {
+        let result = SqlParams::from_args(Some(vec![1.into()]), Some(serde_json::Map::new()));
+        assert!(matches!(result, Err(SidecarError::InvalidState(_))));
+    }
+
+    #[test]
+    fn positional_params_still_bind() {
+        let conn = test_conn();
+        let params = SqlParams::Positional(vec!["Grace".into(), 45.into()]);
+        execute_statement(
+            &conn,
+            "INSERT INTO people (name, age) VALUES (?, ?)",
+            &params,
+        )
+        .unwrap();
+
+        let rows = query_rows(
+            &conn,
+            "SELECT age FROM people",
+            &SqlParams::Positional(vec![]),
+        )
+        .unwrap();
+        assert_eq!(rows, vec![serde_json::json!({ "age": 45 })]);
+    }
+
+    #[test]
+    fn close_then_reinit_at_same_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sidecar.db");
+        let state = AppState::new();
+
+        state.open_db(&path).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")?)
+            })
+            .unwrap();
+
+        assert!(state.close_db().unwrap());
+        assert!(!state.close_db().unwrap());
+        let err = state.with_conn(|_| Ok(())).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid state: Database not initialized");
+
+        state.open_db(&path).unwrap();
+        let rows = state
+            .with_conn(|conn| query_rows(conn, "SELECT x FROM t", &SqlParams::Positional(vec![])))
+            .unwrap();
+        assert_eq!(rows, vec![serde_json::json!({ "x": 1 })]);
+    }
+
+    #[test]
+    fn reinit_replaces_open_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+
+        state.open_db(&dir.path().join("a.db")).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("CREATE TABLE only_in_a (x INTEGER);")?))
+            .unwrap();
+
+        state.open_db(&dir.path().join("b.db")).unwrap();
+        let tables = state
+            .with_conn(|conn| {
+                query_rows(
+                    conn,
+                    "SELECT name FROM sqlite_master WHERE name = 'only_in_a'",
+                    &SqlParams::Positional(vec![]),
+                )
+            })
+            .unwrap();
+        assert!(tables.is_empty());
+    }
+
+    fn migrations() -> Vec<Migration> {
+        [
+            (1, "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);"),
+            (
+                2,
+                "ALTER TABLE notes ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;",
+            ),
+            (3, "CREATE INDEX idx_notes_pinned ON notes (pinned);"),
+        ]
+        .into_iter()
+        .map(|(version, sql)| Migration {
+            version,
+            up_sql: sql.to_string(),
+        })
+        .collect()
+    }
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrate_fresh_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(run_migrations(&conn, &migrations()).unwrap(), 3);
+        assert_eq!(user_version(&conn), 3);
+
+        // Idempotent on repeat runs
+        assert_eq!(run_migrations(&conn, &migrations()).unwrap(), 3);
+    }
+
+    #[test]
+    fn migrate_applies_only_remaining() {
+        let conn = Connection::open_in_memory().unwrap();
+        run_migrations(&conn, &migrations()[..1]).unwrap();
+        conn.execute("INSERT INTO notes (body) VALUES ('kept')", [])
+            .unwrap();
+
+        assert_eq!(run_migrations(&conn, &migrations()).unwrap(), 3);
+        let pinned: i64 = conn
+            .query_row("SELECT pinned FROM notes WHERE body = 'kept'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(pinned, 0);
+    }
+
+    #[test]
+    fn migrate_rejects_out_of_order_versions() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut list = migrations();
+        list.swap(1, 2);
+        assert!(matches!(
+            run_migrations(&conn, &list),
+            Err(SidecarError::InvalidState(_))
+        ));
+        assert_eq!(user_version(&conn), 0);
+    }
+
+    #[test]
+    fn failed_migration_rolls_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut list = migrations();
+        list[1].up_sql = "ALTER TABLE missing ADD COLUMN x INTEGER;".to_string();
+
+        assert!(run_migrations(&conn, &list).is_err());
+        assert_eq!(user_version(&conn), 1);
+    }
+
+    #[test]
+    fn backup_copies_live_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("live.db")).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1), (2), (3);",
+                )?)
+            })
+            .unwrap();
+
+        let dest = dir.path().join("backups/nested/copy.db");
+        let pacing = BackupPacing {
+            pages_per_step: 1,
+            step_delay: Duration::from_millis(1),
+        };
+        let mut steps = Vec::new();
+        let summary = backup_database(&state, &dest, false, pacing, |p| steps.push(p)).unwrap();
+
+        assert_eq!(summary.size_bytes, std::fs::metadata(&dest).unwrap().len());
+        let last = steps.last().unwrap();
+        assert_eq!(last.pages_done, last.pages_total);
+        assert_eq!(steps.len(), last.pages_total as usize, "one page per step");
+        assert_eq!(summary.pages_copied, last.pages_total as u64);
+        assert!(!dir.path().join("backups/nested/copy.db.partial").exists());
+
+        let copy = Connection::open(&dest).unwrap();
+        let count: i64 = copy
+            .query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn backup_pacing_defaults_to_small_spaced_steps() {
+        let pacing = BackupPacing::default();
+        assert_eq!(pacing.pages_per_step, 32);
+        assert_eq!(pacing.step_delay, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn backup_refuses_to_overwrite_without_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("live.db")).unwrap();
+
+        let dest = dir.path().join("copy.db");
+        std::fs::write(&dest, b"keep me").unwrap();
+
+        assert!(matches!(
+            backup_database(
+                &AppState::new(),
+                &dest,
+                true,
+                BackupPacing::default(),
+                |_| {}
+            ),
+            Err(SidecarError::InvalidState(_))
+        ));
+
+        assert!(matches!(
+            backup_database(&state, &dest, false, BackupPacing::default(), |_| {}),
+            Err(SidecarError::InvalidState(_))
+        ));
+        assert_eq!(std::fs::read(&dest).unwrap(), b"keep me");
+
+        backup_database(&state, &dest, true, BackupPacing::default(), |_| {}).unwrap();
+        assert_ne!(std::fs::read(&dest).unwrap(), b"keep me");
+    }
+
+    #[test]
+    fn rotate_key_reencrypts_under_new_password() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        let old_key = derive_key("old password");
+        *state.encryption_key.lock() = Some(old_key.clone());
+        let original = encrypt_with_key(&old_key, &[1; 12], "meeting notes", b"").unwrap();
+
+        let rotated = rotate_key(
+            &state,
+            "old password",
+            "new password",
+            std::slice::from_ref(&original),
+        )
+        .unwrap();
+
+        let new_key = stored_key(&state, "new password");
+        assert_eq!(*state.encryption_key.lock(), Some(new_key.clone()));
+        assert!(decrypt_with_key(&new_key, &original, b"").is_err());
+        assert_eq!(
+            decrypt_with_key(&new_key, &rotated[0], b"").unwrap(),
+            "meeting notes"
+        );
+    }
+
+    #[test]
+    fn rotate_key_rejects_wrong_old_password() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        let key = derive_key("correct");
+        *state.encryption_key.lock() = Some(key.clone());
+        let ciphertext = encrypt_with_key(&key, &[1; 12], "secret", b"").unwrap();
+
+        assert!(rotate_key(&state, "wrong", "new", &[ciphertext]).is_err());
+        assert_eq!(*state.encryption_key.lock(), Some(key));
+    }
+
+    #[test]
+    fn rotate_key_checks_the_old_password_while_locked() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        unlock_with_password(&state, "correct", &[]).unwrap();
+        let params = state.with_conn(stored_kdf_params).unwrap();
+        state.lock_key();
+
+        assert!(matches!(
+            rotate_key(&state, "anything", "new", &[]),
+            Err(SidecarError::EncryptionFailed(_))
+        ));
+        assert_eq!(state.with_conn(stored_kdf_params).unwrap(), params);
+        assert!(check_password(&state, "correct").unwrap());
+    }
+
+    #[test]
+    fn replacing_the_database_locks_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        state.open_db(&dir.path().join("first.db")).unwrap();
+        unlock_with_password(&state, "first", &[]).unwrap();
+        let backup = dir.path().join("backup.db");
+        backup_database(&state, &backup, false, BackupPacing::default(), |_| {}).unwrap();
+
+        state.open_db(&dir.path().join("second.db")).unwrap();
+        assert!(state.encryption_key.lock().is_none());
+        assert!(matches!(
+            kv_store_set(&state, "k", "v"),
+            Err(SidecarError::Unauthorized(_))
+        ));
+
+        unlock_with_password(&state, "second", &[]).unwrap();
+        state.close_db().unwrap();
+        restore_database(&state, &backup).unwrap();
+        assert!(state.encryption_key.lock().is_none());
+        unlock_with_password(&state, "first", &[]).unwrap();
+    }
+
+    #[test]
+    fn restore_replaces_live_database_and_keeps_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("live.db")).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1); PRAGMA user_version = 7;",
+                )?)
+            })
+            .unwrap();
+
+        let backup = dir.path().join("backup.db");
+        backup_database(&state, &backup, false, BackupPacing::default(), |_| {}).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("INSERT INTO t VALUES (2);")?))
+            .unwrap();
+
+        // Only after db_close
+        assert!(matches!(
+            restore_database(&state, &backup),
+            Err(SidecarError::InvalidState(_))
+        ));
+        state.close_db().unwrap();
+        assert_eq!(restore_database(&state, &backup).unwrap(), 7);
+
+        let rows = state
+            .with_conn(|conn| query_rows(conn, "SELECT x FROM t", &SqlParams::Positional(vec![])))
+            .unwrap();
+        assert_eq!(rows, vec![serde_json::json!({ "x": 1 })]);
+
+        let baks: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".bak"))
+            .collect();
+        assert_eq!(baks.len(), 1);
+    }
+
+    #[test]
+    fn restore_rejects_non_database_and_keeps_live_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("live.db")).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("CREATE TABLE t (x INTEGER);")?))
+            .unwrap();
+
+        let bogus = dir.path().join("bogus.db");
+        std::fs::write(&bogus, b"definitely not sqlite, just some text").unwrap();
+
+        assert!(matches!(
+            restore_database(&state, &bogus),
+            Err(SidecarError::Database(_))
+        ));
+        let tables = state
+            .with_conn(|conn| {
+                query_rows(
+                    conn,
+                    "SELECT name FROM sqlite_master WHERE name = 't'",
+                    &SqlParams::Positional(vec![]),
+                )
+            })
+            .unwrap();
+        assert_eq!(tables.len(), 1);
+    }
+
+    #[test]
+    fn restore_rejects_truncated_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("live.db")).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE t (x BLOB);
+                     WITH RECURSIVE c(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM c WHERE i < 100)
+                     INSERT INTO t SELECT randomblob(2000) FROM c;
+                     CREATE INDEX t_x ON t (x);",
+                )?)
+            })
+            .unwrap();
+
+        let backup = dir.path().join("backup.db");
+        backup_database(&state, &backup, false, BackupPacing::default(), |_| {}).unwrap();
+        let bytes = std::fs::read(&backup).unwrap();
+        std::fs::write(&backup, &bytes[..bytes.len() / 2]).unwrap();
+
+        assert!(matches!(
+            restore_database(&state, &backup),
+            Err(SidecarError::Database(_))
+        ));
+        let count: i64 = state
+            .with_conn(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))?))
+            .unwrap();
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn query_page_limits_and_counts() {
+        let conn = test_conn();
+        for i in 0..25 {
+            conn.execute(
+                "INSERT INTO people (name, age) VALUES (?1, ?2)",
+                rusqlite::params![format!("p{i}"), i],
+            )
+            .unwrap();
+        }
+
+        let params = SqlParams::Positional(vec![10.into()]);
+        let sql = "SELECT age FROM people WHERE age >= ? ORDER BY age;";
+
+        let page = query_page(&conn, sql, &params, 10, 1, true).unwrap();
+        assert_eq!(page.total_count, Some(15));
+        assert_eq!(page.rows.len(), 5);
+        assert_eq!(page.rows[0], serde_json::json!({ "age": 20 }));
+
+        let page = query_page(&conn, sql, &params, 10, 0, false).unwrap();
+        assert_eq!(page.total_count, None);
+        assert_eq!(page.rows.len(), 10);
+    }
+
+    #[test]
+    fn query_page_ignores_trailing_comments() {
+        let conn = test_conn();
+        for i in 0..25 {
+            conn.execute(
+                "INSERT INTO people (name, age) VALUES (?1, ?2)",
+                rusqlite::params![format!("p{i}"), i],
+            )
+            .unwrap();
+        }
+        let none = SqlParams::Positional(vec![]);
+
+        for sql in [
+            "SELECT age FROM people ORDER BY age -- newest first",
+            "SELECT age FROM people ORDER BY age; -- newest first",
+            "SELECT age FROM people ORDER BY age /* ; */ ;",
+        ] {
+            let page = query_page(&conn, sql, &none, 10, 2, true).unwrap();
+            assert_eq!(page.rows.len(), 5, "{sql}");
+            assert_eq!(page.rows[0], serde_json::json!({ "age": 20 }), "{sql}");
+            assert_eq!(page.total_count, Some(25), "{sql}");
+        }
+
+        let page =
+            query_page(&conn, "SELECT age FROM people LIMIT 12", &none, 10, 1, true).unwrap();
+        assert_eq!((page.rows.len(), page.total_count), (2, Some(12)));
+        assert!(matches!(
+            query_page(&conn, "SELECT 1; SELECT 2", &none, 10, 0, false),
+            Err(SidecarError::MultipleStatements(_))
+        ));
+        assert!(matches!(
+            query_page(&conn, "SELEC 1", &none, 10, 0, false),
+            Err(SidecarError::Database(_))
+        ));
+    }
+
+    #[test]
+    fn named_params_report_missing_placeholder() {
+        let conn = test_conn();
+        let err = execute_statement(
+            &conn,
+            "INSERT INTO people (name, age) VALUES (:name, :age)",
+            &named(&[("name", "Ada".into())]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid state: Missing value for named parameter :age"
+        );
+    }
+
+    #[test]
+    fn named_params_reject_extra_keys() {
+        let conn = test_conn();
+        let err = query_rows(
+            &conn,
+            "SELECT * FROM people WHERE name = :name",
+            &named(&[("name", "Ada".into()), ("nmae", "typo".into())]),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("'nmae'"), "{err}");
+    }
+
+    #[test]
+    fn cleared_key_is_not_initialized() {
+        let state = AppState::new();
+        *state.encryption_key.lock() = Some(derive_key("password"));
+        assert!(state
+            .with_key(|key| encrypt_with_key(key, &state.next_nonce()?, "x", b""))
+            .is_ok());
+
+        state.encryption_key.lock().take();
+        let err = state
+            .with_key(|key| encrypt_with_key(key, &state.next_nonce()?, "x", b""))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Unauthorized: Encryption not initialized");
+    }
+
+    #[test]
+    fn fresh_oauth_state_validates_once() {
+        let state = AppState::new();
+        let now = Instant::now();
+        store_oauth_state_at(&state, "slack".into(), "abc".into(), now);
+
+        let later = now + Duration::from_secs(60);
+        assert!(!validate_oauth_state_at(&state, "slack", "wrong", later));
+        assert!(validate_oauth_state_at(&state, "slack", "abc", later));
+        assert!(!validate_oauth_state_at(&state, "slack", "abc", later));
+    }
+
+    #[test]
+    fn expired_oauth_state_fails_and_is_removed() {
+        let state = AppState::new();
+        let now = Instant::now();
+        store_oauth_state_at(&state, "gmail".into(), "abc".into(), now);
+
+        let expired = now + DEFAULT_OAUTH_STATE_TTL;
+        assert!(!validate_oauth_state_at(&state, "gmail", "abc", expired));
+        assert!(state.oauth_states.lock().is_empty());
+    }
+
+    #[test]
+    fn purge_removes_only_expired_oauth_states() {
+        let state = AppState::new();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        store_oauth_state_at(&state, "gmail".into(), "old".into(), now);
+        store_oauth_state_at(&state, "slack".into(), "new".into(), later);
+
+        assert_eq!(purge_oauth_states_at(&state, later), 0);
+        assert_eq!(
+            purge_oauth_states_at(&state, now + DEFAULT_OAUTH_STATE_TTL),
+            1
+        );
+        assert!(validate_oauth_state_at(&state, "slack", "new", later));
+    }
+
+    #[test]
+    fn storing_evicts_expired_states() {
+        let state = AppState::new();
+        *state.oauth_state_ttl.lock() = Duration::from_secs(30);
+        let now = Instant::now();
+        store_oauth_state_at(&state, "zoom".into(), "old".into(), now);
+        store_oauth_state_at(
+            &state,
+            "slack".into(),
+            "new".into(),
+            now + Duration::from_secs(31),
+        );
+
+        let states = state.oauth_states.lock();
+        assert_eq!(states.len(), 1);
+        assert!(states.contains_key("slack"));
+    }
+
+    #[test]
+    fn pkce_challenge_matches_rfc7636_vector() {
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn pkce_verifier_is_stored_once() {
+        let state = AppState::new();
+        let now = Instant::now();
+        let pair = generate_pkce_pair_at(&state, "gmail".into(), now);
+        assert_eq!(pair.verifier.len(), 43);
+        assert_eq!(pair.challenge, pkce_challenge(&pair.verifier));
+        assert_eq!(pair.method, "S256");
+
+        assert_eq!(
+            get_pkce_verifier_at(&state, "gmail", now).as_deref(),
+            Some(pair.verifier.as_str())
+        );
+        assert_eq!(get_pkce_verifier_at(&state, "gmail", now), None);
+
+        generate_pkce_pair_at(&state, "slack".into(), now);
+        let expired = now + DEFAULT_OAUTH_STATE_TTL;
+        assert_eq!(get_pkce_verifier_at(&state, "slack", expired), None);
+    }
+
+    #[test]
+    fn pkce_verifier_check_does_not_consume() {
+        let state = AppState::new();
+        let now = Instant::now();
+        let pair = generate_pkce_pair_at(&state, "gmail".into(), now);
+
+        assert!(!verify_pkce_verifier_at(&state, "gmail", "wrong", now));
+        assert!(!verify_pkce_verifier_at(
+            &state,
+            "slack",
+            &pair.verifier,
+            now
+        ));
+        assert!(verify_pkce_verifier_at(
+            &state,
+            "gmail",
+            &pair.verifier,
+            now
+        ));
+        assert!(!verify_pkce_verifier_at(
+            &state,
+            "gmail",
+            &pair.verifier,
+            now + DEFAULT_OAUTH_STATE_TTL
+        ));
+        assert_eq!(
+            get_pkce_verifier_at(&state, "gmail", now).as_deref(),
+            Some(pair.verifier.as_str())
+        );
+    }
+
+    #[test]
+    fn integrity_check_reports_foreign_key_violations() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE parent (id INTEGER PRIMARY KEY);
+             CREATE TABLE child (id INTEGER PRIMARY KEY, parent_id INTEGER REFERENCES parent(id));
+             INSERT INTO parent VALUES (1);
+             INSERT INTO child VALUES (10, 1);",
+        )
+        .unwrap();
+        assert!(integrity_issues(&conn).unwrap().is_empty());
+
+        // Simulate a violation written while enforcement was off
+        conn.execute_batch("PRAGMA foreign_keys = OFF; INSERT INTO child VALUES (11, 99);")
+            .unwrap();
+        let issues = integrity_issues(&conn).unwrap();
+        assert_eq!(
+            issues,
+            ["Foreign key violation: child row 11 references missing row in parent"]
+        );
+    }
+
+    #[test]
+    fn row_values_mirror_storage_class() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE v (n, i, r, t, b);")
+            .unwrap();
+        execute_statement(
+            &conn,
+            "INSERT INTO v VALUES (?, ?, ?, ?, X'009FFF')",
+            &SqlParams::Positional(vec![
+                serde_json::Value::Null,
+                i64::MAX.into(),
+                2.5.into(),
+                "42".into(),
+            ]),
+        )
+        .unwrap();
+
+        let rows = query_rows(
+            &conn,
+            "SELECT n, i, r, t, b, typeof(t) AS tt FROM v",
+            &SqlParams::Positional(vec![]),
+        )
+        .unwrap();
+        assert_eq!(
+            rows[0],
+            serde_json::json!({
+                "n": null,
+                "i": i64::MAX,
+                "r": 2.5,
+                "t": "42",
+                "b": { "$blob": "AJ//" },
+                "tt": "text",
+            })
+        );
+    }
+
+    #[test]
+    fn blob_params_round_trip() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE v (b);").unwrap();
+        let blob = serde_json::json!({ "$blob": "AJ//" });
+        execute_statement(
+            &conn,
+            "INSERT INTO v VALUES (?)",
+            &SqlParams::Positional(vec![blob.clone()]),
+        )
+        .unwrap();
+
+        let rows = query_rows(
+            &conn,
+            "SELECT b, typeof(b) AS tb FROM v",
+            &SqlParams::Positional(vec![]),
+        )
+        .unwrap();
+        assert_eq!(rows[0], serde_json::json!({ "b": blob, "tb": "blob" }));
+    }
+
+    #[test]
+    fn invalid_blob_param_names_its_index() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE v (a, b);").unwrap();
+        let err = execute_statement(
+            &conn,
+            "INSERT INTO v VALUES (?, ?)",
+            &SqlParams::Positional(vec![
+                1.into(),
+                serde_json::json!({ "$blob": "not base64!" }),
+            ]),
+        )
+        .unwrap_err();
+        assert!(matches!(err, SidecarError::Serialization(_)), "{err}");
+        assert!(err.to_string().contains("Parameter 2"), "{err}");
+    }
+
+    #[test]
+    fn vacuum_reports_reclaimed_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = open_connection(&dir.path().join("app.db"), None).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE blobs (data BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+             INSERT INTO blobs SELECT zeroblob(4096) FROM n;
+             DELETE FROM blobs;",
+        )
+        .unwrap();
+
+        assert!(vacuum_database(&conn).unwrap() > 200 * 4096);
+        assert_eq!(vacuum_database(&conn).unwrap(), 0);
+
+        conn.execute_batch("BEGIN").unwrap();
+        assert!(matches!(
+            vacuum_database(&conn),
+            Err(SidecarError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn deadline_interrupts_slow_queries() {
+        let conn = Connection::open_in_memory().unwrap();
+        let slow = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c)
+                    SELECT count(*) FROM (SELECT x FROM c LIMIT 10000000000)";
+        let err = with_deadline(&conn, Some(50), |conn| {
+            query_rows(conn, slow, &SqlParams::Positional(vec![]))
+        })
+        .unwrap_err();
+        assert!(matches!(err, SidecarError::Timeout(_)), "{err}");
+
+        let rows = with_deadline(&conn, Some(5_000), |conn| {
+            query_rows(conn, "SELECT 1 AS one", &SqlParams::Positional(vec![]))
+        })
+        .unwrap();
+        assert_eq!(rows, vec![serde_json::json!({ "one": 1 })]);
+    }
+
+    #[test]
+    fn execute_returning_only_reports_rowid_for_inserts() {
+        let conn = test_conn();
+        let insert = execute_returning(
+            &conn,
+            "INSERT INTO people (name, age) VALUES (?, ?)",
+            &SqlParams::Positional(vec!["Ada".into(), 36.into()]),
+        )
+        .unwrap();
+        assert_eq!(insert.rows_affected, 1);
+        assert_eq!(insert.last_insert_rowid, Some(conn.last_insert_rowid()));
+
+        let update = execute_returning(
+            &conn,
+            "UPDATE people SET age = age + 1",
+            &SqlParams::Positional(vec![]),
+        )
+        .unwrap();
+        assert_eq!(update.rows_affected, 1);
+        assert_eq!(update.last_insert_rowid, None);
+    }
+
+    #[test]
+    fn execute_returning_reports_autoincrement_ids() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE messages (id INTEGER PRIMARY KEY AUTOINCREMENT, body TEXT);
+             INSERT INTO messages (body) VALUES ('first');
+             DELETE FROM messages;",
+        )
+        .unwrap();
+
+        let inserted = execute_returning(
+            &conn,
+            "insert into messages (body) values (?)",
+            &SqlParams::Positional(vec!["second".into()]),
+        )
+        .unwrap();
+        let id: i64 = conn
+            .query_row("SELECT id FROM messages WHERE body = 'second'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        // AUTOINCREMENT never reuses the deleted row's id
+        assert_eq!(id, 2);
+        assert_eq!(inserted.last_insert_rowid, Some(id));
+        assert_eq!(inserted.rows_affected, 1);
+    }
+
+    #[test]
+    fn blob_alias_binds_raw_bytes() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE v (b);").unwrap();
+        let bytes = vec![0u8, 1, 254, 255, b'x'];
+        execute_statement(
+            &conn,
+            "INSERT INTO v VALUES (:b)",
+            &named(&[(
+                "b",
+                serde_json::json!({ "__blob_base64": BASE64.encode(&bytes) }),
+            )]),
+        )
+        .unwrap();
+
+        let stored: Vec<u8> = conn
+            .query_row("SELECT b FROM v", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, bytes);
+    }
+
+    #[test]
+    fn paginated_queries_report_total() {
+        let conn = test_conn();
+        for age in 1..=5 {
+            execute_statement(
+                &conn,
+                "INSERT INTO people (name, age) VALUES ('p', ?)",
+                &SqlParams::Positional(vec![age.into()]),
+            )
+            .unwrap();
+        }
+        let sql = "SELECT age FROM people WHERE age > ? ORDER BY age";
+        let params = SqlParams::Positional(vec![0.into()]);
+        let ages = |page: &PaginatedRows| -> Vec<i64> {
+            page.rows
+                .iter()
+                .map(|r| r["age"].as_i64().unwrap())
+                .collect()
+        };
+
+        let first = query_paginated(&conn, sql, &params, 2, 0).unwrap();
+        assert_eq!((ages(&first), first.total), (vec![1, 2], 5));
+
+        let middle = query_paginated(&conn, sql, &params, 2, 2).unwrap();
+        assert_eq!((ages(&middle), middle.total), (vec![3, 4], 5));
+
+        let past_end = query_paginated(&conn, sql, &params, 2, 10).unwrap();
+        assert!(past_end.rows.is_empty());
+        assert_eq!(past_end.total, 5);
+    }
+
+    #[test]
+    fn paginated_queries_accept_limit_and_trailing_comments() {
+        let conn = test_conn();
+        conn.execute_batch(
+            "INSERT INTO people (name, age) VALUES ('limit', 1), ('b', 2), ('c', 3), ('d', 4);",
+        )
+        .unwrap();
+        let none = SqlParams::Positional(vec![]);
+
+        let page = query_paginated(
+            &conn,
+            "SELECT name AS \"limit\" FROM people WHERE name <> 'limit' ORDER BY age -- x",
+            &none,
+            2,
+            0,
+        )
+        .unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(
+            page.rows,
+            [
+                serde_json::json!({ "limit": "b" }),
+                serde_json::json!({ "limit": "c" })
+            ]
+        );
+
+        let page = query_paginated(
+            &conn,
+            "SELECT age FROM people ORDER BY age LIMIT 3; -- x",
+            &none,
+            2,
+            2,
+        )
+        .unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(page.rows, [serde_json::json!({ "age": 3 })]);
+    }
+
+    #[test]
+    fn interrupted_and_busy_errors_map_to_their_own_variants() {
+        let failure = |code| {
+            SidecarError::from(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(code),
+                None,
+            ))
+        };
+        assert!(matches!(
+            failure(rusqlite::ffi::SQLITE_INTERRUPT),
+            SidecarError::Cancelled(_)
+        ));
+        assert!(matches!(
+            failure(rusqlite::ffi::SQLITE_BUSY),
+            SidecarError::Busy(_)
+        ));
+        assert!(matches!(
+            failure(rusqlite::ffi::SQLITE_LOCKED),
+            SidecarError::Busy(_)
+        ));
+        assert!(matches!(
+            failure(rusqlite::ffi::SQLITE_CONSTRAINT),
+            SidecarError::Database(_)
+        ));
+    }
+
+    #[test]
+    fn stream_rows_delivers_batches_and_stops_when_asked() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE n (x INTEGER);
+             WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 1234)
+             INSERT INTO n SELECT x FROM c;",
+        )
+        .unwrap();
+        let sql = "SELECT x FROM n ORDER BY x";
+        let no_params = SqlParams::Positional(vec![]);
+
+        let mut sizes = Vec::new();
+        let total = stream_rows(&conn, sql, &no_params, 500, |rows| {
+            sizes.push(rows.len());
+            true
+        })
+        .unwrap();
+        assert_eq!(total, 1234);
+        assert_eq!(sizes, vec![500, 500, 234]);
+
+        let mut batches = 0;
+        let total = stream_rows(&conn, sql, &no_params, 500, |_| {
+            batches += 1;
+            false
+        })
+        .unwrap();
+        assert_eq!((total, batches), (500, 1));
+    }
+
+    #[test]
+    fn export_writes_one_json_line_per_row() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = test_conn();
+        conn.execute_batch("INSERT INTO people (name, age) VALUES ('Ada', 36), ('Alan', 41);")
+            .unwrap();
+
+        let dest = dir.path().join("people.ndjson");
+        assert_eq!(export_table_json(&conn, "people", &dest).unwrap(), 2);
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&dest)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({ "id": 1, "name": "Ada", "age": 36 }),
+                serde_json::json!({ "id": 2, "name": "Alan", "age": 41 }),
+            ]
+        );
+
+        let missing = dir.path().join("nope.ndjson");
+        assert!(matches!(
+            export_table_json(&conn, "people; DROP TABLE people", &missing),
+            Err(SidecarError::NotFound(_))
+        ));
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn stream_query_emits_batches_then_done() {
+        let state = AppState::new();
+        *state.db.lock() = Some(Connection::open_in_memory().unwrap());
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE n (x INTEGER);
+                     WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 10000)
+                     INSERT INTO n SELECT x FROM c;",
+                )?)
+            })
+            .unwrap();
+
+        let mut events = Vec::new();
+        let total = stream_query(
+            &state,
+            "s1".into(),
+            "SELECT x FROM n",
+            &SqlParams::Positional(vec![]),
+            500,
+            |event| {
+                events.push(event);
+                true
+            },
+        )
+        .unwrap();
+
+        assert_eq!(total, 10_000);
+        assert_eq!(events.len(), 21);
+        assert!(events[..20]
+            .iter()
+            .all(|e| matches!(e, StreamEvent::Batch(b) if b.rows.len() == 500)));
+        assert!(matches!(
+            &events[20],
+            StreamEvent::Done(done) if done.total_rows == 10_000 && !done.cancelled
+        ));
+        assert!(state.query_streams.lock().is_empty());
+    }
+
+    #[test]
+    fn stream_query_stops_when_window_closes() {
+        let state = AppState::new();
+        *state.db.lock() = Some(test_conn());
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "INSERT INTO people (name) VALUES ('a'), ('b'), ('c'), ('d'), ('e');",
+                )?)
+            })
+            .unwrap();
+
+        let mut sent = 0;
+        let mut last = None;
+        let result = stream_query(
+            &state,
+            "s2".into(),
+            "SELECT name FROM people",
+            &SqlParams::Positional(vec![]),
+            1,
+            |event| {
+                let open = sent < 2;
+                if open {
+                    sent += 1;
+                }
+                last = Some(event);
+                open
+            },
+        );
+
+        assert!(result.is_err());
+        assert_eq!(sent, 2);
+        assert!(matches!(last, Some(StreamEvent::Error(_))));
+    }
+
+    #[test]
+    fn query_one_returns_first_row_or_none() {
+        let conn = test_conn();
+        conn.execute_batch("INSERT INTO people (name, age) VALUES ('Ada', 36), ('Alan', 41);")
+            .unwrap();
+        let no_params = SqlParams::Positional(vec![]);
+
+        let ada = query_one(
+            &conn,
+            "SELECT name FROM people WHERE age = 36",
+            &no_params,
+            true,
+        );
+        assert_eq!(ada.unwrap(), Some(serde_json::json!({ "name": "Ada" })));
+
+        let nobody = query_one(
+            &conn,
+            "SELECT name FROM people WHERE age > 99",
+            &no_params,
+            true,
+        );
+        assert_eq!(nobody.unwrap(), None);
+
+        let sql = "SELECT name FROM people ORDER BY id";
+        assert_eq!(
+            query_one(&conn, sql, &no_params, false).unwrap(),
+            Some(serde_json::json!({ "name": "Ada" }))
+        );
+        assert!(matches!(
+            query_one(&conn, sql, &no_params, true),
+            Err(SidecarError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn import_reads_ndjson_into_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = test_conn();
+        conn.execute_batch("INSERT INTO people (id, name, age) VALUES (1, 'Ada', 36);")
+            .unwrap();
+
+        let src = dir.path().join("people.ndjson");
+        std::fs::write(
+            &src,
+            "{\"id\": 1, \"name\": \"Ada Lovelace\", \"age\": 36}\n\n{\"id\": 2, \"name\": \"Alan\"}\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            import_table_json(&conn, "people", &src, "ignore").unwrap(),
+            1
+        );
+        assert!(import_table_json(&conn, "people", &src, "fail").is_err());
+        assert_eq!(
+            import_table_json(&conn, "people", &src, "replace").unwrap(),
+            2
+        );
+
+        let rows = query_rows(
+            &conn,
+            "SELECT id, name, age FROM people ORDER BY id",
+            &SqlParams::Positional(vec![]),
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                serde_json::json!({ "id": 1, "name": "Ada Lovelace", "age": 36 }),
+                serde_json::json!({ "id": 2, "name": "Alan", "age": null }),
+            ]
+        );
+
+        assert!(matches!(
+            import_table_json(&conn, "nobody", &src, "fail"),
+            Err(SidecarError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn readers_run_while_writer_is_busy() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new());
+        state.open_db(&dir.path().join("app.db")).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1), (2), (3);",
+                )?)
+            })
+            .unwrap();
+
+        // Hold the writer for the whole test; readers must not need it
+        let writer = state.db.lock();
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    state.with_reader(|conn| {
+                        query_rows(
+                            conn,
+                            "SELECT SUM(x) AS total FROM t",
+                            &SqlParams::Positional(vec![]),
+                        )
+                    })
+                })
+            })
+            .collect();
+        for handle in handles {
+            let rows = handle.join().unwrap().unwrap();
+            assert_eq!(rows, vec![serde_json::json!({ "total": 6 })]);
+        }
+        drop(writer);
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn database_password_needs_sqlcipher_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        *state.db_key.lock() = Some(DbPassphrase::new("hunter2".to_string()));
+        assert!(matches!(
+            state.open_db(&dir.path().join("app.db")),
+            Err(SidecarError::Encryption(_))
+        ));
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn wrong_database_password_is_reported_cleanly() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let state = AppState::new();
+        *state.db_key.lock() = Some(DbPassphrase::new("right".to_string()));
+        state.open_db(&path).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("CREATE TABLE t (x INTEGER);")?))
+            .unwrap();
+        state.close_db().unwrap();
+
+        *state.db_key.lock() = Some(DbPassphrase::new("wrong".to_string()));
+        let err = state.open_db(&path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Encryption failed: Invalid database password"
+        );
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn plaintext_database_can_be_encrypted_and_rekeyed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let state = AppState::new();
+        state.open_db(&path).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (7); PRAGMA user_version = 3;",
+                )?)
+            })
+            .unwrap();
+
+        encrypt_database(&state, DbPassphrase::new("first".to_string())).unwrap();
+        assert!(matches!(
+            rekey_database(&state, "wrong", DbPassphrase::new("second".to_string())),
+            Err(SidecarError::EncryptionFailed(_))
+        ));
+        rekey_database(&state, "first", DbPassphrase::new("second".to_string())).unwrap();
+        state.close_db().unwrap();
+
+        let header = std::fs::read(&path).unwrap();
+        assert_ne!(&header[..16], b"SQLite format 3\0");
+
+        *state.db_key.lock() = Some(DbPassphrase::new("first".to_string()));
+        assert!(state.open_db(&path).is_err());
+
+        // A plain SQLCipher connection opens it with just the password
+        let plain = Connection::open(&path).unwrap();
+        plain.pragma_update(None, "key", "second").unwrap();
+        let x: i64 = plain
+            .query_row("SELECT x FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(x, 7);
+        drop(plain);
+
+        *state.db_key.lock() = Some(DbPassphrase::new("second".to_string()));
+        state.open_db(&path).unwrap();
+        let (x, version) = state
+            .with_reader(|conn| {
+                let x: i64 = conn.query_row("SELECT x FROM t", [], |row| row.get(0))?;
+                let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+                Ok((x, version))
+            })
+            .unwrap();
+        assert_eq!((x, version), (7, 3));
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn encrypted_database_cannot_be_reopened_without_a_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let state = AppState::new();
+        state.open_db(&path).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("CREATE TABLE t (x INTEGER);")?))
+            .unwrap();
+        encrypt_database(&state, DbPassphrase::new("secret".to_string())).unwrap();
+        state.close_db().unwrap();
+
+        *state.db_key.lock() = None;
+        let reopened = state
+            .open_db(&path)
+            .and_then(|()| state.with_conn(|conn| Ok(conn.execute_batch("SELECT * FROM t;")?)));
+        assert!(reopened.is_err());
+    }
+
+    #[test]
+    fn fts_index_tracks_table_and_ranks_matches() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE messages (id INTEGER PRIMARY KEY, sender TEXT, body TEXT);
+             INSERT INTO messages (sender, body) VALUES
+                 ('ada', 'the release is blocked on review'),
+                 ('alan', 'lunch?');",
+        )
+        .unwrap();
+
+        let index = create_fts_index(&conn, "messages", &["body".to_string()]).unwrap();
+        assert_eq!(index, "messages_fts");
+        create_fts_index(&conn, "messages", &["body".to_string()]).unwrap();
+
+        conn.execute_batch(
+            "INSERT INTO messages (sender, body) VALUES ('grace', 'review the blocked release notes');
+             UPDATE messages SET body = 'lunch is blocked' WHERE sender = 'alan';
+             DELETE FROM messages WHERE sender = 'ada';",
+        )
+        .unwrap();
+
+        let hits = fts_search(&conn, &index, "blocked", 10, 0).unwrap();
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().all(|hit| hit["snippet"]
+            .as_str()
+            .unwrap()
+            .contains("<mark>blocked</mark>")));
+
+        let hits = fts_search(&conn, &index, "release review", 10, 0).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0]["body"], "review the blocked release notes");
+    }
+
+    #[test]
+    fn fts_search_falls_back_to_phrase_on_syntax_errors() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE notes (body TEXT);
+             INSERT INTO notes VALUES ('she said \"ship it\" twice');",
+        )
+        .unwrap();
+        let index = create_fts_index(&conn, "notes", &["body".to_string()]).unwrap();
+
+        assert_eq!(
+            fts_search(&conn, &index, "\"ship it", 10, 0).unwrap().len(),
+            1
+        );
+        assert_eq!(
+            fts_search(&conn, &index, "ship AND (", 10, 0)
+                .unwrap()
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn bytes_round_trip_without_base64() {
+        let key = derive_key("password");
+        let data = vec![0u8, 159, 146, 150, 255];
+
+        let sealed = encrypt_bytes_with_key(&key, &[1; 12], &data, b"").unwrap();
+        assert_eq!(sealed.len(), 12 + data.len() + 16);
+        assert_eq!(decrypt_bytes_with_key(&key, &sealed, b"").unwrap(), data);
+
+        let other = derive_key("other");
+        assert!(decrypt_bytes_with_key(&other, &sealed, b"").is_err());
+    }
+
+    #[test]
+    fn integrity_report_lists_foreign_key_violations() {
+        let conn = test_conn();
+        conn.execute_batch(
+            "CREATE TABLE pets (id INTEGER PRIMARY KEY, owner INTEGER REFERENCES people(id));
+             PRAGMA foreign_keys=OFF;
+             INSERT INTO pets (id, owner) VALUES (7, 42);",
+        )
+        .unwrap();
+
+        let report = integrity_report(&conn).unwrap();
+        assert!(!report.ok);
+        assert!(report.errors.is_empty());
+        assert_eq!(report.foreign_key_violations.len(), 1);
+        let violation = &report.foreign_key_violations[0];
+        assert_eq!(violation.table, "pets");
+        assert_eq!(violation.rowid, Some(7));
+        assert_eq!(violation.parent, "people");
+        assert_eq!(
+            report.issues,
+            ["Foreign key violation: pets row 7 references missing row in people"]
+        );
+        assert_eq!(integrity_issues(&conn).unwrap(), report.issues);
+    }
+
+    #[test]
+    fn recover_salvages_rows_and_keeps_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let live = dir.path().join("live.db");
+        let state = AppState::new();
+        state.open_db(&live).unwrap();
+        state
+            .with_conn(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+                     CREATE INDEX notes_body ON notes (body);
+                     INSERT INTO notes (body) VALUES ('alpha'), ('beta'), ('gamma');
+                     PRAGMA user_version = 3;",
+                )?;
+                create_fts_index(conn, "notes", &["body".to_string()])?;
+                Ok(())
+            })
+            .unwrap();
+
+        let report = recover_database(&state).unwrap();
+        assert_eq!(report.tables.len(), 1);
+        assert_eq!(report.tables[0].table, "notes");
+        assert_eq!(report.tables[0].rows_recovered, 3);
+        assert!(report.tables[0].complete);
+
+        let corrupt = Path::new(&report.corrupt_path);
+        assert_eq!(corrupt, dir.path().join("live.db.corrupt"));
+        let original = Connection::open(corrupt).unwrap();
+        let kept: i64 = original
+            .query_row("SELECT COUNT(*) FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(kept, 3);
+
+        state
+            .with_conn(|conn| {
+                assert!(integrity_report(conn)?.ok);
+                let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+                assert_eq!(version, 3);
+                let index: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE name = 'notes_body'",
+                    [],
+                    |row| row.get(0),
+                )?;
+                assert_eq!(index, 1);
+                Ok(())
+            })
+            .unwrap();
+        let hits = state
+            .with_conn(|conn| fts_search(conn, "notes_fts", "beta", 10, 0))
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+
+        // A second recovery must not overwrite the first .corrupt file
+        let again = recover_database(&state).unwrap();
+        assert_ne!(again.corrupt_path, report.corrupt_path);
+        assert!(corrupt.exists());
+    }
+
+    #[test]
+    fn recover_stops_at_damaged_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let live = dir.path().join("live.db");
+        let state = AppState::new();
+        state.open_db(&live).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE t (x BLOB);
+                     WITH RECURSIVE c(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM c WHERE i < 100)
+                     INSERT INTO t SELECT randomblob(2000) FROM c;",
+                )?)
+            })
+            .unwrap();
+        state.close_db().unwrap();
+
+        let mut bytes = std::fs::read(&live).unwrap();
+        let page = 4096;
+        bytes[page * 40..page * 41].fill(0xff);
+        std::fs::write(&live, &bytes).unwrap();
+        state.open_db(&live).unwrap();
+
+        let report = recover_database(&state).unwrap();
+        let salvaged = &report.tables[0];
+        assert!(!salvaged.complete);
+        assert!(salvaged.rows_recovered > 0 && salvaged.rows_recovered < 100);
+        assert_eq!(std::fs::read(&report.corrupt_path).unwrap(), bytes);
+        state
+            .with_conn(|conn| {
+                assert!(integrity_report(conn)?.ok);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rotate_fields_reencrypts_text_and_blob_columns() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        let old_key = derive_key("old password");
+        *state.encryption_key.lock() = Some(old_key.clone());
+        state
+            .with_conn(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE notes (note_id INTEGER PRIMARY KEY, body TEXT, raw BLOB)",
+                )?;
+                conn.execute(
+                    "INSERT INTO notes (body, raw) VALUES (?1, ?2), (NULL, NULL)",
+                    rusqlite::params![
+                        encrypt_with_key(&old_key, &[1; 12], "meeting notes", b"")?,
+                        encrypt_bytes_with_key(&old_key, &[2; 12], b"\x00\x01", b"")?
+                    ],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+
+        let fields = ["body", "raw"].map(|column| EncryptedField {
+            table: "notes".to_string(),
+            column: column.to_string(),
+            id_column: "note_id".to_string(),
+            aad_column: None,
+        });
+        assert_eq!(
+            rotate_fields(&state, "old password", "new password", &fields).unwrap(),
+            2
+        );
+
+        let new_key = stored_key(&state, "new password");
+        assert_eq!(*state.encryption_key.lock(), Some(new_key.clone()));
+        let (body, raw): (String, Vec<u8>) = state
+            .with_conn(|conn| {
+                Ok(
+                    conn.query_row("SELECT body, raw FROM notes WHERE note_id = 1", [], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })?,
+                )
+            })
+            .unwrap();
+        assert_eq!(
+            decrypt_with_key(&new_key, &body, b"").unwrap(),
+            "meeting notes"
+        );
+        assert_eq!(
+            decrypt_bytes_with_key(&new_key, &raw, b"").unwrap(),
+            b"\x00\x01"
+        );
+    }
+
+    #[test]
+    fn rotate_fields_checks_the_old_password_while_locked() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        unlock_with_password(&state, "correct", &[]).unwrap();
+        let params = state.with_conn(stored_kdf_params).unwrap();
+        state.lock_key();
+
+        assert!(matches!(
+            rotate_fields(&state, "wrong", "new", &[]),
+            Err(SidecarError::EncryptionFailed(_))
+        ));
+        assert_eq!(state.with_conn(stored_kdf_params).unwrap(), params);
+        assert!(state.encryption_key.lock().is_none());
+        unlock_with_password(&state, "correct", &[]).unwrap();
+
+        state.lock_key();
+        rotate_fields(&state, "correct", "new", &[]).unwrap();
+        assert!(check_password(&state, "new").unwrap());
+    }
+
+    #[test]
+    fn rotations_reencrypt_the_kv_store() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        unlock_with_password(&state, "first", &[]).unwrap();
+        kv_store_set(&state, "theme", "dark").unwrap();
+
+        rotate_key(&state, "first", "second", &[]).unwrap();
+        let listed = [kv_store_field()];
+        assert_eq!(
+            rotate_fields(&state, "second", "third", &listed).unwrap(),
+            1,
+            "kv_store is re-encrypted once even when listed"
+        );
+
+        state.lock_key();
+        unlock_with_password(&state, "third", &[]).unwrap();
+        assert_eq!(
+            kv_store_get(&state, "theme").unwrap().as_deref(),
+            Some("dark")
+        );
+    }
+
+    #[test]
+    fn rotate_fields_keeps_values_bound_to_their_aad() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        let old_key = derive_key("old password");
+        *state.encryption_key.lock() = Some(old_key.clone());
+        state
+            .with_conn(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE notes (id INTEGER PRIMARY KEY, record TEXT, body TEXT)",
+                )?;
+                conn.execute(
+                    "INSERT INTO notes (record, body) VALUES ('note:1', ?1), (NULL, ?2)",
+                    [
+                        encrypt_with_key(&old_key, &[1; 12], "bound", b"note:1")?,
+                        encrypt_with_key(&old_key, &[2; 12], "unbound", b"")?,
+                    ],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+        let mut field = EncryptedField {
+            table: "notes".to_string(),
+            column: "body".to_string(),
+            id_column: "id".to_string(),
+            aad_column: None,
+        };
+        assert!(rotate_fields(
+            &state,
+            "old password",
+            "new password",
+            std::slice::from_ref(&field)
+        )
+        .is_err());
+
+        field.aad_column = Some("record".to_string());
+        assert_eq!(
+            rotate_fields(&state, "old password", "new password", &[field]).unwrap(),
+            2
+        );
+        let new_key = stored_key(&state, "new password");
+        let bodies: Vec<String> = state
+            .with_conn(|conn| {
+                Ok(conn
+                    .prepare("SELECT body FROM notes ORDER BY id")?
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?)
+            })
+            .unwrap();
+        assert_eq!(
+            decrypt_with_key(&new_key, &bodies[0], b"note:1").unwrap(),
+            "bound"
+        );
+        assert!(decrypt_with_key(&new_key, &bodies[0], b"").is_err());
+        assert_eq!(
+            decrypt_with_key(&new_key, &bodies[1], b"").unwrap(),
+            "unbound"
+        );
+    }
+
+    #[test]
+    fn rotate_fields_rolls_back_on_bad_ciphertext() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        let old_key = derive_key("old password");
+        *state.encryption_key.lock() = Some(old_key.clone());
+        let good = encrypt_with_key(&old_key, &[1; 12], "fine", b"").unwrap();
+        state
+            .with_conn(|conn| {
+                conn.execute_batch("CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT)")?;
+                conn.execute(
+                    "INSERT INTO notes (body) VALUES (?1), ('not ciphertext')",
+                    [&good],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+
+        let fields = [EncryptedField {
+            table: "notes".to_string(),
+            column: "body".to_string(),
+            id_column: "id".to_string(),
+            aad_column: None,
+        }];
+        assert!(rotate_fields(&state, "old password", "new password", &fields).is_err());
+
+        assert_eq!(*state.encryption_key.lock(), Some(old_key));
+        let body: String = state
+            .with_conn(|conn| {
+                Ok(conn.query_row("SELECT body FROM notes WHERE id = 1", [], |row| row.get(0))?)
+            })
+            .unwrap();
+        assert_eq!(body, good);
+    }
+
+    #[test]
+    fn maintenance_truncates_wal_and_optionally_vacuums() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let conn = open_connection(&path, None).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE blobs (data BLOB);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+             INSERT INTO blobs SELECT zeroblob(4096) FROM n;
+             DELETE FROM blobs;",
+        )
+        .unwrap();
+
+        let mut steps = Vec::new();
+        let report = run_maintenance(&conn, false, |p| steps.push(p.step)).unwrap();
+        assert_eq!(steps, ["analyze", "checkpoint"]);
+        assert_eq!(
+            std::fs::metadata(sibling_path(&path, "-wal"))
+                .unwrap()
+                .len(),
+            0
+        );
+        assert!(report.size_after > 200 * 4096);
+
+        steps.clear();
+        let report = run_maintenance(&conn, true, |p| steps.push(p.step)).unwrap();
+        assert_eq!(steps, ["analyze", "vacuum", "checkpoint"]);
+        assert!(report.size_after < report.size_before);
+
+        conn.execute_batch("BEGIN").unwrap();
+        assert!(matches!(
+            run_maintenance(&conn, true, |_| {}),
+            Err(SidecarError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn nonce_counter_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let state = AppState::new();
+        state.open_db(&path).unwrap();
+
+        let first = state.next_nonce().unwrap();
+        let second = state.next_nonce().unwrap();
+        assert_eq!(first[..8], 0u64.to_be_bytes());
+        assert_eq!(second[..8], 1u64.to_be_bytes());
+
+        // A fresh process must start past everything handed out before
+        let restarted = AppState::new();
+        restarted.open_db(&path).unwrap();
+        let next = restarted.next_nonce().unwrap();
+        assert_eq!(next[..8], NONCE_RESERVATION.to_be_bytes());
+        assert_eq!(restarted.nonce_counter.lock().next, NONCE_RESERVATION + 1);
+    }
+
+    #[test]
+    fn aad_binds_ciphertext_to_record() {
+        let key = derive_key("password");
+        let sealed = encrypt_with_key(&key, &[1; 12], "secret", b"record:42").unwrap();
+
+        assert_eq!(
+            decrypt_with_key(&key, &sealed, b"record:42").unwrap(),
+            "secret"
+        );
+        for aad in [&b"record:43"[..], b""] {
+            assert!(matches!(
+                decrypt_with_key(&key, &sealed, aad),
+                Err(SidecarError::EncryptionFailed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn field_round_trip_preserves_json_type() {
+        let key = derive_key("password");
+        for value in [
+            serde_json::json!("jane@example.com"),
+            serde_json::json!(42),
+            serde_json::json!(null),
+            serde_json::json!({ "street": "1 Main St", "unit": [4, "B"] }),
+        ] {
+            let sealed = encrypt_field_with_key(&key, &[1; 12], &value).unwrap();
+            assert_eq!(decrypt_field_with_key(&key, &sealed).unwrap(), value);
+        }
+
+        let nested = serde_json::json!({
+            "name": "Zoë Çelik",
+            "greeting": "こんにちは 👋",
+            "scores": [1, -2.5, 1e300, u64::MAX],
+            "contacts": [{ "city": "Москва", "tags": [] }, { "city": null }],
+        });
+        let sealed = encrypt_field_with_key(&key, &[3; 12], &nested).unwrap();
+        assert_eq!(decrypt_field_with_key(&key, &sealed).unwrap(), nested);
+
+        let text = encrypt_with_key(&key, &[2; 12], "not json", b"").unwrap();
+        assert!(matches!(
+            decrypt_field_with_key(&key, &text),
+            Err(SidecarError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn busy_writes_retry_until_the_lock_is_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let state = AppState::new();
+        *state.busy_timeout.lock() = Duration::from_millis(10);
+        state.open_db(&path).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("CREATE TABLE t (x INTEGER)")?))
+            .unwrap();
+
+        let holder = Connection::open(&path).unwrap();
+        holder.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(120));
+            holder.execute_batch("COMMIT").unwrap();
+        });
+
+        let insert = || {
+            state.with_conn(|conn| {
+                execute_statement(
+                    conn,
+                    "INSERT INTO t VALUES (1)",
+                    &SqlParams::Positional(vec![]),
+                )
+            })
+        };
+        assert!(matches!(insert(), Err(SidecarError::Busy(_))));
+        let started = Instant::now();
+        assert_eq!(retry_busy(insert).unwrap(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn busy_timeout_waits_out_a_held_write_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let state = AppState::new();
+        state.open_db(&path).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("CREATE TABLE t (x INTEGER)")?))
+            .unwrap();
+
+        let holder = Connection::open(&path).unwrap();
+        holder
+            .execute_batch("BEGIN IMMEDIATE; INSERT INTO t VALUES (1);")
+            .unwrap();
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            holder.execute_batch("COMMIT").unwrap();
+        });
+
+        // No retry here: SQLite itself waits, within the default timeout
+        let started = Instant::now();
+        let inserted = state.with_conn(|conn| {
+            execute_statement(
+                conn,
+                "INSERT INTO t VALUES (2)",
+                &SqlParams::Positional(vec![]),
+            )
+        });
+        assert_eq!(inserted.unwrap(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        release.join().unwrap();
+        let count: i64 = state
+            .with_conn(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM t", [], |row| row.get(0))?))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn hmac_detects_tampering() {
+        let key = derive_key("password");
+        let tag = BASE64.encode(hmac_tag(&key, b"cached response").unwrap());
+
+        assert!(hmac_matches(&key, b"cached response", &tag).unwrap());
+        assert!(!hmac_matches(&key, b"cached responsE", &tag).unwrap());
+        assert!(!hmac_matches(&derive_key("other"), b"cached response", &tag).unwrap());
+        assert!(!hmac_matches(&key, b"cached response", "not base64!").unwrap());
+    }
+
+    #[test]
+    fn verify_password_checks_the_stored_sentinel() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        assert!(matches!(
+            check_password(&state, "correct"),
+            Err(SidecarError::InvalidState(_))
+        ));
+
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        unlock_with_password(&state, "correct", &[]).unwrap();
+        assert!(check_password(&state, "correct").unwrap());
+        assert!(!check_password(&state, "wrong").unwrap());
+
+        // Re-initializing with another password is refused and must not
+        // replace the sentinel
+        assert!(matches!(
+            unlock_with_password(&state, "wrong", &[]),
+            Err(SidecarError::EncryptionFailed(_))
+        ));
+        assert!(!check_password(&state, "wrong").unwrap());
+
+        *state.encryption_key.lock() = Some(stored_key(&state, "correct"));
+        rotate_key(&state, "correct", "rotated", &[]).unwrap();
+        assert!(check_password(&state, "rotated").unwrap());
+        assert!(!check_password(&state, "correct").unwrap());
+    }
+
+    #[test]
+    fn committed_changes_reach_the_listener() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        state.changes.lock().listener = Some(Arc::new(move |batch: Vec<TableChange>| {
+            sink.lock().push(batch)
+        }));
+        state.changes.lock().subscribed.insert("notes".to_string());
+
+        state
+            .with_conn(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+                     CREATE TABLE other (id INTEGER PRIMARY KEY);
+                     INSERT INTO notes (body) VALUES ('a');
+                     INSERT INTO other DEFAULT VALUES;
+                     BEGIN;
+                     UPDATE notes SET body = 'b' WHERE id = 1;
+                     ROLLBACK;
+                     BEGIN;
+                     DELETE FROM notes WHERE id = 1;
+                     COMMIT;",
+                )?;
+                Ok(())
+            })
+            .unwrap();
+
+        let batches = received.lock().clone();
+        let change = |op, rowid| TableChange {
+            table: "notes".to_string(),
+            op,
+            rowid,
+        };
+        assert_eq!(
+            batches,
+            [
+                vec![change("insert", Some(1))],
+                vec![change("delete", Some(1))]
+            ]
+        );
+    }
+
+    #[test]
+    fn change_notifications_cover_every_table_until_disabled() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        set_row_change_listener(
+            &state,
+            Some(Arc::new(move |change: RowChange| sink.lock().push(change))),
+        );
+
+        let run = |sql: &str| {
+            state
+                .with_conn(|conn| Ok(conn.execute_batch(sql)?))
+                .unwrap()
+        };
+        run("CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+             CREATE TABLE other (id INTEGER PRIMARY KEY);
+             INSERT INTO notes (body) VALUES ('a');
+             BEGIN;
+             INSERT INTO other DEFAULT VALUES;
+             ROLLBACK;
+             BEGIN;
+             UPDATE notes SET body = 'b' WHERE id = 1;
+             INSERT INTO other DEFAULT VALUES;
+             COMMIT;");
+        set_row_change_listener(&state, None);
+        run("DELETE FROM notes");
+
+        let change = |operation, table: &str, rowid| RowChange {
+            operation,
+            table: table.to_string(),
+            rowid,
+        };
+        assert_eq!(
+            *received.lock(),
+            [
+                change("insert", "notes", 1),
+                change("update", "notes", 1),
+                change("insert", "other", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn bulk_changes_are_summarized() {
+        let batch = (0..1000)
+            .map(|rowid| TableChange {
+                table: "notes".to_string(),
+                op: "insert",
+                rowid: Some(rowid),
+            })
+            .collect();
+        assert_eq!(
+            summarize_changes(batch),
+            [TableChange {
+                table: "notes".to_string(),
+                op: "insert",
+                rowid: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn idle_key_is_locked_after_timeout() {
+        let state = AppState::new();
+        *state.encryption_key.lock() = Some(derive_key("password"));
+        let start = Instant::now();
+        *state.key_last_used.lock() = start;
+        assert!(!state.lock_if_idle(start + Duration::from_secs(3600)));
+
+        *state.auto_lock_after.lock() = Some(Duration::from_secs(60));
+        assert!(!state.lock_if_idle(start + Duration::from_secs(59)));
+        assert!(state.encryption_key.lock().is_some());
+
+        assert!(state.lock_if_idle(start + Duration::from_secs(60)));
+        assert!(state.encryption_key.lock().is_none());
+        assert!(!state.lock_if_idle(start + Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn file_round_trip_and_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = derive_key("password");
+        let plain = dir.path().join("attachment.bin");
+        let sealed = dir.path().join("attachment.enc");
+        let opened = dir.path().join("attachment.out");
+
+        let mut data = vec![0u8; 3 * 1024 * 1024 + 123];
+        rand::thread_rng().fill(&mut data[..]);
+        std::fs::write(&plain, &data).unwrap();
+
+        encrypt_file_with_key(&key, &[7; 12], &plain, &sealed).unwrap();
+        let encrypted = std::fs::read(&sealed).unwrap();
+        assert_eq!(&encrypted[..4], FILE_MAGIC);
+        assert!(!sibling_path(&sealed, ".partial").exists());
+
+        decrypt_file_with_key(&key, &sealed, &opened).unwrap();
+        assert_eq!(std::fs::read(&opened).unwrap(), data);
+        std::fs::remove_file(&opened).unwrap();
+
+        // Cutting the file at a chunk boundary must still be caught
+        let boundary = FILE_HEADER_LEN + 2 * (FILE_CHUNK_SIZE + FILE_TAG_LEN);
+        for len in [boundary, encrypted.len() - 1, FILE_HEADER_LEN] {
+            std::fs::write(&sealed, &encrypted[..len]).unwrap();
+            assert!(matches!(
+                decrypt_file_with_key(&key, &sealed, &opened),
+                Err(SidecarError::Encryption(_))
+            ));
+            assert!(!opened.exists());
+        }
+
+        std::fs::write(&sealed, b"plain text").unwrap();
+        assert!(decrypt_file_with_key(&key, &sealed, &opened).is_err());
+    }
+
+    #[test]
+    fn empty_file_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = derive_key("password");
+        let plain = dir.path().join("empty");
+        std::fs::write(&plain, b"").unwrap();
+
+        encrypt_file_with_key(&key, &[1; 12], &plain, &dir.path().join("empty.enc")).unwrap();
+        decrypt_file_with_key(
+            &key,
+            &dir.path().join("empty.enc"),
+            &dir.path().join("empty.out"),
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(dir.path().join("empty.out")).unwrap(), b"");
+    }
+
+    #[test]
+    fn named_connections_are_independent() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("main.db")).unwrap();
+        let cache_path = dir.path().join("cache.db");
+        state.open_named("cache", &cache_path, None).unwrap();
+
+        let run = |name: Option<&str>, sql: &str| {
+            state.with_named_conn(name, |conn| Ok(conn.execute_batch(sql)?))
+        };
+        run(
+            None,
+            "CREATE TABLE notes (body TEXT); INSERT INTO notes VALUES ('kept');",
+        )
+        .unwrap();
+        run(
+            Some("cache"),
+            "CREATE TABLE embeddings (v BLOB); INSERT INTO embeddings VALUES (x'00');",
+        )
+        .unwrap();
+        assert!(run(Some("main"), "SELECT * FROM embeddings").is_err());
+
+        attach_to(&state, MAIN_CONNECTION, &cache_path, "cache").unwrap();
+        let rows = state
+            .with_named_reader(None, |conn| {
+                query_rows(
+                    conn,
+                    "SELECT (SELECT COUNT(*) FROM notes) AS notes,
+                            (SELECT COUNT(*) FROM cache.embeddings) AS embeddings",
+                    &SqlParams::Positional(vec![]),
+                )
+            })
+            .unwrap();
+        assert_eq!(rows, [serde_json::json!({ "notes": 1, "embeddings": 1 })]);
+
+        assert!(state.close_named(Some("cache")).unwrap());
+        let err = run(Some("cache"), "SELECT 1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid state: Database 'cache' not initialized"
+        );
+        run(None, "SELECT * FROM notes").unwrap();
+    }
+
+    #[test]
+    fn key_bytes_are_zeroed_on_drop() {
+        let mut slot = std::mem::MaybeUninit::new(derive_key("password"));
+        let key = slot.as_mut_ptr();
+        let bytes = unsafe { std::ptr::addr_of!((*key).0) };
+        assert_ne!(unsafe { bytes.read() }, [0; 32]);
+
+        // Drop in place so the memory the key lived in can still be read
+        unsafe { std::ptr::drop_in_place(key) };
+        assert_eq!(unsafe { bytes.read() }, [0; 32]);
+
+        let mut key = derive_key("password");
+        key.zeroize();
+        assert_eq!(key.0, [0; 32]);
+    }
+
+    #[test]
+    fn in_memory_database_needs_confirmation_to_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        assert!(state.readers.lock().is_none());
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch("CREATE TABLE guest (x); INSERT INTO guest VALUES (1);")?)
+            })
+            .unwrap();
+
+        assert!(matches!(
+            close_database(&state, None, false),
+            Err(SidecarError::InvalidState(_))
+        ));
+        assert!(state.db.lock().is_some());
+
+        // The session can be persisted before it goes away
+        let saved = dir.path().join("guest.db");
+        backup_database(&state, &saved, false, BackupPacing::default(), |_| {}).unwrap();
+        assert!(close_database(&state, None, true).unwrap());
+        let count: i64 = Connection::open(&saved)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM guest", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // Nothing open: closing is still a no-op
+        assert!(!close_database(&state, None, false).unwrap());
+    }
+
+    #[test]
+    fn errors_serialize_with_stable_codes() {
+        let sqlite = || rusqlite::Error::QueryReturnedNoRows;
+        let cases = [
+            (SidecarError::Database(sqlite()), "database"),
+            (SidecarError::Encryption("e".into()), "encryption"),
+            (SidecarError::Unauthorized("e".into()), "unauthorized"),
+            (
+                SidecarError::EncryptionFailed("e".into()),
+                "encryption_failed",
+            ),
+            (SidecarError::Keyring("e".into()), "keyring"),
+            (SidecarError::InvalidState("e".into()), "invalid_state"),
+            (SidecarError::NotFound("e".into()), "not_found"),
+            (
+                serde_json::from_str::<()>("{").unwrap_err().into(),
+                "serialization",
+            ),
+            (std::io::Error::other("e").into(), "io"),
+            (SidecarError::Timeout("e".into()), "timeout"),
+            (
+                r2d2::Pool::builder()
+                    .connection_timeout(Duration::from_millis(1))
+                    .build(SqliteConnectionManager::file("/nonexistent/dir/db"))
+                    .unwrap_err()
+                    .into(),
+                "pool",
+            ),
+            (SidecarError::Busy("e".into()), "busy"),
+            (SidecarError::Cancelled("e".into()), "cancelled"),
+            (
+                SidecarError::MultipleStatements("e".into()),
+                "multiple_statements",
+            ),
+        ];
+        for (err, code) in cases {
+            let json = serde_json::to_value(&err).unwrap();
+            assert_eq!(
+                json,
+                serde_json::json!({ "code": code, "message": err.to_string() })
+            );
+        }
+    }
+
+    /// Process-wide in-memory keychain, since the mock store in `keyring`
+    /// doesn't persist across entries
+    fn use_memory_keychain() {
+        use keyring::credential::{Credential, CredentialApi, CredentialBuilderApi};
+        use std::any::Any;
+
+        type Store = Arc<Mutex<HashMap<String, String>>>;
+
+        #[derive(Debug)]
+        struct MemoryCredential {
+            store: Store,
+            name: String,
+        }
+
+        impl CredentialApi for MemoryCredential {
+            fn set_password(&self, password: &str) -> keyring::Result<()> {
+                self.store
+                    .lock()
+                    .insert(self.name.clone(), password.to_string());
+                Ok(())
+            }
+
+            fn get_password(&self) -> keyring::Result<String> {
+                self.store
+                    .lock()
+                    .get(&self.name)
+                    .cloned()
+                    .ok_or(keyring::Error::NoEntry)
+            }
+
+            fn delete_password(&self) -> keyring::Result<()> {
+                self.store
+                    .lock()
+                    .remove(&self.name)
+                    .map(drop)
+                    .ok_or(keyring::Error::NoEntry)
+            }
+
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        #[derive(Debug, Default)]
+        struct MemoryBuilder(Store);
+
+        impl CredentialBuilderApi for MemoryBuilder {
+            fn build(
+                &self,
+                _target: Option<&str>,
+                service: &str,
+                user: &str,
+            ) -> keyring::Result<Box<Credential>> {
+                Ok(Box::new(MemoryCredential {
+                    store: self.0.clone(),
+                    name: format!("{service}/{user}"),
+                }))
+            }
+
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        static INSTALLED: std::sync::Once = std::sync::Once::new();
+        INSTALLED.call_once(|| {
+            keyring::set_default_credential_builder(Box::new(MemoryBuilder::default()))
+        });
+    }
+
+    #[test]
+    fn credentials_are_kept_per_account() {
+        use_memory_keychain();
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+
+        store_account_credentials(&state, "test-gmail", Some("alice@x.com"), "alice-token")
+            .unwrap();
+        store_account_credentials(&state, "test-gmail", Some("bob@x.com"), "bob-token").unwrap();
+        store_account_credentials(&state, "test-gmail", None, "default-token").unwrap();
+
+        assert_eq!(
+            get_account_credentials(&state, "test-gmail", Some("alice@x.com"))
+                .unwrap()
+                .as_deref(),
+            Some("alice-token")
+        );
+        assert_eq!(
+            get_account_credentials(&state, "test-gmail", Some("bob@x.com"))
+                .unwrap()
+                .as_deref(),
+            Some("bob-token")
+        );
+        assert_eq!(
+            get_account_credentials(&state, "test-gmail", None)
+                .unwrap()
+                .as_deref(),
+            Some("default-token")
+        );
+        assert_eq!(
+            state
+                .with_conn(|conn| accounts_for(conn, "test-gmail"))
+                .unwrap(),
+            ["alice@x.com", "bob@x.com"]
+        );
+
+        delete_account_credentials(&state, "test-gmail", Some("alice@x.com")).unwrap();
+        assert_eq!(
+            get_account_credentials(&state, "test-gmail", Some("alice@x.com")).unwrap(),
+            None
+        );
+        assert_eq!(
+            state
+                .with_conn(|conn| accounts_for(conn, "test-gmail"))
+                .unwrap(),
+            ["bob@x.com"]
+        );
+    }
+
+    #[test]
+    fn export_writes_csv_and_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        state
+            .with_conn(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE notes (id INTEGER, body TEXT, score REAL);
+                     INSERT INTO notes VALUES (1, 'plain', 0.5);
+                     INSERT INTO notes VALUES (2, 'say \"hi\", then
+leave', NULL);",
+                )?;
+                Ok(())
+            })
+            .unwrap();
+        let sql = "SELECT id, body, score FROM notes ORDER BY id";
+
+        let csv_path = dir.path().join("notes.csv");
+        let summary = state
+            .with_conn(|conn| {
+                export_query(
+                    conn,
+                    sql,
+                    &SqlParams::Positional(vec![]),
+                    &csv_path,
+                    ExportFormat::Csv,
+                    |_| {},
+                )
+            })
+            .unwrap();
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        assert_eq!(
+            csv,
+            "id,body,score\n1,plain,0.5\n2,\"say \"\"hi\"\", then\nleave\",\n"
+        );
+        assert_eq!(summary.rows_written, 2);
+        assert_eq!(summary.size_bytes, csv.len() as u64);
+
+        let jsonl_path = dir.path().join("notes.jsonl");
+        state
+            .with_conn(|conn| {
+                export_query(
+                    conn,
+                    sql,
+                    &SqlParams::Positional(vec![]),
+                    &jsonl_path,
+                    ExportFormat::Jsonl,
+                    |_| {},
+                )
+            })
+            .unwrap();
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&jsonl_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                serde_json::json!({ "id": 1, "body": "plain", "score": 0.5 }),
+                serde_json::json!({ "id": 2, "body": "say \"hi\", then\nleave", "score": null }),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_export_leaves_no_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+        let conn = Connection::open_in_memory().unwrap();
+
+        // abs() overflows on the second row, after the first has been written
+        let result = export_query(
+            &conn,
+            "SELECT abs(value) FROM (SELECT 1 AS value UNION ALL SELECT -9223372036854775808)",
+            &SqlParams::Positional(vec![]),
+            &path,
+            ExportFormat::Csv,
+            |_| {},
+        );
+        assert!(result.is_err());
+        assert!(!path.exists());
+        assert!(!sibling_path(&path, ".partial").exists());
+    }
+
+    #[test]
+    fn oauth_tokens_round_trip_separately_from_credentials() {
+        use_memory_keychain();
+        let state = AppState::new();
+        let token = OAuthToken {
+            access_token: "access".into(),
+            refresh_token: Some("refresh".into()),
+            expires_at: Some(1_700_000_000),
+            scopes: vec!["mail.read".into(), "openid".into()],
+            token_type: "Bearer".into(),
+        };
+
+        store_account_credentials(&state, "test-oauth", None, "raw").unwrap();
+        assert_eq!(load_token(&state, "test-oauth").unwrap(), None);
+
+        store_token(&state, "test-oauth", &token).unwrap();
+        assert_eq!(load_token(&state, "test-oauth").unwrap(), Some(token));
+        assert_eq!(
+            get_account_credentials(&state, "test-oauth", None)
+                .unwrap()
+                .as_deref(),
+            Some("raw")
+        );
+    }
+
+    #[test]
+    fn repeated_queries_use_the_statement_cache() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.set_prepared_statement_cache_capacity(DEFAULT_STATEMENT_CACHE_SIZE);
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, label TEXT)")
+            .unwrap();
+        let insert = "INSERT INTO t (label) VALUES (?1)";
+        let select = "SELECT label FROM t WHERE id = ?1";
+
+        // Only the first iteration compiles each statement; the rest reuse
+        // it. For a simple query that is typically several times faster than
+        // preparing every call.
+        for i in 1..=1000i64 {
+            let params = SqlParams::Positional(vec![format!("row {i}").into()]);
+            assert_eq!(execute_statement(&conn, insert, &params).unwrap(), 1);
+            let rows = query_rows(&conn, select, &SqlParams::Positional(vec![i.into()])).unwrap();
+            assert_eq!(rows, [serde_json::json!({ "label": format!("row {i}") })]);
+        }
+
+        // A schema change flushes the cache, and cached SELECTs see it
+        let everything = "SELECT * FROM t WHERE id = 1";
+        let rows = query_rows(&conn, everything, &SqlParams::Positional(vec![])).unwrap();
+        assert_eq!(rows, [serde_json::json!({ "id": 1, "label": "row 1" })]);
+        execute_statement(
+            &conn,
+            "ALTER TABLE t ADD COLUMN score INTEGER DEFAULT 7",
+            &SqlParams::Positional(vec![]),
+        )
+        .unwrap();
+        let rows = query_rows(&conn, everything, &SqlParams::Positional(vec![])).unwrap();
+        assert_eq!(
+            rows,
+            [serde_json::json!({ "id": 1, "label": "row 1", "score": 7 })]
+        );
+    }
+
+    #[test]
+    fn csv_records_handle_quotes_bom_and_crlf() {
+        let records: Vec<_> =
+            CsvRecords::new("a,b\r\n\"x, \"\"y\"\"\",\r\n\r\n\"multi\nline\",\"\"")
+                .collect::<Result<_, _>>()
+                .unwrap();
+        assert_eq!(
+            records,
+            [
+                vec![Some("a".into()), Some("b".into())],
+                vec![Some("x, \"y\"".into()), None],
+                vec![Some("multi\nline".into()), Some(String::new())],
+            ]
+        );
+        assert!(CsvRecords::new("\"open").next().unwrap().is_err());
+    }
+
+    #[test]
+    fn import_reads_csv_and_json_arrays() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE contacts (id INTEGER PRIMARY KEY, name TEXT NOT NULL, age INTEGER)",
+        )
+        .unwrap();
+
+        let csv = dir.path().join("contacts.csv");
+        std::fs::write(
+            &csv,
+            "\u{feff}Name,age,nickname\r\nAda,36,countess\r\n\"Turing, Alan\",41,\r\n",
+        )
+        .unwrap();
+        let strict = ImportOptions::default();
+        assert!(matches!(
+            import_file(&conn, &csv, "contacts", ImportFormat::Csv, &strict),
+            Err(SidecarError::NotFound(_))
+        ));
+        let lenient = ImportOptions {
+            ignore_unknown: true,
+            best_effort: false,
+        };
+        let summary = import_file(&conn, &csv, "contacts", ImportFormat::Csv, &lenient).unwrap();
+        assert_eq!((summary.inserted, summary.skipped), (2, 0));
+
+        let json = dir.path().join("contacts.json");
+        std::fs::write(
+            &json,
+            r#"[{"name": "Grace", "age": "85"}, {"age": 1}, {"name": "Edsger"}]"#,
+        )
+        .unwrap();
+        // The bad second row rolls back the unfinished batch with it
+        assert!(import_file(&conn, &json, "contacts", ImportFormat::Json, &strict).is_err());
+        let best_effort = ImportOptions {
+            ignore_unknown: false,
+            best_effort: true,
+        };
+        let summary =
+            import_file(&conn, &json, "contacts", ImportFormat::Json, &best_effort).unwrap();
+        assert_eq!((summary.inserted, summary.skipped), (2, 1));
+        assert_eq!(summary.errors.len(), 1);
+        assert_eq!(summary.errors[0].row, 2);
+
+        let rows = query_rows(
+            &conn,
+            "SELECT name, age, typeof(age) AS kind FROM contacts ORDER BY id",
+            &SqlParams::Positional(vec![]),
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            [
+                serde_json::json!({ "name": "Ada", "age": 36, "kind": "integer" }),
+                serde_json::json!({ "name": "Turing, Alan", "age": 41, "kind": "integer" }),
+                serde_json::json!({ "name": "Grace", "age": 85, "kind": "integer" }),
+                serde_json::json!({ "name": "Edsger", "age": null, "kind": "null" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn execute_script_runs_every_statement_or_none() {
+        let conn = Connection::open_in_memory().unwrap();
+        let ran = execute_script(
+            &conn,
+            "-- people first, notes refer to them
+             CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT);
+             CREATE TABLE notes (id INTEGER PRIMARY KEY, person_id INTEGER, body TEXT);;
+             INSERT INTO notes (body) VALUES ('a; b');
+             PRAGMA table_info(notes);
+             CREATE INDEX notes_by_person ON notes (person_id);",
+        )
+        .unwrap();
+        assert_eq!(ran, 5);
+        let names = |conn: &Connection| -> Vec<String> {
+            conn.prepare("SELECT name FROM sqlite_master ORDER BY name")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        assert_eq!(names(&conn), ["notes", "notes_by_person", "people"]);
+
+        assert!(execute_script(
+            &conn,
+            "CREATE TABLE tags (name TEXT); CREATE TABLE people (x);"
+        )
+        .is_err());
+        assert_eq!(names(&conn), ["notes", "notes_by_person", "people"]);
+    }
+
+    #[test]
+    fn statement_cache_counts_hits_and_is_flushed_by_migrations() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (a INTEGER); INSERT INTO t VALUES (1);")
+            .unwrap();
+        let no_params = SqlParams::Positional(vec![]);
+
+        // Other tests share the counters, so only look for increases
+        let misses = STATEMENT_CACHE_MISSES.load(Ordering::Relaxed);
+        query_rows(&conn, "SELECT a FROM t", &no_params).unwrap();
+        assert!(STATEMENT_CACHE_MISSES.load(Ordering::Relaxed) > misses);
+        let hits = STATEMENT_CACHE_HITS.load(Ordering::Relaxed);
+        query_rows(&conn, "SELECT a FROM t", &no_params).unwrap();
+        assert!(STATEMENT_CACHE_HITS.load(Ordering::Relaxed) > hits);
+
+        let rename = Migration {
+            version: 1,
+            up_sql: "ALTER TABLE t RENAME COLUMN a TO b".to_string(),
+        };
+        run_migrations(&conn, &[rename]).unwrap();
+        assert!(query_rows(&conn, "SELECT a FROM t", &no_params).is_err());
+        assert_eq!(
+            query_rows(&conn, "SELECT b FROM t", &no_params).unwrap(),
+            [serde_json::json!({ "b": 1 })]
+        );
+    }
+
+    #[test]
+    fn stored_credentials_are_listed_by_provider() {
+        use_memory_keychain();
+        let state = AppState::new();
+        store_account_credentials(&state, "test-list-early", None, "before db").unwrap();
+        state.open_db(Path::new(":memory:")).unwrap();
+
+        store_account_credentials(&state, "test-list-zoom", None, "z").unwrap();
+        store_account_credentials(&state, "test-list-gmail", Some("a@x.com"), "a").unwrap();
+        store_account_credentials(&state, "test-list-gmail", Some("b@x.com"), "b").unwrap();
+        let providers = || state.with_conn(credential_providers).unwrap();
+        assert_eq!(providers(), ["test-list-gmail", "test-list-zoom"]);
+
+        delete_account_credentials(&state, "test-list-zoom", None).unwrap();
+        delete_account_credentials(&state, "test-list-gmail", Some("a@x.com")).unwrap();
+        assert_eq!(providers(), ["test-list-gmail"]);
+        assert_eq!(
+            state
+                .with_conn(|conn| accounts_for(conn, "test-list-gmail"))
+                .unwrap(),
+            ["b@x.com"]
+        );
+    }
+
+    #[test]
+    fn serial_worker_runs_jobs_in_order_off_the_caller() {
+        let worker = SerialWorker::spawn("test-worker").unwrap();
+        let caller = std::thread::current().id();
+        let (done, finished) = std::sync::mpsc::channel();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        worker.submit(|| panic!("a failing job"));
+        for i in 0..100 {
+            let order = order.clone();
+            let done = done.clone();
+            worker.submit(move || {
+                assert_ne!(std::thread::current().id(), caller);
+                // Early jobs are slowest; they must still finish first
+                if i < 3 {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                order.lock().push(i);
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..100 {
+            finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(*order.lock(), (0..100).collect::<Vec<_>>());
+
+        assert!(runs_on_db_worker("db_execute"));
+        assert!(!runs_on_db_worker("db_query"));
+        assert!(!runs_on_db_worker("db_backup"));
+        assert!(!runs_on_db_worker("db_query_stream"));
+        assert!(!runs_on_db_worker("db_cancel_stream"));
+        assert!(!runs_on_db_worker("db_cancel"));
+        assert!(runs_on_db_worker("kv_set"));
+        assert!(runs_on_db_worker("init_encryption"));
+        assert!(runs_on_db_worker("list_credentials"));
+        assert!(!runs_on_db_worker("decrypt_data"));
+        assert!(!runs_on_db_worker("encrypt_file"));
+    }
+
+    fn ipc_request(cmd: &str, args: serde_json::Value) -> tauri::webview::InvokeRequest {
+        tauri::webview::InvokeRequest {
+            cmd: cmd.to_string(),
+            callback: tauri::ipc::CallbackFn(0),
+            error: tauri::ipc::CallbackFn(1),
+            url: "tauri://localhost".parse().unwrap(),
+            body: tauri::ipc::InvokeBody::Json(args),
+            headers: Default::default(),
+            invoke_key: tauri::test::INVOKE_KEY.to_string(),
+        }
+    }
+
+    /// Invoke `cmd` the way the frontend would, waiting for the reply
+    fn ipc_call(
+        webview: &tauri::WebviewWindow<tauri::test::MockRuntime>,
+        cmd: &str,
+        args: serde_json::Value,
+    ) -> Result<serde_json::Value, serde_json::Value> {
+        tauri::test::get_ipc_response(webview, ipc_request(cmd, args))
+            .map(|body| body.deserialize().unwrap())
+    }
+
+    #[test]
+    fn commands_sent_back_to_back_apply_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("rows.csv");
+        let rows: String = (0..20_000).map(|i| format!("{i}\n")).collect();
+        std::fs::write(&csv, format!("x\n{rows}")).unwrap();
+
+        let state = Arc::new(AppState::new());
+        let app = tauri::test::mock_builder()
+            .manage(state.clone())
+            .invoke_handler(route_commands(tauri::generate_handler![
+                db_init,
+                db_execute,
+                db_import,
+                db_query_one
+            ]))
+            .build(tauri::test::mock_context(tauri::test::noop_assets()))
+            .unwrap();
+        let webview = tauri::WebviewWindowBuilder::new(&app, "main", Default::default())
+            .build()
+            .unwrap();
+        let call = |cmd: &str, args: serde_json::Value| ipc_call(&webview, cmd, args).unwrap();
+        call("db_init", serde_json::json!({ "path": ":memory:" }));
+        // Record which thread each insert runs on
+        state
+            .with_conn(|conn| {
+                conn.create_scalar_function(
+                    "thread_name",
+                    0,
+                    rusqlite::functions::FunctionFlags::default(),
+                    |_| Ok(std::thread::current().name().map(str::to_string)),
+                )?;
+                Ok(conn.execute_batch(
+                    "CREATE TABLE t (x TEXT);
+                     CREATE TABLE threads (name TEXT);
+                     CREATE TRIGGER t_thread AFTER INSERT ON t
+                     BEGIN INSERT INTO threads VALUES (thread_name()); END;",
+                )?)
+            })
+            .unwrap();
+
+        // A slow import, then an insert sent before the import has replied
+        let (done, finished) = std::sync::mpsc::channel();
+        let requests = [
+            (
+                "db_import",
+                serde_json::json!({ "path": csv, "table": "t", "format": "csv" }),
+            ),
+            (
+                "db_execute",
+                serde_json::json!({ "sql": "INSERT INTO t VALUES ('last')" }),
+            ),
+        ];
+        for (cmd, args) in requests {
+            let done = done.clone();
+            webview.clone().on_message(
+                ipc_request(cmd, args),
+                Box::new(move |_, _, response, _, _| {
+                    done.send(matches!(response, tauri::ipc::InvokeResponse::Ok(_)))
+                        .unwrap();
+                }),
+            );
+        }
+        for _ in 0..2 {
+            assert!(finished.recv_timeout(Duration::from_secs(10)).unwrap());
+        }
+
+        let newest = call(
+            "db_query_one",
+            serde_json::json!({ "sql": "SELECT x, (SELECT COUNT(*) FROM t) AS n FROM t ORDER BY rowid DESC LIMIT 1" }),
+        );
+        assert_eq!(newest, serde_json::json!({ "x": "last", "n": 20_001 }));
+        let threads = call(
+            "db_query_one",
+            serde_json::json!({ "sql": "SELECT group_concat(DISTINCT name) AS names FROM threads" }),
+        );
+        assert_eq!(threads, serde_json::json!({ "names": "sidecar-db" }));
+    }
+
+    #[test]
+    fn stats_answer_under_both_command_names() {
+        let app = tauri::test::mock_builder()
+            .manage(Arc::new(AppState::new()))
+            .invoke_handler(tauri::generate_handler![db_init, db_get_stats, db_stats])
+            .build(tauri::test::mock_context(tauri::test::noop_assets()))
+            .unwrap();
+        let webview = tauri::WebviewWindowBuilder::new(&app, "main", Default::default())
+            .build()
+            .unwrap();
+        ipc_call(
+            &webview,
+            "db_init",
+            serde_json::json!({ "path": ":memory:" }),
+        )
+        .unwrap();
+
+        for command in ["db_get_stats", "db_stats"] {
+            let stats = ipc_call(&webview, command, serde_json::json!({})).unwrap();
+            assert!(stats["pageSize"].as_u64().unwrap() > 0, "{command}");
+            assert!(stats.get("cacheHitRatio").is_some(), "{command}");
+        }
+    }
+
+    #[test]
+    fn query_one_command_is_strict_unless_told_otherwise() {
+        let app = tauri::test::mock_builder()
+            .manage(Arc::new(AppState::new()))
+            .invoke_handler(tauri::generate_handler![
+                db_init,
+                db_execute_batch,
+                db_query_one
+            ])
+            .build(tauri::test::mock_context(tauri::test::noop_assets()))
+            .unwrap();
+        let webview = tauri::WebviewWindowBuilder::new(&app, "main", Default::default())
+            .build()
+            .unwrap();
+        let query_one = |sql: &str, strict: Option<bool>| {
+            let mut args = serde_json::json!({ "sql": sql, "params": ["ada"] });
+            if let Some(strict) = strict {
+                args["strict"] = strict.into();
+            }
+            ipc_call(&webview, "db_query_one", args)
+        };
+        ipc_call(
+            &webview,
+            "db_init",
+            serde_json::json!({ "path": ":memory:" }),
+        )
+        .unwrap();
+        ipc_call(
+            &webview,
+            "db_execute_batch",
+            serde_json::json!({ "script": "CREATE TABLE people (name TEXT, team TEXT);
+                 INSERT INTO people VALUES ('ada', 'core'), ('ada', 'web'), ('alan', 'core');" }),
+        )
+        .unwrap();
+
+        let by_team = "SELECT team FROM people WHERE name = ? AND team = 'core'";
+        assert_eq!(
+            query_one(by_team, None),
+            Ok(serde_json::json!({ "team": "core" }))
+        );
+        assert_eq!(
+            query_one(
+                "SELECT team FROM people WHERE name = ? AND team = 'ops'",
+                None
+            ),
+            Ok(serde_json::Value::Null)
+        );
+
+        let by_name = "SELECT team FROM people WHERE name = ? ORDER BY team";
+        let err = query_one(by_name, None).unwrap_err();
+        assert_eq!(err["code"], "invalid_state");
+        assert_eq!(
+            query_one(by_name, Some(false)),
+            Ok(serde_json::json!({ "team": "core" }))
+        );
+    }
+
+    #[test]
+    fn cancel_interrupts_a_running_query() {
+        let state = Arc::new(AppState::new());
+        state.open_db(Path::new(":memory:")).unwrap();
+        assert!(!state.cancel_queries());
+
+        // An interrupt that lands before the statement starts is a no-op,
+        // so keep cancelling until the query gives up
+        let canceller = state.clone();
+        let finished = Arc::new(AtomicBool::new(false));
+        let done = finished.clone();
+        let cancel = std::thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                canceller.cancel_queries();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        });
+        let slow = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c)
+                    SELECT count(*) FROM (SELECT x FROM c LIMIT 10000000000)";
+        let err = state
+            .with_conn(|conn| query_rows(conn, slow, &SqlParams::Positional(vec![])))
+            .unwrap_err();
+        finished.store(true, Ordering::Relaxed);
+        cancel.join().unwrap();
+        assert!(matches!(err, SidecarError::Cancelled(_)), "{err}");
+
+        // Nothing is left interrupted for the next query
+        assert!(!state.cancel_queries());
+        let rows = state
+            .with_conn(|conn| query_rows(conn, "SELECT 1 AS one", &SqlParams::Positional(vec![])))
+            .unwrap();
+        assert_eq!(rows, [serde_json::json!({ "one": 1 })]);
+    }
+
+    #[test]
+    fn namespaces_keep_workspace_credentials_apart() {
+        use_memory_keychain();
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        let work = scoped_provider("test-ns-slack", Some("work")).unwrap();
+        let home = scoped_provider("test-ns-slack", Some("home")).unwrap();
+        assert_eq!(work, "work/test-ns-slack");
+        assert_eq!(
+            scoped_provider("test-ns-slack", None).unwrap(),
+            "test-ns-slack"
+        );
+        assert!(scoped_provider("test-ns-slack", Some("a/b")).is_err());
+        assert!(scoped_provider("test-ns-slack", Some("")).is_err());
+
+        store_account_credentials(&state, &work, None, "work-token").unwrap();
+        store_account_credentials(&state, &home, None, "home-token").unwrap();
+        store_account_credentials(&state, "test-ns-slack", None, "plain-token").unwrap();
+        assert_eq!(
+            get_account_credentials(&state, &work, None)
+                .unwrap()
+                .as_deref(),
+            Some("work-token")
+        );
+        assert_eq!(
+            get_account_credentials(&state, &home, None)
+                .unwrap()
+                .as_deref(),
+            Some("home-token")
+        );
+        assert_eq!(
+            get_account_credentials(&state, "test-ns-slack", None)
+                .unwrap()
+                .as_deref(),
+            Some("plain-token")
+        );
+
+        let providers = state.with_conn(credential_providers).unwrap();
+        assert_eq!(
+            providers_in(providers.clone(), Some("work")),
+            ["test-ns-slack"]
+        );
+        assert_eq!(providers_in(providers, None), ["test-ns-slack"]);
+    }
+
+    #[test]
+    fn account_credentials_need_a_database_before_the_keychain_write() {
+        use_memory_keychain();
+        let state = AppState::new();
+        assert!(matches!(
+            store_account_credentials(&state, "test-no-db", Some("bot"), "secret"),
+            Err(SidecarError::InvalidState(_))
+        ));
+        assert_eq!(
+            get_account_credentials(&state, "test-no-db", Some("bot")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn interrupted_rotation_is_completed_by_cleanup() {
+        use_memory_keychain();
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        store_account_credentials(&state, "test-rotate", None, "old").unwrap();
+        store_account_credentials(&state, "test-rotate", Some("bot"), "bot-old").unwrap();
+
+        rotate_account_credentials(&state, "test-rotate", None, "new").unwrap();
+        assert_eq!(
+            get_account_credentials(&state, "test-rotate", None)
+                .unwrap()
+                .as_deref(),
+            Some("new")
+        );
+        assert_eq!(
+            get_account_credentials(&state, &pending_provider("test-rotate"), None).unwrap(),
+            None
+        );
+
+        // Stop after the pending write, as a crash would
+        credential_entry(&state, &pending_provider("test-rotate"), Some("bot"))
+            .unwrap()
+            .set_password("bot-new")
+            .unwrap();
+        assert_eq!(complete_pending_rotations(&state).unwrap(), 1);
+        assert_eq!(
+            get_account_credentials(&state, "test-rotate", Some("bot"))
+                .unwrap()
+                .as_deref(),
+            Some("bot-new")
+        );
+        assert_eq!(complete_pending_rotations(&state).unwrap(), 0);
+    }
+
+    #[test]
+    fn rotations_without_a_database_are_completed_on_open() {
+        use_memory_keychain();
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState::new());
+        store_account_credentials(&state, "test-unindexed", None, "old").unwrap();
+
+        // Crash after the pending write of a rotation made with no database
+        let rotation = PendingRotation {
+            provider: "test-unindexed".to_string(),
+            account: None,
+        };
+        let mut rotations = pending_rotations(&state).unwrap();
+        rotations.push(rotation.clone());
+        store_pending_rotations(&state, &rotations).unwrap();
+        credential_entry(&state, &pending_provider("test-unindexed"), None)
+            .unwrap()
+            .set_password("new")
+            .unwrap();
+
+        let app = tauri::test::mock_builder()
+            .manage(state.clone())
+            .invoke_handler(tauri::generate_handler![db_init])
+            .build(tauri::test::mock_context(tauri::test::noop_assets()))
+            .unwrap();
+        let webview = tauri::WebviewWindowBuilder::new(&app, "main", Default::default())
+            .build()
+            .unwrap();
+        ipc_call(
+            &webview,
+            "db_init",
+            serde_json::json!({ "path": dir.path().join("app.db") }),
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_account_credentials(&state, "test-unindexed", None)
+                .unwrap()
+                .as_deref(),
+            Some("new")
+        );
+        assert_eq!(
+            get_account_credentials(&state, &pending_provider("test-unindexed"), None).unwrap(),
+            None
+        );
+        assert!(!pending_rotations(&state).unwrap().contains(&rotation));
+    }
+
+    #[test]
+    fn schema_describes_tables_indexes_and_foreign_keys() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE people (id INTEGER PRIMARY KEY AUTOINCREMENT, email TEXT NOT NULL UNIQUE);
+             CREATE TABLE notes (
+                 id INTEGER PRIMARY KEY,
+                 person_id INTEGER REFERENCES people (id) ON DELETE CASCADE,
+                 body TEXT DEFAULT 'empty'
+             );
+             CREATE INDEX notes_by_person ON notes (person_id) WHERE person_id IS NOT NULL;
+             CREATE VIRTUAL TABLE notes_fts USING fts5(body);
+             CREATE TABLE sidecar_state (key TEXT PRIMARY KEY, value NOT NULL);",
+        )
+        .unwrap();
+
+        let tables = read_schema(&conn, false).unwrap();
+        let names: Vec<&str> = tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["notes", "notes_fts", "people"]);
+
+        let notes = &tables[0];
+        assert!(!notes.is_virtual);
+        let body = &notes.columns[2];
+        assert_eq!(
+            (
+                body.name.as_str(),
+                body.declared_type.as_str(),
+                body.default_value.as_deref()
+            ),
+            ("body", "TEXT", Some("'empty'"))
+        );
+        assert_eq!(notes.columns[0].primary_key, 1);
+        assert_eq!(notes.indexes.len(), 1);
+        assert!(notes.indexes[0].partial);
+        assert_eq!(notes.indexes[0].columns, ["person_id"]);
+        assert_eq!(notes.foreign_keys.len(), 1);
+        let key = &notes.foreign_keys[0];
+        assert_eq!(key.parent_table, "people");
+        assert_eq!(key.columns, ["person_id"]);
+        assert_eq!(key.parent_columns, [Some("id".to_string())]);
+        assert_eq!(key.on_delete, "CASCADE");
+
+        assert!(tables[1].is_virtual);
+        assert_eq!(tables[1].columns[0].name, "body");
+        let people = &tables[2];
+        assert!(people.columns[1].not_null);
+        assert!(people.indexes[0].unique);
+        assert_eq!(people.indexes[0].origin, "u");
+
+        let all = read_schema(&conn, true).unwrap();
+        let names: Vec<&str> = all.iter().map(|t| t.name.as_str()).collect();
+        assert!(names.contains(&"sqlite_sequence"));
+        assert!(names.contains(&"notes_fts_data"));
+        assert!(names.contains(&"sidecar_state"));
+    }
+
+    #[test]
+    fn integer_params_keep_full_precision() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE v (n);").unwrap();
+        for n in [i64::MAX, i64::MIN] {
+            execute_statement(
+                &conn,
+                "INSERT INTO v VALUES (?)",
+                &SqlParams::Positional(vec![n.into()]),
+            )
+            .unwrap();
+        }
+        let rows = query_rows(
+            &conn,
+            "SELECT n, typeof(n) AS t FROM v",
+            &SqlParams::Positional(vec![]),
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            [
+                serde_json::json!({ "n": i64::MAX, "t": "integer" }),
+                serde_json::json!({ "n": i64::MIN, "t": "integer" }),
+            ]
+        );
+
+        let too_big = i64::MAX as u64 + 1;
+        let err = execute_statement(
+            &conn,
+            "INSERT INTO v VALUES (?)",
+            &SqlParams::Positional(vec![too_big.into()]),
+        )
+        .unwrap_err();
+        assert!(matches!(err, SidecarError::InvalidState(_)), "{err}");
+        assert!(err.to_string().contains("9223372036854775808"), "{err}");
+        let count: i64 = conn
+            .query_row("SELECT count(*) FROM v", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        // The RFC's SHA-1 seed, "12345678901234567890", in base32
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        for (time, code) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ] {
+            assert_eq!(
+                totp_at(secret, 8, 30, time).unwrap().code,
+                code,
+                "at {time}"
+            );
+        }
+
+        let lower = totp_at("gezd gnbv gy3t qojq gezd gnbv gy3t qojq", 6, 30, 59).unwrap();
+        assert_eq!(
+            lower,
+            TotpCode {
+                code: "287082".to_string(),
+                seconds_remaining: 1,
+            }
+        );
+        assert!(totp_at("not base32!", 6, 30, 59).is_err());
+        assert!(totp_at(secret, 5, 30, 59).is_err());
+        assert!(totp_at(secret, 6, 0, 59).is_err());
+    }
+
+    #[test]
+    fn stats_estimate_row_counts_without_scanning() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let conn = open_connection(&path, None).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);
+             CREATE TABLE tags (name TEXT PRIMARY KEY) WITHOUT ROWID;
+             CREATE VIRTUAL TABLE notes_fts USING fts5(body);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 10)
+             INSERT INTO notes (body) SELECT 'note ' || i FROM n;
+             DELETE FROM notes WHERE id = 3;
+             INSERT INTO tags VALUES ('a'), ('b');",
+        )
+        .unwrap();
+        let rows = |stats: &DbStats, table: &str| {
+            let table = stats.tables.iter().find(|t| t.name == table).unwrap();
+            (table.rows, table.exact)
+        };
+
+        let stats = database_stats(&conn, false).unwrap();
+        assert_eq!(stats.path.as_deref(), path.to_str());
+        assert_eq!(stats.journal_mode, "wal");
+        assert_eq!(stats.file_size, std::fs::metadata(&path).unwrap().len());
+        assert!(stats.wal_size > 0);
+        assert!(stats.page_count > 0 && stats.page_size > 0);
+        let page_size = u64::from(stats.page_size);
+        assert_eq!(stats.size_bytes, stats.page_count * page_size);
+        assert_eq!(stats.wal_size, 32 + stats.wal_frames * (page_size + 24));
+        assert!(stats.wal_frames > 0);
+        assert_eq!(stats.cache_size, -2000, "SQLite's default of 2000 KiB");
+        assert!(!stats.tables.iter().any(|t| t.name == "notes_fts"));
+        assert_eq!(rows(&stats, "notes"), (Some(10), false));
+        assert_eq!(rows(&stats, "tags"), (None, false));
+
+        conn.execute_batch("ANALYZE").unwrap();
+        let stats = database_stats(&conn, false).unwrap();
+        assert_eq!(rows(&stats, "notes"), (Some(9), false));
+        assert_eq!(rows(&stats, "tags"), (Some(2), false));
+        assert!(!stats.tables.iter().any(|t| t.name == "sqlite_stat1"));
+
+        conn.execute_batch("INSERT INTO tags VALUES ('c')").unwrap();
+        let stats = database_stats(&conn, true).unwrap();
+        assert_eq!(rows(&stats, "tags"), (Some(3), true));
+    }
+
+    #[test]
+    fn stats_report_the_page_cache_hit_ratio() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(cache_hit_ratio(&conn), None, "no lookups yet");
+
+        conn.execute_batch(
+            "CREATE TABLE t (x TEXT);
+             WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+             INSERT INTO t SELECT hex(randomblob(100)) FROM n;",
+        )
+        .unwrap();
+        for _ in 0..10 {
+            conn.query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0))
+                .unwrap();
+        }
+        let ratio = database_stats(&conn, false)
+            .unwrap()
+            .cache_hit_ratio
+            .unwrap();
+        assert!(
+            ratio > 0.5 && ratio <= 1.0,
+            "repeat scans hit the cache: {ratio}"
+        );
+    }
+
+    #[test]
+    fn indexes_are_validated_and_quoted() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(r#"CREATE TABLE "odd ""name" (email TEXT, "group" TEXT);"#)
+            .unwrap();
+        let table = r#"odd "name"#;
+
+        create_index(
+            &conn,
+            "by email",
+            table,
+            &["email".into(), "group".into()],
+            true,
+        )
+        .unwrap();
+        let (unique, columns): (bool, String) = conn
+            .query_row(
+                "SELECT il.\"unique\", group_concat(ii.name)
+                 FROM pragma_index_list(?1) AS il, pragma_index_info(il.name) AS ii",
+                [table],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert!(unique);
+        assert_eq!(columns, "email,group");
+
+        let err = create_index(&conn, "x", "missing", &["email".into()], false).unwrap_err();
+        assert!(matches!(err, SidecarError::NotFound(_)), "{err}");
+        let err = create_index(&conn, "x", table, &["nope".into()], false).unwrap_err();
+        assert!(err.to_string().contains("Column 'nope'"), "{err}");
+        assert!(create_index(&conn, "x", table, &[], false).is_err());
+        // Names are quoted, never interpreted
+        create_index(
+            &conn,
+            "x); DROP TABLE t; --",
+            table,
+            &["email".into()],
+            false,
+        )
+        .unwrap();
+
+        drop_index(&conn, "by email").unwrap();
+        let err = drop_index(&conn, "by email").unwrap_err();
+        assert!(matches!(err, SidecarError::NotFound(_)), "{err}");
+    }
+
+    #[test]
+    fn kv_store_round_trips_encrypted_values() {
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        assert!(matches!(
+            kv_store_set(&state, "theme", "dark"),
+            Err(SidecarError::Unauthorized(_))
+        ));
+        *state.encryption_key.lock() = Some(derive_key("password"));
+
+        assert_eq!(kv_store_get(&state, "theme").unwrap(), None);
+        kv_store_set(&state, "theme", "dark").unwrap();
+        assert_eq!(
+            kv_store_get(&state, "theme").unwrap().as_deref(),
+            Some("dark")
+        );
+        kv_store_set(&state, "theme", "light").unwrap();
+        assert_eq!(
+            kv_store_get(&state, "theme").unwrap().as_deref(),
+            Some("light")
+        );
+
+        let stored: String = state
+            .with_conn(|conn| {
+                Ok(conn.query_row(
+                    "SELECT value FROM kv_store WHERE key = 'theme'",
+                    [],
+                    |row| row.get(0),
+                )?)
+            })
+            .unwrap();
+        assert!(!stored.contains("light"));
+
+        assert!(kv_store_delete(&state, "theme").unwrap());
+        assert_eq!(kv_store_get(&state, "theme").unwrap(), None);
+        assert!(!kv_store_delete(&state, "theme").unwrap());
+    }
+
+    #[test]
+    fn savepoints_nest_and_roll_back_independently() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE notes (body TEXT)")
+            .unwrap();
+        let insert = |body: &str| {
+            conn.execute("INSERT INTO notes VALUES (?1)", [body])
+                .unwrap();
+        };
+        let bodies = || -> Vec<String> {
+            conn.prepare("SELECT body FROM notes ORDER BY rowid")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+
+        run_savepoint(&conn, SavepointAction::Open, "conversation").unwrap();
+        insert("message");
+        run_savepoint(&conn, SavepointAction::Open, "suggestion").unwrap();
+        insert("draft");
+        run_savepoint(&conn, SavepointAction::RollbackTo, "suggestion").unwrap();
+        assert_eq!(bodies(), ["message"]);
+        run_savepoint(&conn, SavepointAction::Release, "suggestion").unwrap();
+        assert!(!conn.is_autocommit());
+
+        let err = run_savepoint(&conn, SavepointAction::Release, "suggestion").unwrap_err();
+        assert!(matches!(err, SidecarError::NotFound(_)), "{err}");
+        run_savepoint(&conn, SavepointAction::Release, "conversation").unwrap();
+        assert!(conn.is_autocommit());
+        assert_eq!(bodies(), ["message"]);
+
+        let err = run_savepoint(&conn, SavepointAction::RollbackTo, "conversation").unwrap_err();
+        assert!(err.to_string().contains("No transaction is open"), "{err}");
+        for name in ["", "1st", "a; DROP TABLE notes", "a-b"] {
+            let err = run_savepoint(&conn, SavepointAction::Open, name).unwrap_err();
+            assert!(
+                matches!(err, SidecarError::InvalidState(_)),
+                "{name}: {err}"
+            );
+        }
+        assert!(conn.is_autocommit());
+    }
+
+    #[test]
+    fn schema_reports_composite_primary_keys_in_order() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE memberships (
+                 member TEXT NOT NULL,
+                 team INTEGER NOT NULL DEFAULT 0,
+                 joined TEXT,
+                 PRIMARY KEY (team, member)
+             ) WITHOUT ROWID;",
+        )
+        .unwrap();
+
+        let tables = read_schema(&conn, false).unwrap();
+        let keys: Vec<(&str, u32)> = tables[0]
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.primary_key))
+            .collect();
+        assert_eq!(keys, [("member", 2), ("team", 1), ("joined", 0)]);
+        assert_eq!(tables[0].columns[1].default_value.as_deref(), Some("0"));
+        assert_eq!(tables[0].indexes[0].origin, "pk");
+        assert_eq!(tables[0].indexes[0].columns, ["team", "member"]);
+    }
+
+    #[test]
+    fn auto_backup_skips_unchanged_databases_and_prunes_old_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("backups");
+        let state = AppState::new();
+        state.open_db(&dir.path().join("app.db")).unwrap();
+        let start = Instant::now();
+        let config = AutoBackupConfig {
+            interval_secs: 60,
+            directory: backups.to_string_lossy().into_owned(),
+            keep_last: 2,
+        };
+        set_auto_backup(&state, Some(config), start).unwrap();
+        let minute = |n: u64| start + Duration::from_secs(60 * n);
+        let write = |sql: &str| {
+            state
+                .with_conn(|conn| Ok(conn.execute_batch(sql)?))
+                .unwrap()
+        };
+
+        assert!(run_auto_backup(&state, start).is_none(), "not due yet");
+        let first = run_auto_backup(&state, minute(1)).expect("first backup");
+        assert!(first.error.is_none(), "{:?}", first.error);
+        assert!(
+            run_auto_backup(&state, minute(2)).is_none(),
+            "nothing changed"
+        );
+
+        write("CREATE TABLE notes (body TEXT)");
+        for n in 3..5 {
+            std::thread::sleep(Duration::from_millis(5));
+            write("INSERT INTO notes VALUES ('x')");
+            let event = run_auto_backup(&state, minute(n)).expect("backup after a write");
+            assert!(event.size_bytes.is_some(), "{:?}", event.error);
+        }
+        std::fs::write(backups.join("notes.txt"), "keep me").unwrap();
+        write("INSERT INTO notes VALUES ('y')");
+        std::thread::sleep(Duration::from_millis(5));
+        let last = run_auto_backup(&state, minute(5)).unwrap();
+
+        let mut names: Vec<String> = std::fs::read_dir(&backups)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3, "{names:?}");
+        assert!(!names.iter().any(|n| first.path.ends_with(n.as_str())));
+        assert_eq!(names[0], "notes.txt");
+        assert!(last.path.ends_with(names[2].as_str()));
+        let rows: i64 = Connection::open(&last.path)
+            .unwrap()
+            .query_row("SELECT count(*) FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 3);
+
+        set_auto_backup(&state, None, start).unwrap();
+        write("INSERT INTO notes VALUES ('z')");
+        assert!(run_auto_backup(&state, minute(10)).is_none());
+    }
+
+    #[test]
+    fn relocate_moves_the_database_and_remembers_where() {
+        let dir = tempfile::tempdir().unwrap();
+        let old_path = dir.path().join("old").join("sidecar.db");
+        std::fs::create_dir_all(old_path.parent().unwrap()).unwrap();
+        let location = dir.path().join("location.json");
+        let state = AppState::new();
+        state.open_db(&old_path).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (1);")?)
+            })
+            .unwrap();
+
+        let same = relocate_database(&state, old_path.parent().unwrap(), &location).unwrap();
+        assert_eq!(same, old_path);
+        assert!(!location.exists());
+        *state.encryption_key.lock() = Some(derive_key("pw"));
+
+        let new_dir = dir.path().join("new");
+        let new_path = relocate_database(&state, &new_dir, &location).unwrap();
+        assert_eq!(new_path, new_dir.join("sidecar.db"));
+        assert!(!old_path.exists());
+        assert_eq!(relocated_database_path(&location), Some(new_path.clone()));
+        assert!(state.encryption_key.lock().is_none());
+        let x: i64 = state
+            .with_reader(|conn| Ok(conn.query_row("SELECT x FROM t", [], |row| row.get(0))?))
+            .unwrap();
+        assert_eq!(x, 1);
+        let live = state
+            .with_conn(|conn| Ok(conn.path().map(PathBuf::from)))
+            .unwrap();
+        assert_eq!(live.as_deref(), Some(new_path.as_path()));
+
+        // A failed move leaves the current database in use
+        let occupied = dir.path().join("occupied");
+        std::fs::create_dir_all(&occupied).unwrap();
+        std::fs::write(occupied.join("sidecar.db"), "not mine").unwrap();
+        assert!(relocate_database(&state, &occupied, &location).is_err());
+        assert_eq!(relocated_database_path(&location), Some(new_path.clone()));
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("INSERT INTO t VALUES (2)")?))
+            .unwrap();
+        assert!(new_path.exists());
+    }
+
+    #[test]
+    fn custom_keyring_service_keeps_credentials_apart() {
+        use_memory_keychain();
+        let state = AppState::new();
+        set_service_name(&state, "test-other-app".into()).unwrap();
+        store_account_credentials(&state, "test-service", None, "theirs").unwrap();
+        assert_eq!(
+            get_account_credentials(&state, "test-service", None)
+                .unwrap()
+                .as_deref(),
+            Some("theirs")
+        );
+
+        let default_state = AppState::new();
+        assert_eq!(
+            get_account_credentials(&default_state, "test-service", None).unwrap(),
+            None
+        );
+
+        set_service_name(&state, "test-other-app".into()).unwrap();
+        assert!(set_service_name(&state, "test-third-app".into()).is_err());
+        assert!(set_service_name(&default_state, " ".into()).is_err());
+    }
+
+    #[test]
+    fn credential_metadata_tracks_labels_and_timestamps() {
+        use_memory_keychain();
+        let state = AppState::new();
+        state.open_db(Path::new(":memory:")).unwrap();
+        let work = scoped_provider("test-meta", Some("work")).unwrap();
+
+        store_labelled_credentials(&state, &work, None, "v1", Some("Work mail"), 100).unwrap();
+        store_labelled_credentials(&state, "test-meta", Some("bob"), "b1", None, 150).unwrap();
+        store_labelled_credentials(&state, &work, None, "v2", None, 200).unwrap();
+
+        let meta = state.with_conn(credential_metadata).unwrap();
+        assert_eq!(
+            meta,
+            [
+                CredentialMeta {
+                    provider: "test-meta".into(),
+                    account: Some("bob".into()),
+                    label: None,
+                    created_at: 150,
+                    updated_at: 150,
+                },
+                CredentialMeta {
+                    provider: work.clone(),
+                    account: None,
+                    label: Some("Work mail".into()),
+                    created_at: 100,
+                    updated_at: 200,
+                },
+            ]
+        );
+        assert_eq!(
+            unscoped_provider(&work, Some("work")).as_deref(),
+            Some("test-meta")
+        );
+        assert_eq!(unscoped_provider(&work, None), None);
+
+        delete_account_credentials(&state, &work, None).unwrap();
+        let meta = state.with_conn(credential_metadata).unwrap();
+        assert_eq!(meta.len(), 1);
+        assert_eq!(meta[0].account.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn sql_functions_cover_regexp_uuid_and_time() {
+        let conn = Connection::open_in_memory().unwrap();
+        register_sql_functions(&conn).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE mail (subject TEXT);
+             INSERT INTO mail VALUES ('Re: invoice #42'), ('Lunch?'), (NULL);",
+        )
+        .unwrap();
+
+        let matched: Vec<String> = conn
+            .prepare("SELECT subject FROM mail WHERE subject REGEXP '#\\d+$'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(matched, ["Re: invoice #42"]);
+
+        let err = conn
+            .query_row("SELECT 'x' REGEXP '(unclosed'", [], |row| {
+                row.get::<_, bool>(0)
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("'(unclosed'"), "{err}");
+
+        let (a, b): (String, String) = conn
+            .query_row("SELECT uuid4(), uuid4()", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_ne!(a, b);
+        assert!(Uuid::parse_str(&a).is_ok());
+
+        let before = chrono::Utc::now().timestamp_millis();
+        let now: i64 = conn
+            .query_row("SELECT now_ms()", [], |row| row.get(0))
+            .unwrap();
+        assert!((before..before + 60_000).contains(&now));
+    }
+
+    #[test]
+    fn password_hashes_verify_only_the_right_password() {
+        let hash = hash_password("correct horse".into()).unwrap();
+        assert!(hash.starts_with("$argon2id$"), "{hash}");
+        assert_ne!(
+            hash,
+            hash_password("correct horse".into()).unwrap(),
+            "salted"
+        );
+
+        assert!(verify_password_hash("correct horse".into(), hash.clone()).unwrap());
+        assert!(!verify_password_hash("battery staple".into(), hash).unwrap());
+        assert!(matches!(
+            verify_password_hash("correct horse".into(), "not a hash".into()),
+            Err(SidecarError::Encryption(_))
+        ));
+    }
+
+    #[test]
+    fn unicode_nocase_folds_beyond_ascii() {
+        let conn = Connection::open_in_memory().unwrap();
+        register_sql_functions(&conn).unwrap();
+        let same = |a: &str, b: &str| -> bool {
+            conn.query_row("SELECT ?1 = ?2 COLLATE UNICODE_NOCASE", [a, b], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+
+        assert!(same("Éléonore", "éLÉONORE"));
+        assert!(!same("Éléonore", "Eleonore"), "accents still count");
+        assert!(same("Straße", "STRASSE"));
+        assert!(same("ΣΊΣΥΦΟΣ", "σίσυφος"));
+        assert!(same("Ivan ИВАН", "ivan иван"));
+        assert!(same("I", "i"));
+        assert!(!same("ı", "i"), "dotless i is its own letter");
+        assert!(!same("İ", "I"));
+
+        conn.execute_batch(
+            "CREATE TABLE participants (name TEXT COLLATE UNICODE_NOCASE);
+             INSERT INTO participants VALUES ('zoë'), ('Émile'), ('adam'), ('ÉMILE');",
+        )
+        .unwrap();
+        let names: Vec<String> = conn
+            .prepare("SELECT name FROM participants WHERE name = 'émile' ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(names, ["Émile", "ÉMILE"]);
+        let first: String = conn
+            .query_row(
+                "SELECT name FROM participants ORDER BY name LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(first, "adam");
+    }
+
+    #[test]
+    fn restore_and_recover_keep_unicode_nocase_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("live.db")).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE participants (id INTEGER PRIMARY KEY, name TEXT);
+                     CREATE INDEX idx_participants_name
+                         ON participants (name COLLATE UNICODE_NOCASE);
+                     INSERT INTO participants (name) VALUES ('Éléonore'), ('Øyvind');",
+                )?)
+            })
+            .unwrap();
+        let backup = dir.path().join("backup.db");
+        backup_database(&state, &backup, false, BackupPacing::default(), |_| {}).unwrap();
+
+        state.close_db().unwrap();
+        restore_database(&state, &backup).unwrap();
+        let report = recover_database(&state).unwrap();
+        assert_eq!(report.tables[0].rows_recovered, 2);
+
+        let (index, hit) = state
+            .with_conn(|conn| {
+                assert!(integrity_report(conn)?.ok);
+                let index: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE name = 'idx_participants_name'",
+                    [],
+                    |row| row.get(0),
+                )?;
+                let hit: i64 = conn.query_row(
+                    "SELECT id FROM participants WHERE name = 'ÉLÉONORE' COLLATE UNICODE_NOCASE",
+                    [],
+                    |row| row.get(0),
+                )?;
+                Ok((index, hit))
+            })
+            .unwrap();
+        assert_eq!((index, hit), (1, 1));
+    }
+
+    #[test]
+    fn restore_after_close_replaces_the_closed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("live.db")).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")?)
+            })
+            .unwrap();
+        let backup = dir.path().join("backup.db");
+        backup_database(&state, &backup, false, BackupPacing::default(), |_| {}).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("INSERT INTO t VALUES (2);")?))
+            .unwrap();
+        assert!(close_database(&state, None, false).unwrap());
+
+        restore_database(&state, &backup).unwrap();
+        let live = state
+            .with_conn(|conn| Ok(conn.path().map(PathBuf::from)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(live.file_name().unwrap(), "live.db");
+        let count: i64 = state
+            .with_conn(|conn| Ok(conn.query_row("SELECT count(*) FROM t", [], |row| row.get(0))?))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn restore_without_any_database_is_invalid_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.db");
+        Connection::open(&src)
+            .unwrap()
+            .execute_batch("CREATE TABLE t (x INTEGER);")
+            .unwrap();
+        let state = AppState::new();
+        assert!(matches!(
+            restore_database(&state, &src),
+            Err(SidecarError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn single_statement_commands_reject_trailing_statements() {
+        let conn = test_conn();
+        conn.execute_batch("CREATE TABLE messages (body TEXT)")
+            .unwrap();
+        let none = SqlParams::Positional(vec![]);
+
+        for sql in [
+            "INSERT INTO messages VALUES ('a'); DROP TABLE messages",
+            "INSERT INTO messages VALUES ('a');\n-- tidy up\nDELETE FROM messages",
+            "INSERT INTO messages VALUES ('a'); not even sql",
+        ] {
+            assert!(
+                matches!(
+                    execute_statement(&conn, sql, &none),
+                    Err(SidecarError::MultipleStatements(_))
+                ),
+                "{sql}"
+            );
+        }
+        assert!(matches!(
+            query_rows(&conn, "SELECT 1; SELECT 2", &none),
+            Err(SidecarError::MultipleStatements(_))
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let export = dir.path().join("out.csv");
+        assert!(matches!(
+            export_query(
+                &conn,
+                "SELECT * FROM messages; DROP TABLE messages",
+                &none,
+                &export,
+                ExportFormat::Csv,
+                |_| {}
+            ),
+            Err(SidecarError::MultipleStatements(_))
+        ));
+        assert!(!export.exists());
+        let count: i64 = conn
+            .query_row("SELECT count(*) FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0, "nothing ran");
+
+        for sql in [
+            "INSERT INTO messages VALUES ('one; DROP TABLE messages')",
+            "INSERT INTO messages VALUES ('two');",
+            "INSERT INTO messages VALUES ('three');; -- done\n/* really */ ;",
+            "-- leading comment\nINSERT INTO messages VALUES ('four' || ';')",
+        ] {
+            assert_eq!(execute_statement(&conn, sql, &none).unwrap(), 1, "{sql}");
+        }
+        // A second run comes from the cache and is still accepted
+        execute_statement(&conn, "INSERT INTO messages VALUES ('two');", &none).unwrap();
+        assert_eq!(
+            query_rows(&conn, "SELECT count(*) AS n FROM messages;", &none).unwrap(),
+            vec![serde_json::json!({ "n": 5 })]
+        );
+    }
+
+    #[test]
+    fn workspace_databases_keep_same_named_tables_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        for name in ["work", "personal"] {
+            state
+                .open_named(name, &dir.path().join(format!("{name}.db")), None)
+                .unwrap();
+            state
+                .with_named_conn(Some(name), |conn| {
+                    conn.execute_batch("CREATE TABLE situations (title TEXT)")?;
+                    execute_statement(
+                        conn,
+                        "INSERT INTO situations VALUES (?1)",
+                        &SqlParams::Positional(vec![serde_json::json!(format!("{name} review"))]),
+                    )
+                })
+                .unwrap();
+        }
+        let titles = |name: &str| {
+            state.with_named_reader(Some(name), |conn| {
+                query_rows(
+                    conn,
+                    "SELECT title FROM situations",
+                    &SqlParams::Positional(vec![]),
+                )
+            })
+        };
+        assert_eq!(
+            titles("work").unwrap(),
+            [serde_json::json!({ "title": "work review" })]
+        );
+        assert_eq!(
+            titles("personal").unwrap(),
+            [serde_json::json!({ "title": "personal review" })]
+        );
+        assert!(matches!(
+            state.with_conn(|conn| Ok(conn.path().map(str::to_string))),
+            Err(SidecarError::InvalidState(_))
+        ));
+
+        assert!(close_database(&state, Some("work"), false).unwrap());
+        assert!(titles("work").is_err());
+        assert_eq!(titles("personal").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn readers_see_committed_writes_and_cannot_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        state.open_db(&dir.path().join("app.db")).unwrap();
+        let none = SqlParams::Positional(vec![]);
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("CREATE TABLE t (x INTEGER)")?))
+            .unwrap();
+
+        for x in 1..=3 {
+            state
+                .with_conn(|conn| {
+                    execute_statement(
+                        conn,
+                        "INSERT INTO t VALUES (?1)",
+                        &SqlParams::Positional(vec![serde_json::json!(x)]),
+                    )
+                })
+                .unwrap();
+            let rows = state
+                .with_reader(|conn| query_rows(conn, "SELECT max(x) AS x FROM t", &none))
+                .unwrap();
+            assert_eq!(rows, [serde_json::json!({ "x": x })]);
+        }
+
+        let err = state
+            .with_reader(|conn| execute_statement(conn, "DELETE FROM t", &none))
+            .unwrap_err();
+        assert!(err.to_string().contains("readonly"), "{err}");
+        assert!(close_database(&state, None, false).unwrap());
+        assert!(state.readers.lock().is_none());
+    }
+
+    #[test]
+    fn registered_builtins_run_on_writer_and_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = AppState::new();
+        register_user_function(&state, "tidy", 1, "normalize_whitespace").unwrap();
+        state.open_db(&dir.path().join("app.db")).unwrap();
+        register_user_function(&state, "digest", 1, "sha256").unwrap();
+        register_user_function(&state, "shout", 1, "upper").unwrap();
+        register_user_function(&state, "matches", 2, "regex_match").unwrap();
+
+        let none = SqlParams::Positional(vec![]);
+        let sql = "SELECT tidy('  hello \t\n world ') AS tidy, digest('abc') AS digest,
+                          shout('straße') AS shout, matches('ticket-42', '^ticket-\\d+$') AS hit,
+                          digest(NULL) AS missing";
+        let expected = serde_json::json!({
+            "tidy": "hello world",
+            "digest": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            "shout": "STRASSE",
+            "hit": 1,
+            "missing": null,
+        });
+        let written = state
+            .with_conn(|conn| query_rows(conn, sql, &none))
+            .unwrap();
+        let read = state
+            .with_reader(|conn| query_rows(conn, sql, &none))
+            .unwrap();
+        assert_eq!(written, read);
+        assert_eq!(read, [expected]);
+
+        register_user_function(&state, "DIGEST", 1, "upper").unwrap();
+        assert_eq!(
+            state
+                .with_reader(|conn| query_rows(conn, "SELECT digest('a') AS d", &none))
+                .unwrap(),
+            [serde_json::json!({ "d": "A" })]
+        );
+        assert_eq!(
+            state
+                .sql_functions
+                .lock()
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            ["tidy", "shout", "matches", "DIGEST"]
+        );
+
+        for (name, n_args, body) in [
+            ("run", 1, "os.execute('rm -rf /')"),
+            ("digest", 2, "sha256"),
+            ("bad name", 1, "upper"),
+            ("regexp", 2, "regex_match"),
+            ("UPPER", 1, "upper"),
+            ("now_ms", 1, "sha256"),
+        ] {
+            assert!(matches!(
+                register_user_function(&state, name, n_args, body),
+                Err(SidecarError::InvalidState(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn close_releases_every_file_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sidecar.db");
+        let state = AppState::new();
+        state.open_db(&path).unwrap();
+        state
+            .with_conn(|conn| Ok(conn.execute_batch("CREATE TABLE t (x INTEGER)")?))
+            .unwrap();
+        // Make sure a reader connection is open too
+        state
+            .with_reader(|conn| query_rows(conn, "SELECT * FROM t", &SqlParams::Positional(vec![])))
+            .unwrap();
+
+        close_database(&state, None, false).unwrap();
+        close_database(&state, None, false).unwrap();
+        let err = state.with_reader(|_| Ok(())).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid state: Database not initialized");
+        assert!(!dir.path().join("sidecar.db-wal").exists());
+        assert!(!dir.path().join("sidecar.db-shm").exists());
+
+        // Nothing holds the file any more, so it can be deleted and recreated
+        std::fs::remove_file(&path).unwrap();
+        state.open_db(&path).unwrap();
+        let tables = state
+            .with_conn(|conn| {
+                query_rows(
+                    conn,
+                    "SELECT name FROM sqlite_master",
+                    &SqlParams::Positional(vec![]),
+                )
+            })
+            .unwrap();
+        assert!(tables.is_empty());
+    }
+
+    #[test]
+    fn query_with_meta_reports_declared_types() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE events (id INTEGER PRIMARY KEY, code TEXT, at DATETIME, extra);
+             INSERT INTO events VALUES (1, '42', '2024-05-01 09:00:00', NULL);",
+        )
+        .unwrap();
+        let none = SqlParams::Positional(vec![]);
+
+        let sql = "SELECT id, code, at AS happened, extra, id + 1 AS next FROM events";
+        let result = query_with_meta(&conn, sql, &none).unwrap();
+        let column = |name: &str, decl_type: Option<&str>| ColumnMeta {
+            name: name.to_string(),
+            decl_type: decl_type.map(str::to_string),
+        };
+        assert_eq!(
+            result.columns,
+            [
+                column("id", Some("INTEGER")),
+                column("code", Some("TEXT")),
+                column("happened", Some("DATETIME")),
+                column("extra", None),
+                column("next", None),
+            ]
+        );
+        assert_eq!(result.rows, query_rows(&conn, sql, &none).unwrap());
+        assert_eq!(
+            serde_json::to_value(&result.columns[2]).unwrap(),
+            serde_json::json!({ "name": "happened", "declType": "DATETIME" })
+        );
+
+        // Columns are known even when nothing matches
+        let empty = query_with_meta(&conn, "SELECT code FROM events WHERE 0", &none).unwrap();
+        assert!(empty.rows.is_empty());
+        assert_eq!(empty.columns, [column("code", Some("TEXT"))]);
+    }
+
+    #[test]
+    fn integrity_report_catches_a_corrupted_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let state = AppState::new();
+        state.open_db(&path).unwrap();
+        state
+            .with_conn(|conn| {
+                Ok(conn.execute_batch(
+                    "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT, title TEXT);
+                     INSERT INTO notes (body, title) VALUES ('a', 'z'), ('b', 'y'), ('c', 'x');
+                     CREATE INDEX notes_body ON notes (body);",
+                )?)
+            })
+            .unwrap();
+        assert!(state.with_conn(integrity_report).unwrap().ok);
+        state.close_db().unwrap();
+
+        // Point the index's definition at another column, so its pages no
+        // longer match the table, the way a torn write would leave them
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "PRAGMA writable_schema = ON;
+             UPDATE sqlite_master SET sql = 'CREATE INDEX notes_body ON notes (title)'
+              WHERE name = 'notes_body';
+             PRAGMA writable_schema = OFF;",
+        )
+        .unwrap();
+        drop(conn);
+
+        state.open_db(&path).unwrap();
+        let report = state.with_conn(integrity_report).unwrap();
+        assert!(!report.ok);
+        assert!(report.foreign_key_violations.is_empty());
+        assert!(
+            report.errors.iter().any(|e| e.contains("notes_body")),
+            "{:?}",
+            report.errors
+        );
+        assert_eq!(report.issues, report.errors);
+    }
+
+    #[test]
+    fn kdf_params_derive_per_salt_and_round_trip() {
+        let params = KdfParams::generate(TEST_KDF_COST);
+        assert_eq!(params.derive("pw").unwrap(), params.derive("pw").unwrap());
+        assert_ne!(params.derive("pw").unwrap(), params.derive("pW").unwrap());
+        let other = KdfParams::generate(TEST_KDF_COST);
+        assert_ne!(params.salt, other.salt);
+        assert_ne!(params.derive("pw").unwrap(), other.derive("pw").unwrap());
+        assert_ne!(*params.derive("pw").unwrap(), *derive_key("pw"));
+
+        let json = serde_json::to_value(&params).unwrap();
+        assert_eq!(json["algorithm"], "argon2id");
+        assert_eq!(json["memoryKib"], 8);
+        assert_eq!(serde_json::from_value::<KdfParams>(json).unwrap(), params);
+
+        let weak = KdfCost {
+            memory_kib: 1,
+            ..TEST_KDF_COST
+        };
+        assert!(matches!(weak.argon2(), Err(SidecarError::Encryption(_))));
+    }
+
+    #[test]
+    fn init_encryption_needs_an_open_database() {
+        let state = AppState::new();
+        assert!(matches!(
+            unlock_with_password(&state, "pw", &[]),
+            Err(SidecarError::InvalidState(_))
+        ));
+        assert!(state.encryption_key.lock().is_none());
+    }
+
+    #[test]
+    fn legacy_databases_upgrade_to_argon2id_on_unlock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let state = AppState::new();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        state.open_db(&path).unwrap();
+
+        // Lay the database out the way the SHA-256 derivation left it
+        let legacy = derive_key("hunter2");
+        state
+            .with_conn(|conn| {
+                store_password_sentinel(&state, conn, &legacy)?;
+                conn.execute_batch(
+                    "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT, raw BLOB)",
+                )?;
+                conn.execute(
+                    "INSERT INTO notes (body, raw) VALUES (?1, ?2)",
+                    rusqlite::params![
+                        encrypt_with_key(&legacy, &[1; 12], "meeting notes", b"")?,
+                        encrypt_bytes_with_key(&legacy, &[2; 12], b"\x00\x01", b"")?
+                    ],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+        *state.encryption_key.lock() = Some(legacy.clone());
+        kv_store_set(&state, "theme", "dark").unwrap();
+        state.lock_key();
+
+        let fields = ["body", "raw"].map(|column| EncryptedField {
+            table: "notes".to_string(),
+            column: column.to_string(),
+            id_column: "id".to_string(),
+            aad_column: None,
+        });
+        assert!(matches!(
+            unlock_with_password(&state, "wrong", &fields),
+            Err(SidecarError::EncryptionFailed(_))
+        ));
+        assert!(state.with_conn(stored_kdf_params).unwrap().is_none());
+        assert!(state.encryption_key.lock().is_none());
+
+        unlock_with_password(&state, "hunter2", &fields).unwrap();
+        let params = state.with_conn(stored_kdf_params).unwrap().unwrap();
+        assert_eq!(params.cost, TEST_KDF_COST);
+        let key = params.derive("hunter2").unwrap();
+        assert_eq!(*state.encryption_key.lock(), Some(key.clone()));
+        assert!(check_password(&state, "hunter2").unwrap());
+
+        // Old ciphertexts read back under the new key, and not the old one
+        let (body, raw): (String, Vec<u8>) = state
+            .with_conn(|conn| {
+                Ok(conn.query_row("SELECT body, raw FROM notes", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?)
+            })
+            .unwrap();
+        assert_eq!(decrypt_with_key(&key, &body, b"").unwrap(), "meeting notes");
+        assert_eq!(
+            decrypt_bytes_with_key(&key, &raw, b"").unwrap(),
+            b"\x00\x01"
+        );
+        assert!(decrypt_with_key(&legacy, &body, b"").is_err());
+        assert_eq!(
+            kv_store_get(&state, "theme").unwrap().as_deref(),
+            Some("dark")
+        );
+
+        // Later unlocks use the stored parameters even if the cost changes
+        state.close_db().unwrap();
+        state.lock_key();
+        *state.kdf_cost.lock() = KdfCost {
+            iterations: 2,
+            ..TEST_KDF_COST
+        };
+        state.open_db(&path).unwrap();
+        assert!(matches!(
+            unlock_with_password(&state, "wrong", &[]),
+            Err(SidecarError::EncryptionFailed(_))
+        ));
+        assert!(state.encryption_key.lock().is_none());
+        unlock_with_password(&state, "hunter2", &[]).unwrap();
+        assert_eq!(*state.encryption_key.lock(), Some(key));
+        assert_eq!(
+            kv_store_get(&state, "theme").unwrap().as_deref(),
+            Some("dark")
+        );
+    }
+
+    #[test]
+    fn databases_without_a_sentinel_are_checked_against_their_ciphertexts() {
+        let state = AppState::new();
+        *state.kdf_cost.lock() = TEST_KDF_COST;
+        state.open_db(Path::new(":memory:")).unwrap();
+
+        // What the original code wrote: ciphertext, but no check value
+        let legacy = derive_key("hunter2");
+        state
+            .with_conn(|conn| {
+                conn.execute_batch("CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT)")?;
+                conn.execute(
+                    "INSERT INTO notes (body) VALUES (?1)",
+                    [encrypt_with_key(&legacy, &[1; 12], "meeting notes", b"")?],
+                )?;
+                Ok(())
+            })
+            .unwrap();
+        let fields = [EncryptedField {
+            table: "notes".to_string(),
+            column: "body".to_string(),
+            id_column: "id".to_string(),
+            aad_column: None,
+        }];
+
+        assert!(matches!(
+            unlock_with_password(&state, "wrong", &fields),
+            Err(SidecarError::EncryptionFailed(_))
+        ));
+        assert!(state.with_conn(stored_kdf_params).unwrap().is_none());
+        assert!(state.encryption_key.lock().is_none());
+
+        unlock_with_password(&state, "hunter2", &fields).unwrap();
+        let key = stored_key(&state, "hunter2");
+        let body: String = state
+            .with_conn(|conn| Ok(conn.query_row("SELECT body FROM notes", [], |row| row.get(0))?))
+            .unwrap();
+        assert_eq!(decrypt_with_key(&key, &body, b"").unwrap(), "meeting notes");
+        assert!(check_password(&state, "hunter2").unwrap());
+    }
 }
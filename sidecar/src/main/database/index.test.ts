@@ -0,0 +1,61 @@
+// Tests for the database service
+import { describe, it, expect, beforeEach, vi } from 'vitest';
+import { invoke } from '@tauri-apps/api/core';
+import { executeReturning } from './index';
+import type { SidecarError } from '../../shared/types';
+
+// Mock invoke
+vi.mock('@tauri-apps/api/core', () => ({
+  invoke: vi.fn(),
+}));
+
+const mockInvoke = invoke as ReturnType<typeof vi.fn>;
+
+describe('database service', () => {
+  beforeEach(() => {
+    vi.clearAllMocks();
+  });
+
+  describe('executeReturning', () => {
+    it('should call db_execute_returning with the statement and params', async () => {
+      mockInvoke.mockResolvedValueOnce({ rowsAffected: 1, lastInsertRowid: 42 });
+
+      const result = await executeReturning('INSERT INTO notes (body) VALUES (?)', ['hi']);
+
+      expect(mockInvoke).toHaveBeenCalledWith('db_execute_returning', {
+        sql: 'INSERT INTO notes (body) VALUES (?)',
+        params: ['hi'],
+      });
+      expect(result).toEqual({ rowsAffected: 1, lastInsertRowid: 42 });
+    });
+
+    it('should default to no params and pass a null rowid through', async () => {
+      mockInvoke.mockResolvedValueOnce({ rowsAffected: 3, lastInsertRowid: null });
+
+      const result = await executeReturning('DELETE FROM notes');
+
+      expect(mockInvoke).toHaveBeenCalledWith('db_execute_returning', {
+        sql: 'DELETE FROM notes',
+        params: [],
+      });
+      expect(result.lastInsertRowid).toBeNull();
+    });
+
+    it('should reject with the backend error payload untouched', async () => {
+      // As serialized by SidecarError in the Rust backend
+      const payload: SidecarError = {
+        code: 'multiple_statements',
+        message:
+          'Multiple statements: Expected a single SQL statement; use db_execute_batch for scripts',
+      };
+      mockInvoke.mockRejectedValueOnce(payload);
+
+      const error = await executeReturning('DELETE FROM a; DELETE FROM b').catch(
+        (e: SidecarError) => e
+      );
+
+      expect(error).toEqual(payload);
+      expect(error.code).toBe('multiple_statements');
+    });
+  });
+});
@@ -15,6 +15,7 @@ import type {
   SituationAnalysis,
   AnalysisDTO,
   AppSettings,
+  ExecuteResult,
 } from '../../shared/types';
 
 // ============================================================================
@@ -34,7 +35,7 @@ export async function initializeDatabase(): Promise<void> {
     await initializeEncryptedDatabase();
 
     // Create tables
-    await invoke('db_execute', { sql: CREATE_TABLES_SQL });
+    await invoke('db_execute_batch', { script: CREATE_TABLES_SQL });
 
     // Run migrations
     await runMigrations();
@@ -66,14 +67,14 @@ async function runMigrations(): Promise<void> {
     // Run any pending migrations
     for (let version = currentVersion + 1; version <= SCHEMA_VERSION; version++) {
       if (MIGRATIONS[version]) {
-        await invoke('db_execute', { sql: MIGRATIONS[version] });
+        await invoke('db_execute_batch', { script: MIGRATIONS[version] });
         console.log(`Applied migration version ${version}`);
       }
     }
   } catch {
     // Table might not exist yet, run initial migration
     if (MIGRATIONS[1]) {
-      await invoke('db_execute', { sql: MIGRATIONS[1] });
+      await invoke('db_execute_batch', { script: MIGRATIONS[1] });
     }
   }
 }
@@ -457,6 +458,18 @@ async function logAudit(
   }
 }
 
+// ============================================================================
+// Raw Statements
+// ============================================================================
+
+/**
+ * Execute a statement and return the affected row count together with the
+ * inserted rowid, both read under the same connection lock
+ */
+export async function executeReturning(sql: string, params: unknown[] = []): Promise<ExecuteResult> {
+  return invoke<ExecuteResult>('db_execute_returning', { sql, params });
+}
+
 // ============================================================================
 // DTO Converters
 // ============================================================================
@@ -562,4 +575,5 @@ export default {
   getLatestAnalysis,
   getSettings,
   updateSetting,
+  executeReturning,
 };
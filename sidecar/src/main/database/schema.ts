@@ -1,7 +1,7 @@
 // SQLite Schema for Sidecar
 // Compatible with SQLCipher encryption
 
-export const SCHEMA_VERSION = 1;
+export const SCHEMA_VERSION = 2;
 
 // ============================================================================
 // Table Creation SQL
@@ -141,8 +141,14 @@ export const MIGRATIONS: Record<number, string> = {
   1: `
     INSERT OR REPLACE INTO schema_version (version) VALUES (1);
   `,
+  // Version 2 indexes participant names for case-insensitive lookup. The
+  // UNICODE_NOCASE collation is registered by the Rust backend.
+  2: `
+    CREATE INDEX IF NOT EXISTS idx_participants_name ON participants(name COLLATE UNICODE_NOCASE);
+    INSERT OR REPLACE INTO schema_version (version) VALUES (2);
+  `,
   // Future migrations go here
-  // 2: `ALTER TABLE situations ADD COLUMN priority TEXT;`,
+  // 3: `ALTER TABLE situations ADD COLUMN priority TEXT;`,
 };
 
 // ============================================================================
@@ -326,6 +326,35 @@ export interface IpcResult<T> {
   error?: string;
 }
 
+// Result of the db_execute_returning command
+export interface ExecuteResult {
+  rowsAffected: number;
+  lastInsertRowid: number | null; // Only set for INSERT/REPLACE statements
+}
+
+// Stable identifiers from SidecarError::code in the Rust backend
+export type SidecarErrorCode =
+  | 'database'
+  | 'encryption'
+  | 'unauthorized'
+  | 'encryption_failed'
+  | 'keyring'
+  | 'invalid_state'
+  | 'not_found'
+  | 'serialization'
+  | 'io'
+  | 'timeout'
+  | 'pool'
+  | 'busy'
+  | 'cancelled'
+  | 'multiple_statements';
+
+// What a failed invoke() rejects with. Match on code, not message.
+export interface SidecarError {
+  code: SidecarErrorCode;
+  message: string;
+}
+
 export type IpcCommand =
   | { type: 'situation:create'; payload: Omit<Situation, 'id' | 'createdAt' | 'updatedAt' | 'participants' | 'communications'> }
   | { type: 'situation:update'; payload: Partial<Situation> & { id: string } }